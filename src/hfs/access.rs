@@ -0,0 +1,165 @@
+use fuse::Request;
+
+use libc::{c_int, EPERM};
+
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+
+// The mount is not mounted with default_permissions, so the checks chmod,
+// chown, truncate and utimens make in the kernel are ours to make, from the
+// caller of the request and the mode and ownership of the backing file.
+
+/// Mode and ownership of a file, which the checks go by.
+#[derive(Debug, Clone, Copy)]
+pub struct Perms {
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl From<&fs::Metadata> for Perms {
+    fn from(m: &fs::Metadata) -> Perms {
+        Perms {
+            mode: m.mode(),
+            uid: m.uid(),
+            gid: m.gid(),
+        }
+    }
+}
+
+/// Who a request comes from.
+#[derive(Debug, Clone, Copy)]
+pub struct Caller {
+    pub uid: u32,
+    pub gid: u32,
+    pub pid: u32,
+}
+
+impl Caller {
+    pub fn of(req: &Request) -> Caller {
+        Caller {
+            uid: req.uid(),
+            gid: req.gid(),
+            pid: req.pid(),
+        }
+    }
+
+    pub fn is_root(&self) -> bool {
+        self.uid == 0
+    }
+
+    /// Whether the caller owns `file`, as root owns everything.
+    pub fn owns(&self, file: &Perms) -> bool {
+        self.is_root() || self.uid == file.uid
+    }
+
+    /// Whether the caller is in group `gid`, as its own or as one of the
+    /// supplementary groups of its process, which the kernel does not pass on.
+    pub fn in_group(&self, gid: u32) -> bool {
+        if self.gid == gid {
+            return true;
+        }
+        let status = match fs::read_to_string(format!("/proc/{}/status", self.pid)) {
+            Ok(s) => s,
+            Err(_) => return false,
+        };
+        status.lines()
+            .find(|l| l.starts_with("Groups:"))
+            .is_some_and(|l| l["Groups:".len()..].split_whitespace().any(|g| g.parse() == Ok(gid)))
+    }
+
+    /// Whether the caller may write to `file`, by the mode bits of the owner
+    /// if it is the owner, else of the group if it is in it, else of others.
+    pub fn may_write(&self, file: &Perms) -> bool {
+        if self.is_root() {
+            true
+        } else if self.uid == file.uid {
+            file.mode & 0o200 != 0
+        } else if self.in_group(file.gid) {
+            file.mode & 0o020 != 0
+        } else {
+            file.mode & 0o002 != 0
+        }
+    }
+
+    /// The mode chmod to `mode` gives `file`: only its owner may change it,
+    /// and setgid is dropped unless the owner is in the file's group.
+    pub fn chmod(&self, file: &Perms, mode: u32) -> Result<u32, c_int> {
+        if !self.owns(file) {
+            return Err(EPERM);
+        }
+        if !self.is_root() && !self.in_group(file.gid) {
+            return Ok(mode & !0o2000);
+        }
+        Ok(mode)
+    }
+
+    /// Whether the caller may give `file` to `uid` and `gid`, each `None` if
+    /// unchanged: only root gives files away, and owners may change to a
+    /// group they are in.
+    pub fn may_chown(&self, file: &Perms, uid: Option<u32>, gid: Option<u32>) -> bool {
+        if self.is_root() {
+            return true;
+        }
+        uid.is_none() && gid.is_none_or(|g| self.owns(file) && self.in_group(g))
+    }
+
+    /// Whether the caller may set the times of `file`. Fuse cannot tell us
+    /// whether the times are "now", so either ownership or write permission
+    /// will do.
+    pub fn may_set_times(&self, file: &Perms) -> bool {
+        self.owns(file) || self.may_write(file)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A caller with no process to look supplementary groups up from.
+    fn caller(uid: u32, gid: u32) -> Caller {
+        Caller { uid, gid, pid: 0 }
+    }
+
+    fn file(mode: u32) -> Perms {
+        Perms {
+            mode,
+            uid: 1000,
+            gid: 100,
+        }
+    }
+
+    #[test]
+    fn write_permission_follows_the_class_of_the_caller() {
+        assert!(caller(1000, 1).may_write(&file(0o644)));
+        assert!(!caller(1000, 100).may_write(&file(0o464)));
+        assert!(caller(2000, 100).may_write(&file(0o464)));
+        assert!(!caller(2000, 1).may_write(&file(0o464)));
+        assert!(caller(2000, 1).may_write(&file(0o446)));
+        assert!(caller(0, 0).may_write(&file(0o000)));
+    }
+
+    #[test]
+    fn only_the_owner_changes_the_mode_and_setgid_needs_the_group() {
+        assert_eq!(caller(2000, 100).chmod(&file(0o644), 0o600), Err(EPERM));
+        assert_eq!(caller(1000, 100).chmod(&file(0o644), 0o2755), Ok(0o2755));
+        assert_eq!(caller(1000, 1).chmod(&file(0o644), 0o2755), Ok(0o755));
+        assert_eq!(caller(0, 0).chmod(&file(0o644), 0o2755), Ok(0o2755));
+    }
+
+    #[test]
+    fn only_root_gives_files_away() {
+        assert!(!caller(1000, 100).may_chown(&file(0o644), Some(2000), None));
+        assert!(caller(0, 0).may_chown(&file(0o644), Some(2000), Some(1)));
+        assert!(caller(1000, 1).may_chown(&file(0o644), None, Some(1)));
+        assert!(!caller(1000, 100).may_chown(&file(0o644), None, Some(1)));
+        assert!(!caller(2000, 1).may_chown(&file(0o644), None, Some(1)));
+    }
+
+    #[test]
+    fn times_are_set_by_the_owner_or_a_writer() {
+        assert!(caller(1000, 1).may_set_times(&file(0o444)));
+        assert!(caller(2000, 1).may_set_times(&file(0o666)));
+        assert!(!caller(2000, 1).may_set_times(&file(0o644)));
+    }
+}
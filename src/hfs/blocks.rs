@@ -0,0 +1,83 @@
+/// Which fixed-size blocks of an evicted file are cached in its placeholder.
+/// Block `n` covers bytes `n * block_size` up to the next block.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BlockMap {
+    pub block_size: u64,
+    words: Vec<u64>,
+}
+
+impl BlockMap {
+    pub fn new(block_size: u64) -> BlockMap {
+        BlockMap {
+            block_size,
+            words: Vec::new(),
+        }
+    }
+
+    /// Every block of a file of `length` bytes present.
+    pub fn full(block_size: u64, length: u64) -> BlockMap {
+        let mut map = BlockMap::new(block_size);
+        for n in 0..map.count(length) {
+            map.insert(n);
+        }
+        map
+    }
+
+    /// Number of blocks a file of `length` bytes spans.
+    pub fn count(&self, length: u64) -> u64 {
+        length.div_ceil(self.block_size)
+    }
+
+    /// Byte range of block `n` within a file of `length` bytes.
+    pub fn range(&self, n: u64, length: u64) -> (u64, u64) {
+        let offset = n * self.block_size;
+        (offset, self.block_size.min(length.saturating_sub(offset)))
+    }
+
+    pub fn contains(&self, n: u64) -> bool {
+        self.words.get((n / 64) as usize).is_some_and(|w| w & (1 << (n % 64)) != 0)
+    }
+
+    pub fn insert(&mut self, n: u64) {
+        let word = (n / 64) as usize;
+        if self.words.len() <= word {
+            self.words.resize(word + 1, 0);
+        }
+        self.words[word] |= 1 << (n % 64);
+    }
+
+    pub fn remove(&mut self, n: u64) {
+        if let Some(w) = self.words.get_mut((n / 64) as usize) {
+            *w &= !(1 << (n % 64));
+        }
+        while self.words.last() == Some(&0) {
+            self.words.pop();
+        }
+    }
+
+    /// Forget blocks that start at or past `length`.
+    pub fn truncate(&mut self, length: u64) {
+        let count = self.count(length);
+        for n in self.present() {
+            if n >= count {
+                self.remove(n);
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.words.is_empty()
+    }
+
+    pub fn present(&self) -> Vec<u64> {
+        let mut present = Vec::new();
+        for (i, w) in self.words.iter().enumerate() {
+            for bit in 0..64 {
+                if w & (1 << bit) != 0 {
+                    present.push(i as u64 * 64 + bit);
+                }
+            }
+        }
+        present
+    }
+}
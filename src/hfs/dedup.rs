@@ -0,0 +1,608 @@
+use errors::*;
+
+use super::kv::KvStore;
+use super::store::{self, Codec, ObjectInfo, ObjectStore};
+
+use openssl::sha::sha256;
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock};
+
+// A deduplicated object is a manifest under its own key listing, in order,
+// the chunks its content is cut into. Each chunk is stored once, under the
+// hash of its content, however many files hold it, and a count of the
+// manifest entries naming it is kept in the state directory; the chunk is
+// deleted when its count reaches nothing. Chunks are packed like compressed
+// frames, so they are compressed when compression is on.
+//
+// Chunk boundaries are found from the content itself, with a gear rolling
+// hash, so that an insertion early in a file shifts its later boundaries
+// along with it rather than changing every chunk after it. Boundaries are
+// also forced at the end of every part of a multipart upload.
+//
+// Chunk names are hashes of their content, keyed when the store below is
+// encrypted: they show a reader of the bucket which chunks are equal, but
+// cannot be checked against guesses at what they hold.
+
+const CHUNKS: &str = "chunks/";
+/// No chunk but the last of a part is smaller.
+const MIN_CHUNK: usize = 16 * 1024;
+/// Chunks are this long on average.
+const AVG_CHUNK: usize = 64 * 1024;
+/// A boundary is forced here if the content offers none.
+const MAX_CHUNK: usize = 256 * 1024;
+/// Below the average length a boundary needs more of the hash to be zero,
+/// above it less, which keeps lengths close to the average.
+const MASK_SMALL: u64 = ((1 << 18) - 1) << 46;
+const MASK_LARGE: u64 = ((1 << 14) - 1) << 50;
+
+/// The gear table. It decides every boundary, so it must never change:
+/// it is drawn from splitmix64 with a fixed seed.
+fn gear() -> &'static [u64; 256] {
+    static GEAR: OnceLock<[u64; 256]> = OnceLock::new();
+    GEAR.get_or_init(|| {
+        let mut state: u64 = 0x5333_4846_5344_4450;
+        let mut table = [0u64; 256];
+        for entry in table.iter_mut() {
+            state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+            let mut z = state;
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+            *entry = z ^ (z >> 31);
+        }
+        table
+    })
+}
+
+/// Length of the first chunk of `data`.
+fn cut(data: &[u8]) -> usize {
+    if data.len() <= MIN_CHUNK {
+        return data.len();
+    }
+    let gear = gear();
+    let mut hash: u64 = 0;
+    for (i, b) in data.iter().enumerate().take(MAX_CHUNK).skip(MIN_CHUNK) {
+        hash = (hash << 1).wrapping_add(gear[*b as usize]);
+        let mask = if i < AVG_CHUNK { MASK_SMALL } else { MASK_LARGE };
+        if hash & mask == 0 {
+            return i + 1;
+        }
+    }
+    data.len().min(MAX_CHUNK)
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn chunk_key(hash: &str) -> String {
+    format!("{}{}", CHUNKS, hash)
+}
+
+fn parts_prefix(key: &str) -> String {
+    format!("{}.parts/", key)
+}
+
+fn part_key(key: &str, number: u32) -> String {
+    format!("{}{:05}", parts_prefix(key), number)
+}
+
+/// Hash of the chunk stored under `key`, if it is one.
+pub fn chunk_of(key: &str) -> Option<&str> {
+    key.strip_prefix(CHUNKS)
+}
+
+/// The object a staged part belongs to, if `key` is one.
+pub fn part_of(key: &str) -> Option<&str> {
+    key.find(".parts/").map(|i| &key[..i])
+}
+
+/// Chunks and staged parts, rather than objects of their own.
+fn is_internal(key: &str) -> bool {
+    key.starts_with(CHUNKS) || key.contains(".parts/")
+}
+
+/// The chunks of an object, or of one part of it while it is uploaded.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Manifest {
+    pub length: u64,
+    /// hash and length of each chunk, in order
+    pub chunks: Vec<(String, u64)>,
+}
+
+/// A manifest with the offset each chunk ends at, to find ranges in.
+#[derive(Clone)]
+struct Index {
+    manifest: Manifest,
+    ends: Vec<u64>,
+}
+
+impl Index {
+    fn new(manifest: Manifest) -> Index {
+        let ends = manifest.chunks
+            .iter()
+            .scan(0, |end, c| {
+                *end += c.1;
+                Some(*end)
+            })
+            .collect();
+        Index { manifest, ends }
+    }
+}
+
+/// The manifest under `key` in `inner`, the store chunks are kept in.
+pub fn read_manifest(inner: &dyn ObjectStore, key: &str) -> Result<Option<Manifest>> {
+    if inner.head(key)?.is_none() {
+        return Ok(None);
+    }
+    let manifest = ::serde_json::from_slice(&inner.get(key)?)
+        .chain_err(|| format!("{} is not a chunk manifest", key))?;
+    Ok(Some(manifest))
+}
+
+/// Wraps another store so that content is held in shared chunks, counted
+/// in `refs`.
+pub struct DedupStore {
+    inner: Arc<dyn ObjectStore>,
+    codec: Option<Codec>,
+    level: i32,
+    /// manifest entries naming each chunk, by hash
+    refs: KvStore,
+    /// held to read and change a count or delete a chunk; holds the number
+    /// of stores under way of each chunk, which must not be deleted meanwhile
+    counting: Mutex<HashMap<String, usize>>,
+    /// manifests of objects read recently
+    indexes: Mutex<HashMap<String, Index>>,
+    /// what chunks are named with a keyed hash under, if `inner` asks for one
+    naming: Option<[u8; 32]>,
+}
+
+impl DedupStore {
+    pub fn new(inner: Arc<dyn ObjectStore>,
+               refs: KvStore,
+               codec: Option<Codec>,
+               level: i32)
+               -> DedupStore {
+        DedupStore {
+            naming: inner.naming_key(),
+            inner,
+            codec,
+            level,
+            refs,
+            counting: Mutex::new(HashMap::new()),
+            indexes: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Count one more use of the chunk `hash` holding `data`, storing the
+    /// chunk unless it is held already.
+    fn take(&self, hash: &str, data: &[u8]) -> Result<()> {
+        {
+            let mut storing = self.counting.lock().unwrap();
+            let count = self.refs.get::<u64>(hash)?.unwrap_or(0);
+            if count > 0 {
+                return self.refs.put(hash, &(count + 1));
+            }
+            *storing.entry(hash.to_string()).or_insert(0) += 1;
+        }
+        let stored = store::pack(self.codec, self.level, data)
+            .and_then(|packed| self.inner.put(&chunk_key(hash), &packed));
+        let mut storing = self.counting.lock().unwrap();
+        if let Some(n) = storing.get_mut(hash) {
+            *n -= 1;
+            if *n == 0 {
+                storing.remove(hash);
+            }
+        }
+        stored?;
+        let count = self.refs.get::<u64>(hash)?.unwrap_or(0);
+        self.refs.put(hash, &(count + 1))
+    }
+
+    /// Count one use fewer of each of `chunks`, deleting those left unused.
+    fn release(&self, chunks: &[(String, u64)]) -> Result<()> {
+        let storing = self.counting.lock().unwrap();
+        for (hash, _) in chunks {
+            match self.refs.get::<u64>(hash)? {
+                Some(count) if count > 1 => self.refs.put(hash, &(count - 1))?,
+                Some(_) => {
+                    // being stored anew, it is about to be counted again
+                    if !storing.contains_key(hash) {
+                        self.inner.delete(&chunk_key(hash))?;
+                    }
+                    self.refs.delete(hash)?;
+                }
+                None => warn!("chunk {} released more often than taken", hash),
+            }
+        }
+        Ok(())
+    }
+
+    /// Cut `data` into chunks and take each of them.
+    fn put_chunks(&self, data: &[u8]) -> Result<Manifest> {
+        let mut manifest = Manifest::default();
+        let mut rest = data;
+        while !rest.is_empty() {
+            let (chunk, after) = rest.split_at(cut(rest));
+            let hash = match self.naming {
+                Some(ref key) => to_hex(&store::keyed_hash(key, chunk)?),
+                None => to_hex(&sha256(chunk)),
+            };
+            if let Err(e) = self.take(&hash, chunk) {
+                // give back what was taken, so counts stay exact
+                if let Err(e) = self.release(&manifest.chunks) {
+                    warn!("releasing chunks of a failed put: {}", e);
+                }
+                return Err(e);
+            }
+            manifest.chunks.push((hash, chunk.len() as u64));
+            manifest.length += chunk.len() as u64;
+            rest = after;
+        }
+        Ok(manifest)
+    }
+
+    fn read_manifest(&self, key: &str) -> Result<Option<Manifest>> {
+        read_manifest(&*self.inner, key)
+    }
+
+    /// Uses of chunk `hash` counted.
+    pub fn count(&self, hash: &str) -> Result<u64> {
+        Ok(self.refs.get::<u64>(hash)?.unwrap_or(0))
+    }
+
+    /// Delete chunk `hash`, which no manifest names, unless it is being
+    /// stored or its count has moved from `count` since. Returns whether it
+    /// was deleted.
+    pub fn drop_chunk(&self, hash: &str, count: u64) -> Result<bool> {
+        let storing = self.counting.lock().unwrap();
+        if storing.contains_key(hash) || self.count(hash)? != count {
+            return Ok(false);
+        }
+        self.inner.delete(&chunk_key(hash))?;
+        self.refs.delete(hash)?;
+        Ok(true)
+    }
+
+    fn write_manifest(&self, key: &str, manifest: &Manifest) -> Result<()> {
+        let encoded = ::serde_json::to_vec(manifest).chain_err(|| "encoding manifest")?;
+        self.inner.put(key, &encoded)
+    }
+
+    fn index(&self, key: &str) -> Result<Option<Index>> {
+        if let Some(index) = self.indexes.lock().unwrap().get(key) {
+            return Ok(Some(index.clone()));
+        }
+        let index = match self.read_manifest(key)? {
+            Some(manifest) => Index::new(manifest),
+            None => return Ok(None),
+        };
+        self.indexes.lock().unwrap().insert(key.to_string(), index.clone());
+        Ok(Some(index))
+    }
+
+    fn forget_index(&self, key: &str) {
+        self.indexes.lock().unwrap().remove(key);
+    }
+
+    /// Make `manifest` the content of `key`, releasing what it held before.
+    fn replace(&self, key: &str, manifest: &Manifest) -> Result<()> {
+        let old = match self.read_manifest(key) {
+            Ok(old) => old,
+            Err(e) => {
+                warn!("replacing {}: {}", key, e);
+                None
+            }
+        };
+        self.forget_index(key);
+        self.write_manifest(key, manifest)?;
+        match old {
+            Some(old) => self.release(&old.chunks),
+            None => Ok(()),
+        }
+    }
+
+    fn read_range(&self, key: &str, offset: u64, len: u64) -> Result<Vec<u8>> {
+        let index = match self.index(key)? {
+            Some(i) => i,
+            None => bail!("get {}: no such object", key),
+        };
+        let end = index.manifest.length.min(offset.saturating_add(len));
+        if offset >= end {
+            return Ok(Vec::new());
+        }
+        let first = index.ends.partition_point(|e| *e <= offset);
+        let start = if first == 0 { 0 } else { index.ends[first - 1] };
+        let mut plain = Vec::with_capacity((end - start) as usize);
+        for (hash, chunk_len) in &index.manifest.chunks[first..] {
+            if start + plain.len() as u64 >= end {
+                break;
+            }
+            let packed = self.inner.get(&chunk_key(hash))?;
+            plain.extend(store::unpack(&packed, *chunk_len)
+                .chain_err(|| format!("reading chunk {} of {}", hash, key))?);
+        }
+        plain.truncate((end - start) as usize);
+        Ok(plain.split_off((offset - start) as usize))
+    }
+
+    fn parse_upload_id(&self, key: &str, upload_id: &str) -> Result<u64> {
+        upload_id.parse().chain_err(|| format!("invalid upload id for {}: {:?}", key, upload_id))
+    }
+}
+
+impl ObjectStore for DedupStore {
+    fn get(&self, key: &str) -> Result<Vec<u8>> {
+        self.get_range(key, 0, u64::MAX)
+    }
+
+    fn get_range(&self, key: &str, offset: u64, len: u64) -> Result<Vec<u8>> {
+        let cached = self.indexes.lock().unwrap().contains_key(key);
+        match self.read_range(key, offset, len) {
+            Ok(data) => Ok(data),
+            Err(e) => {
+                // the object may have been replaced since its manifest was read
+                if cached {
+                    self.forget_index(key);
+                    return self.read_range(key, offset, len);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    fn put(&self, key: &str, data: &[u8]) -> Result<()> {
+        let manifest = self.put_chunks(data)?;
+        if let Err(e) = self.replace(key, &manifest) {
+            if let Err(e) = self.release(&manifest.chunks) {
+                warn!("releasing chunks of a failed put: {}", e);
+            }
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Sizes are of the content, however much of it is shared.
+    fn head(&self, key: &str) -> Result<Option<ObjectInfo>> {
+        let info = match self.inner.head(key)? {
+            Some(info) => info,
+            None => return Ok(None),
+        };
+        Ok(self.index(key)?.map(|i| ObjectInfo { size: i.manifest.length, ..info }))
+    }
+
+    /// Chunks and staged parts are not listed; sizes are of the content.
+    fn list(&self, prefix: &str) -> Result<Vec<ObjectInfo>> {
+        let mut found = Vec::new();
+        for info in self.inner.list(prefix)?.into_iter().filter(|i| !is_internal(&i.key)) {
+            if let Some(index) = self.index(&info.key)? {
+                found.push(ObjectInfo { size: index.manifest.length, ..info });
+            }
+        }
+        Ok(found)
+    }
+
+    fn delete(&self, key: &str) -> Result<()> {
+        let old = self.read_manifest(key)?;
+        self.forget_index(key);
+        // the manifest goes first: a crash in between leaves chunks counted
+        // too often, never a manifest naming chunks that are gone
+        self.inner.delete(key)?;
+        match old {
+            Some(old) => self.release(&old.chunks),
+            None => Ok(()),
+        }
+    }
+
+    fn start_multipart(&self, _key: &str, length: u64, _part_size: u64) -> Result<String> {
+        // the chunks of each part are staged in a manifest of the part's
+        // own until the upload is finished, so only the length is needed
+        Ok(length.to_string())
+    }
+
+    fn put_part(&self, key: &str, upload_id: &str, number: u32, data: &[u8]) -> Result<String> {
+        self.parse_upload_id(key, upload_id)?;
+        let part = self.put_chunks(data)?;
+        let previous = self.read_manifest(&part_key(key, number)).unwrap_or(None);
+        if let Err(e) = self.write_manifest(&part_key(key, number), &part) {
+            if let Err(e) = self.release(&part.chunks) {
+                warn!("releasing chunks of a failed part: {}", e);
+            }
+            return Err(e);
+        }
+        // sent again: the chunks it named before are no longer part of it
+        if let Some(previous) = previous {
+            self.release(&previous.chunks)?;
+        }
+        Ok(format!("{}-{}", number, data.len()))
+    }
+
+    fn finish_multipart(&self, key: &str, upload_id: &str, parts: &[(u32, String)]) -> Result<()> {
+        let length = self.parse_upload_id(key, upload_id)?;
+        let mut numbers: Vec<u32> = parts.iter().map(|p| p.0).collect();
+        numbers.sort();
+        let mut manifest = Manifest::default();
+        for number in &numbers {
+            let part = match self.read_manifest(&part_key(key, *number))? {
+                Some(part) => part,
+                None => bail!("put {}: part {} missing", key, number),
+            };
+            manifest.length += part.length;
+            manifest.chunks.extend(part.chunks);
+        }
+        if manifest.length != length {
+            bail!("put {}: parts hold {} bytes rather than {}", key, manifest.length, length);
+        }
+        self.replace(key, &manifest)?;
+        // the chunks now belong to the object
+        for number in numbers {
+            self.inner.delete(&part_key(key, number))?;
+        }
+        Ok(())
+    }
+
+    fn abort_multipart(&self, key: &str, upload_id: &str) -> Result<()> {
+        self.parse_upload_id(key, upload_id)?;
+        let mut parts = Vec::new();
+        for info in self.inner.list(&parts_prefix(key))? {
+            if let Some(part) = self.read_manifest(&info.key)? {
+                parts.push((info.key, part));
+            }
+        }
+        parts.sort_by(|a, b| a.0.cmp(&b.0));
+        // finished, but stopped before the parts were cleared: their chunks
+        // belong to the object now
+        let staged: Vec<&(String, u64)> = parts.iter().flat_map(|p| &p.1.chunks).collect();
+        let finished = self.read_manifest(key)
+            .unwrap_or(None)
+            .is_some_and(|m| !staged.is_empty() && m.chunks.iter().eq(staged.iter().cloned()));
+        for (part_key, part) in parts {
+            self.inner.delete(&part_key)?;
+            if !finished {
+                self.release(&part.chunks)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::store::{EncryptedStore, LocalStore};
+
+    use tempfile;
+
+    /// Bytes that do not repeat, so that no two chunks are the same.
+    fn content(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed | 1;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    fn dedup(dir: &::std::path::Path) -> (DedupStore, Arc<LocalStore>) {
+        let inner = Arc::new(LocalStore::new(dir.join("store")).unwrap());
+        let refs = KvStore::open(dir.join("chunks")).unwrap();
+        (DedupStore::new(inner.clone(), refs, None, 0), inner)
+    }
+
+    fn chunks(inner: &dyn ObjectStore, key: &str) -> Vec<String> {
+        read_manifest(inner, key).unwrap().unwrap().chunks.into_iter().map(|c| c.0).collect()
+    }
+
+    #[test]
+    fn chunks_under_encryption_are_named_by_keyed_hash() {
+        let dir = tempfile::tempdir().unwrap();
+        let local = Arc::new(LocalStore::new(dir.path().join("store")).unwrap());
+        let encrypted: Arc<dyn ObjectStore> =
+            Arc::new(EncryptedStore::new(local.clone(), [7; 32]).unwrap());
+        let refs = KvStore::open(dir.path().join("chunks")).unwrap();
+        let store = DedupStore::new(encrypted.clone(), refs, None, 0);
+        let data = content(AVG_CHUNK / 2, 1);
+        store.put("a", &data).unwrap();
+
+        let names = chunks(&*encrypted, "a");
+        assert_eq!(names.len(), 1);
+        assert!(names[0] != to_hex(&sha256(&data)));
+        let naming = encrypted.naming_key().unwrap();
+        assert_eq!(names[0], to_hex(&store::keyed_hash(&naming, &data).unwrap()));
+        assert!(local.head(&chunk_key(&names[0])).unwrap().is_some());
+        assert_eq!(store.get("a").unwrap(), data);
+    }
+
+    #[test]
+    fn shared_chunks_are_counted_and_released() {
+        let dir = tempfile::tempdir().unwrap();
+        let (store, inner) = dedup(dir.path());
+        let data = content(4 * AVG_CHUNK, 1);
+        store.put("a", &data).unwrap();
+        store.put("b", &data).unwrap();
+        let hashes = chunks(&*inner, "a");
+        assert!(hashes.len() > 1);
+        assert_eq!(hashes, chunks(&*inner, "b"));
+        for hash in &hashes {
+            assert_eq!(store.count(hash).unwrap(), 2);
+        }
+
+        store.delete("a").unwrap();
+        for hash in &hashes {
+            assert_eq!(store.count(hash).unwrap(), 1);
+        }
+        assert_eq!(store.get("b").unwrap(), data);
+
+        store.delete("b").unwrap();
+        for hash in &hashes {
+            assert_eq!(store.count(hash).unwrap(), 0);
+            assert!(inner.head(&chunk_key(hash)).unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn overwritten_object_releases_its_chunks() {
+        let dir = tempfile::tempdir().unwrap();
+        let (store, inner) = dedup(dir.path());
+        store.put("a", &content(2 * AVG_CHUNK, 1)).unwrap();
+        let old = chunks(&*inner, "a");
+        let data = content(2 * AVG_CHUNK, 2);
+        store.put("a", &data).unwrap();
+
+        for hash in &old {
+            assert_eq!(store.count(hash).unwrap(), 0);
+            assert!(inner.head(&chunk_key(hash)).unwrap().is_none());
+        }
+        for hash in &chunks(&*inner, "a") {
+            assert_eq!(store.count(hash).unwrap(), 1);
+        }
+        assert_eq!(store.get("a").unwrap(), data);
+    }
+
+    #[test]
+    fn part_sent_again_releases_what_it_held() {
+        let dir = tempfile::tempdir().unwrap();
+        let (store, inner) = dedup(dir.path());
+        let first = content(2 * AVG_CHUNK, 1);
+        let second = content(2 * AVG_CHUNK, 2);
+        let upload_id = store.start_multipart("a", second.len() as u64, second.len() as u64)
+            .unwrap();
+        store.put_part("a", &upload_id, 1, &first).unwrap();
+        let replaced = chunks(&*inner, &part_key("a", 1));
+        let tag = store.put_part("a", &upload_id, 1, &second).unwrap();
+        store.finish_multipart("a", &upload_id, &[(1, tag)]).unwrap();
+
+        for hash in &replaced {
+            assert_eq!(store.count(hash).unwrap(), 0);
+        }
+        for hash in &chunks(&*inner, "a") {
+            assert_eq!(store.count(hash).unwrap(), 1);
+        }
+        assert_eq!(store.get("a").unwrap(), second);
+    }
+
+    #[test]
+    fn chunk_is_dropped_only_at_the_count_seen() {
+        let dir = tempfile::tempdir().unwrap();
+        let (store, inner) = dedup(dir.path());
+        store.put("a", &content(AVG_CHUNK, 1)).unwrap();
+        let hash = chunks(&*inner, "a").remove(0);
+
+        assert!(!store.drop_chunk(&hash, 0).unwrap());
+        assert!(inner.head(&chunk_key(&hash)).unwrap().is_some());
+        assert!(store.drop_chunk(&hash, 1).unwrap());
+        assert!(inner.head(&chunk_key(&hash)).unwrap().is_none());
+    }
+
+    #[test]
+    fn chunks_are_named_by_hash_of_their_content() {
+        let dir = tempfile::tempdir().unwrap();
+        let (store, inner) = dedup(dir.path());
+        let data = content(AVG_CHUNK / 2, 1);
+        store.put("a", &data).unwrap();
+        assert_eq!(chunks(&*inner, "a"), vec![to_hex(&sha256(&data))]);
+    }
+}
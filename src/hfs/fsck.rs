@@ -0,0 +1,425 @@
+use errors::*;
+
+use super::inode::{InodeTable, ROOT_INO};
+use super::stub;
+use super::tier::{Record, Tiering, STATE_DIR};
+use super::trash;
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::path::{Path, PathBuf};
+
+// A check compares three accounts of the filesystem: the backing directory,
+// the inode table and tiering records under the state directory, and the
+// object store. Each disagreement is a finding; with repair, those that can
+// be settled from what is still there are, and the rest are left for a
+// person to look at. It is meant to run while the filesystem is not mounted.
+
+/// Bytes compared at a time when checking content.
+const COMPARE: u64 = 1 << 20;
+
+/// A disagreement between the backing directory, the metadata and the
+/// object store.
+#[derive(Serialize, Debug, Clone)]
+pub struct Finding {
+    /// what is wrong: dangling-inode, moved-inode, inode-cycle,
+    /// directory-cycle, orphaned-record, missing-object, size-mismatch,
+    /// checksum-mismatch, stale-stub or dangling-journal
+    pub kind: &'static str,
+    pub ino: u64,
+    /// relative to the root of the filesystem, where known
+    pub path: Option<String>,
+    pub detail: String,
+    pub repaired: bool,
+}
+
+struct Checking<'a> {
+    backing: &'a Path,
+    tiering: &'a Tiering,
+    inodes: InodeTable,
+    repair: bool,
+    checksums: bool,
+    /// everything in the namespace, by inode
+    present: HashMap<u64, PathBuf>,
+    /// regular files, in the namespace or the trash
+    files: HashMap<u64, (PathBuf, fs::Metadata)>,
+    findings: Vec<Finding>,
+}
+
+impl<'a> Checking<'a> {
+    fn relative(&self, path: &Path) -> String {
+        path.strip_prefix(self.backing).unwrap_or(path).to_string_lossy().into_owned()
+    }
+
+    fn found(&mut self, kind: &'static str, ino: u64, path: Option<&Path>, detail: String) {
+        let path = path.map(|p| self.relative(p));
+        warn!("{}: inode {} {:?}: {}", kind, ino, path, detail);
+        self.findings.push(Finding {
+            kind,
+            ino,
+            path,
+            detail,
+            repaired: false,
+        });
+    }
+
+    fn repaired(&mut self) {
+        if let Some(finding) = self.findings.last_mut() {
+            finding.repaired = true;
+        }
+    }
+
+    /// Walk the backing directory, noting every inode under `dir`.
+    fn walk(&mut self, dir: &Path, namespace: bool, dirs: &mut HashSet<u64>) -> Result<()> {
+        let rd = fs::read_dir(dir).chain_err(|| format!("listing {:?}", dir))?;
+        for entry in rd {
+            let entry = entry.chain_err(|| format!("listing {:?}", dir))?;
+            if dir == self.backing && entry.file_name() == STATE_DIR {
+                continue;
+            }
+            let path = entry.path();
+            let metadata = match fs::symlink_metadata(&path) {
+                Ok(m) => m,
+                // removed since listed
+                Err(_) => continue,
+            };
+            if namespace {
+                self.present.insert(metadata.ino(), path.clone());
+            }
+            if metadata.is_dir() {
+                // only a bind mount or a broken filesystem gets here twice
+                if !dirs.insert(metadata.ino()) {
+                    self.found("directory-cycle",
+                               metadata.ino(),
+                               Some(&path),
+                               "directory reached twice".to_string());
+                    continue;
+                }
+                self.walk(&path, namespace, dirs)?;
+            } else if metadata.is_file() {
+                self.files.insert(metadata.ino(), (path, metadata));
+            }
+        }
+        Ok(())
+    }
+
+    /// Backing path of `ino` as the table records it, without looking
+    /// further than the names it holds.
+    fn located(&self, ino: u64, depth: usize) -> Result<Option<PathBuf>> {
+        if ino == ROOT_INO {
+            return Ok(Some(self.backing.to_path_buf()));
+        }
+        if depth > 4096 {
+            bail!("inode {} is in a parent cycle", ino);
+        }
+        let entry = match self.inodes.entry(ino)? {
+            Some(e) => e,
+            None => return Ok(None),
+        };
+        let mut names = vec![(entry.parent, entry.name)];
+        names.extend(entry.links.into_iter().map(|l| (l.parent, l.name)));
+        for (parent, name) in names {
+            if let Some(parent_path) = self.located(parent, depth + 1)? {
+                let path = parent_path.join(&name);
+                match fs::symlink_metadata(&path) {
+                    Ok(ref m) if m.ino() == ino => return Ok(Some(path)),
+                    _ => {}
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Whether following parents up from `ino` comes back round.
+    fn in_cycle(&self, ino: u64) -> Result<bool> {
+        let mut seen = HashSet::new();
+        let mut at = ino;
+        while at != ROOT_INO {
+            if !seen.insert(at) {
+                return Ok(true);
+            }
+            at = match self.inodes.entry(at)? {
+                Some(e) => e.parent,
+                None => return Ok(false),
+            };
+        }
+        Ok(false)
+    }
+
+    fn inodes(&mut self) -> Result<()> {
+        for ino in self.inodes.inos()? {
+            if self.in_cycle(ino)? {
+                self.found("inode-cycle",
+                           ino,
+                           None,
+                           "parents lead back to the inode".to_string());
+                if self.repair {
+                    // found again by name on the next lookup
+                    self.inodes.remove(ino)?;
+                    self.repaired();
+                }
+                continue;
+            }
+            if self.located(ino, 0)?.is_some() {
+                continue;
+            }
+            match self.present.get(&ino).cloned() {
+                Some(path) => {
+                    self.found("moved-inode",
+                               ino,
+                               Some(&path),
+                               "moved outside the mount".to_string());
+                    if self.repair {
+                        let parent = path.parent().unwrap_or(self.backing);
+                        let parent_ino = if parent == self.backing {
+                            ROOT_INO
+                        } else {
+                            fs::symlink_metadata(parent)
+                                .chain_err(|| format!("stat {:?}", parent))?
+                                .ino()
+                        };
+                        let name = path.file_name().unwrap_or_default().to_string_lossy();
+                        // in place of the primary name, keeping any links
+                        if let Some(entry) = self.inodes.entry(ino)? {
+                            self.inodes
+                                .rename(ino, (entry.parent, &entry.name), (parent_ino, &name))?;
+                        }
+                        self.repaired();
+                    }
+                }
+                None => {
+                    self.found("dangling-inode",
+                               ino,
+                               None,
+                               "no longer in the backing directory".to_string());
+                    if self.repair {
+                        self.inodes.remove(ino)?;
+                        self.repaired();
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn records(&mut self) -> Result<()> {
+        for (ino, record) in self.tiering.records()? {
+            let (path, metadata) = match self.files.get(&ino) {
+                Some(f) => f.clone(),
+                None => {
+                    let detail = if record.resident || record.key.is_empty() {
+                        "no backing file".to_string()
+                    } else {
+                        format!("no backing file; content left in {}", record.key)
+                    };
+                    self.found("orphaned-record", ino, None, detail);
+                    if self.repair {
+                        // the object, if any, is left for gc
+                        self.tiering.discard(ino)?;
+                        self.repaired();
+                    }
+                    continue;
+                }
+            };
+            self.record(ino, &record, &path, &metadata)?;
+        }
+        Ok(())
+    }
+
+    fn record(&mut self,
+              ino: u64,
+              record: &Record,
+              path: &Path,
+              metadata: &fs::Metadata)
+              -> Result<()> {
+        let unchanged = metadata.len() == record.length &&
+                        stub::mtime_of(metadata) == record.mtime;
+        if !record.resident && !unchanged {
+            self.found("stale-stub",
+                       ino,
+                       Some(path),
+                       format!("placeholder is {} bytes at {:?}, recorded {} bytes at {:?}",
+                               metadata.len(),
+                               stub::mtime_of(metadata),
+                               record.length,
+                               record.mtime));
+            if self.repair {
+                // written outside the mount since eviction: what is there now wins
+                self.tiering.reset(ino)?;
+                self.repaired();
+            }
+            return Ok(());
+        }
+        if record.key.is_empty() {
+            return Ok(());
+        }
+
+        let trouble = match self.tiering.object_info(record)? {
+            None => Some(("missing-object", format!("{} is not in the object store", record.key))),
+            Some(ref info) if info.size != record.size => {
+                Some(("size-mismatch",
+                      format!("{} holds {} bytes, recorded {}",
+                              record.key,
+                              info.size,
+                              record.size)))
+            }
+            Some(_) => {
+                if self.checksums && record.resident && record.synced && unchanged &&
+                   !self.same_content(record, path)? {
+                    Some(("checksum-mismatch",
+                          format!("{} differs from the backing file", record.key)))
+                } else {
+                    None
+                }
+            }
+        };
+        if let Some((kind, detail)) = trouble {
+            if record.resident {
+                self.found(kind, ino, Some(path), detail);
+                if self.repair {
+                    // the backing file still holds the content: upload it again
+                    self.tiering.reset(ino)?;
+                    self.repaired();
+                }
+            } else {
+                self.found(kind,
+                           ino,
+                           Some(path),
+                           format!("{}; evicted, only cached blocks remain", detail));
+            }
+        }
+        Ok(())
+    }
+
+    fn same_content(&self, record: &Record, path: &Path) -> Result<bool> {
+        let file = fs::File::open(path).chain_err(|| format!("opening {:?}", path))?;
+        let mut offset = 0;
+        while offset < record.size {
+            let len = COMPARE.min(record.size - offset);
+            let stored = self.tiering
+                .read_object(&record.key, record.compressed, record.chunked, offset, len)?;
+            let mut local = vec![0; len as usize];
+            file.read_exact_at(&mut local, offset).chain_err(|| format!("reading {:?}", path))?;
+            if stored != local {
+                return Ok(false);
+            }
+            offset += len;
+        }
+        Ok(true)
+    }
+
+    fn journal(&mut self) -> Result<()> {
+        for (ino, _) in self.tiering.pending() {
+            if !self.files.contains_key(&ino) {
+                self.found("dangling-journal",
+                           ino,
+                           None,
+                           "queued for upload but no backing file".to_string());
+                if self.repair {
+                    self.tiering.unqueue(ino)?;
+                    self.repaired();
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Check the filesystem backed by `backing` against its metadata and the
+/// object store, and with `repair` settle what can be. With `checksums`
+/// the content of resident files is compared with their objects too.
+pub fn check(backing: &Path,
+             tiering: &Tiering,
+             repair: bool,
+             checksums: bool)
+             -> Result<Vec<Finding>> {
+    let mut checking = Checking {
+        backing,
+        tiering,
+        inodes: InodeTable::open(backing)?,
+        repair,
+        checksums,
+        present: HashMap::new(),
+        files: HashMap::new(),
+        findings: Vec::new(),
+    };
+    let mut dirs = HashSet::new();
+    checking.walk(backing, true, &mut dirs)?;
+    let trash = trash::dir_of(backing);
+    if trash.is_dir() {
+        checking.walk(&trash, false, &mut dirs)?;
+    }
+    checking.inodes()?;
+    checking.records()?;
+    checking.journal()?;
+    Ok(checking.findings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::store::ObjectStore;
+    use super::super::tier::object_key;
+    use super::super::tier::tests::{content, evict, file, tiering};
+
+    use std::time::Duration;
+    use tempfile;
+
+    fn kinds(findings: &[Finding]) -> Vec<(&'static str, bool)> {
+        findings.iter().map(|f| (f.kind, f.repaired)).collect()
+    }
+
+    #[test]
+    fn inode_moved_outside_the_mount_is_renamed_in_place() {
+        let dir = tempfile::tempdir().unwrap();
+        let (tiering, _) = tiering(dir.path(), Duration::from_secs(0));
+        let backing = dir.path().join("backing");
+        let (path, ino) = file(&tiering, "a", &content(100));
+        InodeTable::open(&backing).unwrap().insert(ino, ROOT_INO, "a").unwrap();
+        fs::rename(&path, backing.join("b")).unwrap();
+
+        let findings = check(&backing, &tiering, false, false).unwrap();
+        assert_eq!(kinds(&findings), vec![("moved-inode", false)]);
+        assert_eq!(findings[0].ino, ino);
+        assert_eq!(findings[0].path, Some("b".to_string()));
+        assert_eq!(InodeTable::open(&backing).unwrap().entry(ino).unwrap().unwrap().name, "a");
+
+        let findings = check(&backing, &tiering, true, false).unwrap();
+        assert_eq!(kinds(&findings), vec![("moved-inode", true)]);
+        let entry = InodeTable::open(&backing).unwrap().entry(ino).unwrap().unwrap();
+        assert_eq!((entry.parent, entry.name.as_str()), (ROOT_INO, "b"));
+        assert!(check(&backing, &tiering, false, false).unwrap().is_empty());
+    }
+
+    #[test]
+    fn record_of_a_removed_file_is_discarded() {
+        let dir = tempfile::tempdir().unwrap();
+        let (tiering, store) = tiering(dir.path(), Duration::from_secs(0));
+        let backing = dir.path().join("backing");
+        let (path, ino) = file(&tiering, "a", &content(100));
+        evict(&tiering, &path, ino);
+        fs::remove_file(&path).unwrap();
+
+        let findings = check(&backing, &tiering, true, false).unwrap();
+        assert_eq!(kinds(&findings), vec![("orphaned-record", true)]);
+        assert!(findings[0].detail.contains(&object_key(ino)));
+        assert!(tiering.records().unwrap().is_empty());
+        // left for gc
+        assert!(store.get(&object_key(ino)).is_ok());
+    }
+
+    #[test]
+    fn missing_object_of_an_evicted_file_is_not_repaired() {
+        let dir = tempfile::tempdir().unwrap();
+        let (tiering, store) = tiering(dir.path(), Duration::from_secs(0));
+        let backing = dir.path().join("backing");
+        let (path, ino) = file(&tiering, "a", &content(100));
+        evict(&tiering, &path, ino);
+        store.delete(&object_key(ino)).unwrap();
+
+        let findings = check(&backing, &tiering, true, false).unwrap();
+        assert_eq!(kinds(&findings), vec![("missing-object", false)]);
+        assert!(findings[0].detail.ends_with("evicted, only cached blocks remain"));
+    }
+}
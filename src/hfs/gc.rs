@@ -0,0 +1,257 @@
+use errors::*;
+
+use super::dedup;
+use super::kv::KvStore;
+use super::store;
+use super::tier::{self, Tiering, STATE_DIR};
+
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, UNIX_EPOCH};
+
+// Garbage collection marks what the metadata refers to in the object
+// store: the objects of files, versions, snapshots and unfinished uploads,
+// their frames and staged parts, the chunks their manifests name, and the
+// sidecars of files with records. Whatever else is under the prefixes the
+// filesystem writes to was left behind by a crash, a failed upload or an
+// interrupted delete, and is swept.
+//
+// An object is only deleted once runs at least the grace period apart have
+// found it unreferenced and it has not been written for as long, so that
+// nothing an upload under way has written but not yet recorded is swept.
+
+/// Prefixes of the keys the filesystem writes; nothing else is touched.
+const PREFIXES: &[&str] = &["data/", "meta/", "chunks/"];
+/// Keys found unreferenced, with the time they first were.
+const ORPHANS: &str = "orphans";
+
+/// When orphaned objects are deleted.
+#[derive(Debug, Clone)]
+pub struct GcPolicy {
+    /// how long an object must have been found unreferenced to be deleted
+    pub grace: Duration,
+    /// how often a mount collects in the background; zero never
+    pub every: Duration,
+}
+
+/// An object nothing refers to.
+#[derive(Debug, Clone)]
+pub struct Orphan {
+    pub key: String,
+    /// object, frame, part, chunk or sidecar
+    pub kind: &'static str,
+    pub size: u64,
+    /// milliseconds since the epoch at which it was first found unreferenced
+    pub since: u64,
+    /// past its grace period
+    pub due: bool,
+    pub deleted: bool,
+}
+
+fn millis(duration: Duration) -> u64 {
+    duration.as_secs() * 1000 + u64::from(duration.subsec_millis())
+}
+
+/// Find the objects nothing refers to and, unless `dry_run`, delete those
+/// that have been unreferenced for the grace period.
+pub fn collect(backing: &Path,
+               tiering: &Tiering,
+               grace: Duration,
+               dry_run: bool)
+               -> Result<Vec<Orphan>> {
+    let started = tier::now_millis();
+    let orphans = KvStore::open(backing.join(STATE_DIR).join(ORPHANS))?;
+    let store = tiering.store();
+    let live = tiering.live()?;
+    let mut listing = Vec::new();
+    for prefix in PREFIXES {
+        listing.extend(store.list(prefix)?);
+    }
+
+    // counted before the manifests are read, so that a chunk taken by an
+    // upload whose manifest is not yet written is seen to be in use
+    let mut counts = HashMap::new();
+    if !dry_run {
+        for hash in listing.iter().filter_map(|i| dedup::chunk_of(&i.key)) {
+            counts.insert(hash.to_string(), tiering.chunk_count(hash)?);
+        }
+    }
+
+    // a manifest that cannot be read may name any chunk, so nothing is swept
+    let mut chunks = HashSet::new();
+    let mut manifests: Vec<&str> =
+        live.objects.iter().filter(|o| *o.1).map(|o| o.0.as_str()).collect();
+    manifests.extend(listing.iter()
+        .filter(|i| dedup::part_of(&i.key).is_some_and(|o| live.objects.contains_key(o)))
+        .map(|i| i.key.as_str()));
+    for key in manifests {
+        if let Some(manifest) = dedup::read_manifest(store, key)? {
+            chunks.extend(manifest.chunks.into_iter().map(|c| c.0));
+        }
+    }
+
+    let mut found = Vec::new();
+    let mut seen = HashSet::new();
+    for info in &listing {
+        let kind = if let Some(hash) = dedup::chunk_of(&info.key) {
+            if chunks.contains(hash) {
+                continue;
+            }
+            "chunk"
+        } else if let Some(owner) = store::frame_of(&info.key) {
+            if live.objects.contains_key(owner) {
+                continue;
+            }
+            "frame"
+        } else if let Some(owner) = dedup::part_of(&info.key) {
+            if live.objects.contains_key(owner) {
+                continue;
+            }
+            "part"
+        } else if let Some(ino) = info.key.strip_prefix("meta/") {
+            if u64::from_str_radix(ino, 16).is_ok_and(|i| live.inos.contains(&i)) {
+                continue;
+            }
+            "sidecar"
+        } else {
+            if live.objects.contains_key(&info.key) {
+                continue;
+            }
+            "object"
+        };
+        seen.insert(info.key.clone());
+        let since = match orphans.get::<u64>(&info.key)? {
+            Some(since) => since,
+            None => {
+                if !dry_run {
+                    orphans.put(&info.key, &started)?;
+                }
+                started
+            }
+        };
+        let written = info.modified
+            .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+            .map(millis)
+            .unwrap_or(0);
+        let due = started >= since + millis(grace) && started >= written + millis(grace);
+        let mut orphan = Orphan {
+            key: info.key.clone(),
+            kind,
+            size: info.size,
+            since,
+            due,
+            deleted: false,
+        };
+        if due && !dry_run {
+            orphan.deleted = match dedup::chunk_of(&info.key) {
+                Some(hash) => {
+                    // counted again since: in use after all
+                    tiering.drop_chunk(hash, counts.get(hash).cloned().unwrap_or(0))?
+                }
+                None => {
+                    store.delete(&info.key)?;
+                    true
+                }
+            };
+            if orphan.deleted {
+                orphans.delete(&info.key)?;
+                debug!("deleted orphaned {} {}", kind, info.key);
+            }
+        }
+        found.push(orphan);
+    }
+
+    if !dry_run {
+        // referred to again, or gone
+        for key in orphans.keys()? {
+            if !seen.contains(&key) {
+                orphans.delete(&key)?;
+            }
+        }
+    }
+    Ok(found)
+}
+
+/// Collect garbage every `policy.every`, unless that is zero.
+pub fn start(backing: &Path, tiering: Arc<Tiering>, policy: GcPolicy) -> Result<()> {
+    if policy.every.is_zero() {
+        return Ok(());
+    }
+    let backing = backing.to_path_buf();
+    thread::Builder::new()
+        .name("gc".to_string())
+        .spawn(move || loop {
+            thread::sleep(policy.every);
+            match collect(&backing, &tiering, policy.grace, false) {
+                Ok(found) => {
+                    let deleted: Vec<&Orphan> = found.iter().filter(|o| o.deleted).collect();
+                    info!("gc: {} orphaned objects, {} deleted, {} bytes freed",
+                          found.len(),
+                          deleted.len(),
+                          deleted.iter().map(|o| o.size).sum::<u64>());
+                }
+                Err(e) => warn!("collecting garbage: {}", e),
+            }
+        })
+        .chain_err(|| "starting gc")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::store::ObjectStore;
+    use super::super::tier::object_key;
+    use super::super::tier::tests::{content, evict, file, tiering};
+
+    use tempfile;
+
+    #[test]
+    fn only_unreferenced_objects_are_found() {
+        let dir = tempfile::tempdir().unwrap();
+        let (tiering, store) = tiering(dir.path(), Duration::from_secs(0));
+        let (path, ino) = file(&tiering, "a", &content(100));
+        evict(&tiering, &path, ino);
+        store.put("data/stray", b"left behind").unwrap();
+
+        let backing = dir.path().join("backing");
+        let found = collect(&backing, &tiering, Duration::from_secs(3600), false).unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].key, "data/stray");
+        assert_eq!(found[0].kind, "object");
+        assert!(!found[0].due && !found[0].deleted);
+        assert!(store.get("data/stray").is_ok());
+
+        let found = collect(&backing, &tiering, Duration::from_secs(0), true).unwrap();
+        assert!(found[0].due && !found[0].deleted);
+        assert!(store.get("data/stray").is_ok());
+
+        let found = collect(&backing, &tiering, Duration::from_secs(0), false).unwrap();
+        assert!(found[0].deleted);
+        assert!(store.get("data/stray").is_err());
+        assert!(store.get(&object_key(ino)).is_ok());
+    }
+
+    #[test]
+    fn grace_period_runs_from_the_first_run_to_find_an_object() {
+        let dir = tempfile::tempdir().unwrap();
+        let (tiering, store) = tiering(dir.path(), Duration::from_secs(0));
+        store.put("data/stray", b"left behind").unwrap();
+        let backing = dir.path().join("backing");
+        let grace = Duration::from_millis(200);
+        thread::sleep(grace);
+
+        let first = collect(&backing, &tiering, grace, false).unwrap();
+        assert!(!first[0].due);
+        let again = collect(&backing, &tiering, grace, false).unwrap();
+        assert_eq!(again[0].since, first[0].since);
+        assert!(!again[0].deleted);
+
+        thread::sleep(grace);
+        let last = collect(&backing, &tiering, grace, false).unwrap();
+        assert!(last[0].deleted);
+        assert!(collect(&backing, &tiering, grace, false).unwrap().is_empty());
+    }
+}
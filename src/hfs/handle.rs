@@ -0,0 +1,99 @@
+use libc;
+
+use fuse::consts::{FOPEN_DIRECT_IO, FOPEN_KEEP_CACHE};
+
+use std::fs::{File, OpenOptions};
+use std::os::unix::fs::OpenOptionsExt;
+
+/// An open file on the mount, with the flags it was opened with.
+pub struct Handle {
+    pub ino: u64,
+    pub file: File,
+    pub flags: u32,
+    /// written through since the last flush
+    pub dirty: bool,
+    /// where the next read starts if access is sequential
+    next_offset: u64,
+    /// reads in a row that carried on from the one before
+    streak: u32,
+    /// end of the range already handed to the prefetcher
+    pub prefetched_to: u64,
+}
+
+impl Handle {
+    pub fn new(ino: u64, file: File, flags: u32) -> Handle {
+        Handle {
+            ino,
+            file,
+            flags,
+            dirty: false,
+            next_offset: 0,
+            streak: 0,
+            prefetched_to: 0,
+        }
+    }
+
+    /// Note a read of `len` bytes at `offset`. Returns how many reads in a
+    /// row, this one included, carried on from the one before.
+    pub fn note_read(&mut self, offset: u64, len: u64) -> u32 {
+        if offset == self.next_offset {
+            self.streak = self.streak.saturating_add(1);
+        } else {
+            self.streak = 0;
+            self.prefetched_to = 0;
+        }
+        self.next_offset = offset + len;
+        self.streak
+    }
+
+    fn access_mode(&self) -> libc::c_int {
+        self.flags as libc::c_int & libc::O_ACCMODE
+    }
+
+    pub fn readable(&self) -> bool {
+        self.access_mode() != libc::O_WRONLY
+    }
+
+    pub fn writable(&self) -> bool {
+        self.access_mode() != libc::O_RDONLY
+    }
+
+    pub fn appending(&self) -> bool {
+        self.flags as libc::c_int & libc::O_APPEND != 0
+    }
+}
+
+/// Options for opening the backing file as the kernel's `flags` ask.
+/// O_DIRECT is not passed on: the mount bypasses the page cache by replying
+/// with direct_io instead, and the backing file keeps ordinary alignment rules.
+pub fn open_options_from(flags: u32) -> OpenOptions {
+    let flags = flags as libc::c_int;
+    let mut options = OpenOptions::new();
+    match flags & libc::O_ACCMODE {
+        libc::O_WRONLY => options.write(true),
+        libc::O_RDWR => options.read(true).write(true),
+        _ => options.read(true),
+    };
+    if flags & libc::O_APPEND != 0 {
+        options.append(true);
+    }
+    if flags & libc::O_TRUNC != 0 && flags & libc::O_ACCMODE != libc::O_RDONLY {
+        options.truncate(true);
+    }
+    let passed = libc::O_NOFOLLOW | libc::O_NOATIME | libc::O_SYNC | libc::O_DSYNC;
+    options.custom_flags(flags & passed);
+    options
+}
+
+/// Open flags for the reply to the kernel.
+pub fn reply_flags_from(flags: u32) -> u32 {
+    let flags = flags as libc::c_int;
+    if flags & libc::O_DIRECT != 0 {
+        FOPEN_DIRECT_IO
+    } else if flags & libc::O_ACCMODE == libc::O_RDONLY {
+        // writes all come through the mount, so cached pages stay valid
+        FOPEN_KEEP_CACHE
+    } else {
+        0
+    }
+}
@@ -0,0 +1,465 @@
+use errors::*;
+
+use super::kv::KvStore;
+use super::tier::STATE_DIR;
+
+use std::collections::HashMap;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+/// Inode number the kernel uses for the root of the mount.
+pub const ROOT_INO: u64 = 1;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Link {
+    pub parent: u64,
+    pub name: String,
+}
+
+/// Where an inode sits in the namespace.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Entry {
+    pub parent: u64,
+    pub name: String,
+    /// further names of a hard-linked file
+    #[serde(default)]
+    pub links: Vec<Link>,
+}
+
+impl Entry {
+    fn new(parent: u64, name: &str) -> Entry {
+        Entry {
+            parent,
+            name: name.to_string(),
+            links: Vec::new(),
+        }
+    }
+
+    fn names(&self) -> Vec<Link> {
+        let mut names = vec![Link {
+                                 parent: self.parent,
+                                 name: self.name.clone(),
+                             }];
+        names.extend(self.links.iter().cloned());
+        names
+    }
+
+    fn has_name(&self, parent: u64, name: &str) -> bool {
+        self.names().iter().any(|l| l.parent == parent && l.name == name)
+    }
+
+    /// Drop one name, promoting a hard link if it was the primary.
+    /// Returns false if no names remain.
+    fn remove_name(&mut self, parent: u64, name: &str) -> bool {
+        if self.parent == parent && self.name == name {
+            if self.links.is_empty() {
+                return false;
+            }
+            let next = self.links.remove(0);
+            self.parent = next.parent;
+            self.name = next.name;
+        } else {
+            self.links.retain(|l| l.parent != parent || l.name != name);
+        }
+        true
+    }
+}
+
+struct Held {
+    entry: Entry,
+    lookups: u64,
+}
+
+/// Persistent map from inode number to parent and name, kept under the
+/// backing path so that inodes handed to the kernel stay resolvable across
+/// remounts. Inode numbers are those of the backing filesystem, except the
+/// root which is always `ROOT_INO`.
+///
+/// Inodes the kernel currently holds are also kept in memory with their
+/// lookup count, and dropped from memory when the kernel forgets them.
+pub struct InodeTable {
+    backing: PathBuf,
+    entries: KvStore,
+    held: HashMap<u64, Held>,
+    /// number of entries, counted on first use
+    count: Option<u64>,
+}
+
+fn entry_key(ino: u64) -> String {
+    format!("{:016x}", ino)
+}
+
+impl InodeTable {
+    pub fn open(backing: &Path) -> Result<InodeTable> {
+        let entries = KvStore::open(backing.join(STATE_DIR).join("inodes"))?;
+        Ok(InodeTable {
+            backing: backing.to_path_buf(),
+            entries,
+            held: HashMap::new(),
+            count: None,
+        })
+    }
+
+    pub fn entry(&self, ino: u64) -> Result<Option<Entry>> {
+        if let Some(held) = self.held.get(&ino) {
+            return Ok(Some(held.entry.clone()));
+        }
+        self.entries.get(&entry_key(ino))
+    }
+
+    /// Every inode in the table but the root.
+    pub fn inos(&self) -> Result<Vec<u64>> {
+        Ok(self.entries.keys()?.iter().filter_map(|k| u64::from_str_radix(k, 16).ok()).collect())
+    }
+
+    /// Number of inodes in the table, including the root.
+    pub fn len(&mut self) -> Result<u64> {
+        if let Some(n) = self.count {
+            return Ok(n);
+        }
+        let n = self.entries.keys()?.len() as u64 + 1;
+        self.count = Some(n);
+        Ok(n)
+    }
+
+    fn store_new(&mut self, ino: u64, entry: Entry) -> Result<()> {
+        self.store(ino, entry)?;
+        if let Some(ref mut n) = self.count {
+            *n += 1;
+        }
+        Ok(())
+    }
+
+    fn store(&mut self, ino: u64, entry: Entry) -> Result<()> {
+        self.entries.put(&entry_key(ino), &entry)?;
+        if let Some(held) = self.held.get_mut(&ino) {
+            held.entry = entry;
+        }
+        Ok(())
+    }
+
+    /// Record `name` in `parent` as a name of `ino`. A new name becomes an
+    /// extra hard link if the recorded one still refers to `ino`, and
+    /// replaces it otherwise, as after a rename outside the mount.
+    pub fn insert(&mut self, ino: u64, parent: u64, name: &str) -> Result<()> {
+        let mut entry = match self.entry(ino)? {
+            None => return self.store_new(ino, Entry::new(parent, name)),
+            Some(ref e) if e.has_name(parent, name) => return Ok(()),
+            Some(e) => e,
+        };
+        let still_there = match self.parent_path(entry.parent)? {
+            Some(p) => {
+                fs::symlink_metadata(p.join(&entry.name))
+                    .map(|m| m.ino() == ino)
+                    .unwrap_or(false)
+            }
+            None => false,
+        };
+        if still_there {
+            entry.links.push(Link {
+                parent,
+                name: name.to_string(),
+            });
+        } else {
+            entry.parent = parent;
+            entry.name = name.to_string();
+        }
+        self.store(ino, entry)
+    }
+
+    /// `ino` moved from one name to another.
+    pub fn rename(&mut self, ino: u64, from: (u64, &str), to: (u64, &str)) -> Result<()> {
+        let mut entry = match self.entry(ino)? {
+            Some(e) => e,
+            None => return self.store_new(ino, Entry::new(to.0, to.1)),
+        };
+        if entry.parent == from.0 && entry.name == from.1 {
+            entry.parent = to.0;
+            entry.name = to.1.to_string();
+        } else if let Some(link) = entry.links
+            .iter_mut()
+            .find(|l| l.parent == from.0 && l.name == from.1) {
+            link.parent = to.0;
+            link.name = to.1.to_string();
+        } else {
+            entry.parent = to.0;
+            entry.name = to.1.to_string();
+        }
+        // renaming one link over another of the same inode leaves one name
+        let mut seen = vec![Link {
+                                parent: entry.parent,
+                                name: entry.name.clone(),
+                            }];
+        entry.links.retain(|l| {
+            if seen.contains(l) {
+                return false;
+            }
+            seen.push(l.clone());
+            true
+        });
+        self.store(ino, entry)
+    }
+
+    /// Drop one name of `ino`, and the whole entry with its last name.
+    pub fn remove_name(&mut self, ino: u64, parent: u64, name: &str) -> Result<()> {
+        let mut entry = match self.entry(ino)? {
+            Some(e) => e,
+            None => return Ok(()),
+        };
+        if entry.remove_name(parent, name) {
+            self.store(ino, entry)
+        } else {
+            self.remove(ino)
+        }
+    }
+
+    /// Record that the kernel was handed `ino` in an entry reply, which it
+    /// balances later with `forget`.
+    pub fn looked_up(&mut self, ino: u64, parent: u64, name: &str) -> Result<()> {
+        self.insert(ino, parent, name)?;
+        if ino == ROOT_INO {
+            return Ok(());
+        }
+        if let Some(held) = self.held.get_mut(&ino) {
+            held.lookups += 1;
+            return Ok(());
+        }
+        if let Some(entry) = self.entry(ino)? {
+            self.held.insert(ino, Held { entry, lookups: 1 });
+        }
+        Ok(())
+    }
+
+    pub fn forget(&mut self, ino: u64, nlookup: u64) {
+        let gone = match self.held.get_mut(&ino) {
+            Some(held) => {
+                held.lookups = held.lookups.saturating_sub(nlookup);
+                held.lookups == 0
+            }
+            None => false,
+        };
+        if gone {
+            self.held.remove(&ino);
+        }
+    }
+
+    /// Number of inodes the kernel currently holds.
+    pub fn held(&self) -> usize {
+        self.held.len()
+    }
+
+    pub fn remove(&mut self, ino: u64) -> Result<()> {
+        if let Some(held) = self.held.get(&ino) {
+            // still referenced by the kernel, which may getattr until it forgets
+            debug!("removing inode {} with {} lookups outstanding", ino, held.lookups);
+        }
+        if self.count.is_some() && self.entries.get::<Entry>(&entry_key(ino))?.is_some() {
+            self.count = self.count.map(|n| n.saturating_sub(1));
+        }
+        self.entries.delete(&entry_key(ino))
+    }
+
+    /// Backing path of `ino`, or `None` if it is unknown or no longer exists.
+    ///
+    /// The result is checked against the backing directory. An entry renamed
+    /// within its directory outside the mount is found again by scanning the
+    /// parent, and the table corrected.
+    pub fn path(&mut self, ino: u64) -> Result<Option<PathBuf>> {
+        self.resolve(ino, 0)
+    }
+
+    fn parent_path(&mut self, parent: u64) -> Result<Option<PathBuf>> {
+        self.resolve(parent, 1)
+    }
+
+    fn resolve(&mut self, ino: u64, depth: usize) -> Result<Option<PathBuf>> {
+        if ino == ROOT_INO {
+            return Ok(Some(self.backing.clone()));
+        }
+        if depth > 4096 {
+            bail!("inode {} is in a parent cycle", ino);
+        }
+        let entry = match self.entry(ino)? {
+            Some(e) => e,
+            None => return Ok(None),
+        };
+
+        // any name will do for a hard-linked file
+        for link in entry.names() {
+            if let Some(parent_path) = self.resolve(link.parent, depth + 1)? {
+                let path = parent_path.join(&link.name);
+                match fs::symlink_metadata(&path) {
+                    Ok(ref m) if m.ino() == ino => return Ok(Some(path)),
+                    _ => {}
+                }
+            }
+        }
+
+        let parent_path = match self.resolve(entry.parent, depth + 1)? {
+            Some(p) => p,
+            None => return Ok(None),
+        };
+        debug!("inode {} no longer at {:?}, searching parent", ino, entry.name);
+        let rd = match fs::read_dir(&parent_path) {
+            Ok(rd) => rd,
+            Err(_) => return Ok(None),
+        };
+        for dirent in rd.filter_map(|d| d.ok()) {
+            use std::os::unix::fs::DirEntryExt;
+            if dirent.ino() != ino {
+                continue;
+            }
+            if let Ok(name) = dirent.file_name().into_string() {
+                let mut moved = entry.clone();
+                moved.name = name.clone();
+                self.store(ino, moved)?;
+                return Ok(Some(parent_path.join(name)));
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tempfile;
+
+    fn mkdir(path: &Path) -> u64 {
+        fs::create_dir(path).unwrap();
+        fs::metadata(path).unwrap().ino()
+    }
+
+    fn touch(path: &Path) -> u64 {
+        fs::write(path, b"").unwrap();
+        fs::metadata(path).unwrap().ino()
+    }
+
+    #[test]
+    fn paths_outlive_the_table() {
+        let dir = tempfile::tempdir().unwrap();
+        let backing = dir.path();
+        let d = mkdir(&backing.join("d"));
+        let f = touch(&backing.join("d/f"));
+        {
+            let mut table = InodeTable::open(backing).unwrap();
+            table.insert(d, ROOT_INO, "d").unwrap();
+            table.insert(f, d, "f").unwrap();
+        }
+
+        let mut table = InodeTable::open(backing).unwrap();
+        assert_eq!(table.path(f).unwrap(), Some(backing.join("d/f")));
+        assert_eq!(table.path(ROOT_INO).unwrap(), Some(backing.to_path_buf()));
+        assert_eq!(table.len().unwrap(), 3);
+    }
+
+    #[test]
+    fn entry_renamed_outside_the_mount_is_found_in_its_parent() {
+        let dir = tempfile::tempdir().unwrap();
+        let backing = dir.path();
+        let f = touch(&backing.join("f"));
+        let mut table = InodeTable::open(backing).unwrap();
+        table.insert(f, ROOT_INO, "f").unwrap();
+
+        fs::rename(backing.join("f"), backing.join("g")).unwrap();
+        assert_eq!(table.path(f).unwrap(), Some(backing.join("g")));
+        assert_eq!(table.entry(f).unwrap().unwrap().name, "g");
+
+        fs::remove_file(backing.join("g")).unwrap();
+        assert_eq!(table.path(f).unwrap(), None);
+    }
+
+    #[test]
+    fn inode_is_held_until_forgotten_as_often_as_looked_up() {
+        let dir = tempfile::tempdir().unwrap();
+        let backing = dir.path();
+        let f = touch(&backing.join("f"));
+        let mut table = InodeTable::open(backing).unwrap();
+
+        table.looked_up(f, ROOT_INO, "f").unwrap();
+        table.looked_up(f, ROOT_INO, "f").unwrap();
+        assert_eq!(table.held(), 1);
+        table.forget(f, 1);
+        assert_eq!(table.held(), 1);
+        table.forget(f, 1);
+        assert_eq!(table.held(), 0);
+        // forgotten by the kernel, but still resolvable from the table
+        assert_eq!(table.path(f).unwrap(), Some(backing.join("f")));
+    }
+
+    #[test]
+    fn renamed_directory_takes_its_contents_along() {
+        let dir = tempfile::tempdir().unwrap();
+        let backing = dir.path();
+        let d = mkdir(&backing.join("d"));
+        let e = mkdir(&backing.join("e"));
+        let f = touch(&backing.join("d/f"));
+        let mut table = InodeTable::open(backing).unwrap();
+        table.insert(d, ROOT_INO, "d").unwrap();
+        table.insert(e, ROOT_INO, "e").unwrap();
+        table.insert(f, d, "f").unwrap();
+
+        fs::rename(backing.join("d"), backing.join("e/g")).unwrap();
+        table.rename(d, (ROOT_INO, "d"), (e, "g")).unwrap();
+        assert_eq!(table.path(d).unwrap(), Some(backing.join("e/g")));
+        assert_eq!(table.path(f).unwrap(), Some(backing.join("e/g/f")));
+    }
+
+    #[test]
+    fn rename_moves_only_the_link_renamed() {
+        let dir = tempfile::tempdir().unwrap();
+        let backing = dir.path();
+        let f = touch(&backing.join("a"));
+        fs::hard_link(backing.join("a"), backing.join("b")).unwrap();
+        let mut table = InodeTable::open(backing).unwrap();
+        table.insert(f, ROOT_INO, "a").unwrap();
+        table.insert(f, ROOT_INO, "b").unwrap();
+
+        fs::rename(backing.join("b"), backing.join("c")).unwrap();
+        table.rename(f, (ROOT_INO, "b"), (ROOT_INO, "c")).unwrap();
+        let entry = table.entry(f).unwrap().unwrap();
+        assert_eq!(entry.name, "a");
+        assert_eq!(entry.links,
+                   vec![Link {
+                            parent: ROOT_INO,
+                            name: "c".to_string(),
+                        }]);
+    }
+
+    #[test]
+    fn link_takes_over_when_the_primary_name_goes() {
+        let dir = tempfile::tempdir().unwrap();
+        let backing = dir.path();
+        let f = touch(&backing.join("a"));
+        fs::hard_link(backing.join("a"), backing.join("b")).unwrap();
+        let mut table = InodeTable::open(backing).unwrap();
+        table.insert(f, ROOT_INO, "a").unwrap();
+        table.insert(f, ROOT_INO, "b").unwrap();
+        assert_eq!(table.entry(f).unwrap().unwrap().links.len(), 1);
+
+        fs::remove_file(backing.join("a")).unwrap();
+        table.remove_name(f, ROOT_INO, "a").unwrap();
+        let entry = table.entry(f).unwrap().unwrap();
+        assert_eq!((entry.name.as_str(), entry.links.len()), ("b", 0));
+        assert_eq!(table.path(f).unwrap(), Some(backing.join("b")));
+
+        table.remove_name(f, ROOT_INO, "b").unwrap();
+        assert!(table.entry(f).unwrap().is_none());
+    }
+
+    #[test]
+    fn name_reused_after_a_move_replaces_the_old_one() {
+        let dir = tempfile::tempdir().unwrap();
+        let backing = dir.path();
+        let f = touch(&backing.join("a"));
+        let mut table = InodeTable::open(backing).unwrap();
+        table.insert(f, ROOT_INO, "a").unwrap();
+
+        fs::rename(backing.join("a"), backing.join("b")).unwrap();
+        table.insert(f, ROOT_INO, "b").unwrap();
+        let entry = table.entry(f).unwrap().unwrap();
+        assert_eq!((entry.name.as_str(), entry.links.len()), ("b", 0));
+    }
+}
@@ -0,0 +1,153 @@
+use errors::*;
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json;
+
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// A small persistent key-value store: one JSON document per key, spread over
+/// 256 shard directories. Values are replaced atomically by rename, so a
+/// crash leaves either the old or the new value in place.
+pub struct KvStore {
+    root: PathBuf,
+}
+
+fn shard_of(key: &str) -> u8 {
+    // FNV-1a, folded to a byte
+    let mut hash: u32 = 0x811c9dc5;
+    for b in key.bytes() {
+        hash ^= b as u32;
+        hash = hash.wrapping_mul(0x01000193);
+    }
+    (hash ^ (hash >> 8) ^ (hash >> 16) ^ (hash >> 24)) as u8
+}
+
+fn encode_key(key: &str) -> String {
+    key.bytes().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn decode_key(name: &str) -> Option<String> {
+    if name.len() & 1 != 0 {
+        return None;
+    }
+    let bytes: Option<Vec<u8>> = (0..name.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&name[i..i + 2], 16).ok())
+        .collect();
+    bytes.and_then(|b| String::from_utf8(b).ok())
+}
+
+impl KvStore {
+    pub fn open<P: AsRef<Path>>(root: P) -> Result<KvStore> {
+        let root = root.as_ref().to_path_buf();
+        fs::create_dir_all(&root).chain_err(|| format!("creating kv store at {:?}", root))?;
+        Ok(KvStore { root })
+    }
+
+    fn path_for(&self, key: &str) -> PathBuf {
+        self.root.join(format!("{:02x}", shard_of(key))).join(encode_key(key))
+    }
+
+    pub fn get<V: DeserializeOwned>(&self, key: &str) -> Result<Option<V>> {
+        let path = self.path_for(key);
+        match File::open(&path) {
+            Ok(f) => {
+                let value = serde_json::from_reader(io::BufReader::new(f))
+                    .chain_err(|| format!("decoding {:?}", path))?;
+                Ok(Some(value))
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).chain_err(|| format!("reading {:?}", path)),
+        }
+    }
+
+    pub fn put<V: Serialize>(&self, key: &str, value: &V) -> Result<()> {
+        use rand::Rng;
+
+        let path = self.path_for(key);
+        let dir = path.parent().unwrap();
+        fs::create_dir_all(dir).chain_err(|| format!("creating {:?}", dir))?;
+        let tmp = dir.join(format!(".{}.{:08x}",
+                                   encode_key(key),
+                                   ::rand::thread_rng().gen::<u32>()));
+        let encoded = serde_json::to_vec(value).chain_err(|| format!("encoding {}", key))?;
+        File::create(&tmp)
+            .and_then(|mut f| f.write_all(&encoded))
+            .and_then(|_| fs::rename(&tmp, &path))
+            .chain_err(|| format!("writing {:?}", path))
+    }
+
+    /// Make the value of `key`, or its absence, survive a crash: puts and
+    /// deletes are atomic but not durable until this returns.
+    pub fn sync(&self, key: &str) -> Result<()> {
+        let path = self.path_for(key);
+        match File::open(&path) {
+            Ok(f) => f.sync_all().chain_err(|| format!("syncing {:?}", path))?,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e).chain_err(|| format!("syncing {:?}", path)),
+        }
+        let dir = path.parent().unwrap();
+        File::open(dir)
+            .and_then(|d| d.sync_all())
+            .chain_err(|| format!("syncing {:?}", dir))
+    }
+
+    pub fn delete(&self, key: &str) -> Result<()> {
+        let path = self.path_for(key);
+        match fs::remove_file(&path) {
+            Ok(()) => Ok(()),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e).chain_err(|| format!("removing {:?}", path)),
+        }
+    }
+
+    /// Every key in the store, sorted. Walks the whole store.
+    pub fn keys(&self) -> Result<Vec<String>> {
+        let mut keys = Vec::new();
+        let shards = fs::read_dir(&self.root).chain_err(|| format!("listing {:?}", self.root))?;
+        for shard in shards {
+            let shard = shard.chain_err(|| format!("listing {:?}", self.root))?;
+            let entries = fs::read_dir(shard.path())
+                .chain_err(|| format!("listing {:?}", shard.path()))?;
+            for entry in entries {
+                let entry = entry.chain_err(|| format!("listing {:?}", shard.path()))?;
+                // in-flight puts are dot files and do not decode
+                if let Some(key) = entry.file_name().to_str().and_then(decode_key) {
+                    keys.push(key);
+                }
+            }
+        }
+        keys.sort();
+        Ok(keys)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tempfile;
+
+    #[test]
+    fn values_are_replaced_and_deleted() {
+        let dir = tempfile::tempdir().unwrap();
+        let kv = KvStore::open(dir.path()).unwrap();
+        assert_eq!(kv.get::<u64>("a/b").unwrap(), None);
+        kv.put("a/b", &1u64).unwrap();
+        kv.put("a/b", &2u64).unwrap();
+        kv.put("c", &3u64).unwrap();
+        assert_eq!(kv.get::<u64>("a/b").unwrap(), Some(2));
+
+        assert_eq!(kv.keys().unwrap(), vec!["a/b".to_string(), "c".to_string()]);
+
+        kv.delete("a/b").unwrap();
+        kv.delete("a/b").unwrap();
+        assert_eq!(kv.get::<u64>("a/b").unwrap(), None);
+        assert_eq!(kv.keys().unwrap(), vec!["c".to_string()]);
+    }
+}
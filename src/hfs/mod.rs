@@ -1,37 +1,557 @@
 use super::errors::*;
 
+pub mod store;
+pub mod tier;
+mod access;
+mod blocks;
+mod dedup;
+mod fsck;
+mod gc;
+mod handle;
+mod inode;
+mod kv;
+mod prefetch;
+mod snapshot;
+mod stub;
+mod sys;
+mod transfer;
+mod trash;
+mod upload;
+mod version;
+
+use self::access::{Caller, Perms};
+use self::store::ObjectStore;
+use self::tier::{Tiering, CompressionPolicy, Durability, EvictionPolicy, STATE_DIR};
+use self::inode::{InodeTable, ROOT_INO};
+use self::handle::Handle;
+use self::trash::Trash;
+use self::upload::Uploader;
+use self::prefetch::Prefetcher;
+use self::snapshot::{Kind, Node, SnapshotView};
+pub use self::fsck::Finding;
+pub use self::gc::{GcPolicy, Orphan};
+pub use self::prefetch::PrefetchPolicy;
+pub use self::snapshot::Snapshot;
+pub use self::transfer::TransferPolicy;
+pub use self::trash::Trashed;
+pub use self::upload::UploadPolicy;
+pub use self::version::Version;
+
 use fuse;
 use fuse::{Filesystem, Request, ReplyAttr, ReplyDirectory, ReplyEntry, FileAttr, ReplyOpen,
-           ReplyData, ReplyEmpty, ReplyCreate, ReplyWrite};
+           ReplyData, ReplyEmpty, ReplyCreate, ReplyWrite, ReplyStatfs, ReplyXattr};
 
-use libc::{ENOSYS, ENOENT};
+use libc;
+use libc::{c_int, ENOENT, EIO, EINVAL, EBADF, EPERM, EACCES, ERANGE, ENODATA, EROFS,
+           ENOTEMPTY};
 
 use time::Timespec;
 use std;
 use std::ffi::OsStr;
 use std::fs;
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
 use std::path::{Path, PathBuf};
-use std::fs::File;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// Settings for a mount beyond its paths and store.
+pub struct Options {
+    pub policy: EvictionPolicy,
+    /// bytes the object store is reported to add to the backing path's capacity
+    pub cold_capacity: u64,
+    pub durability: Durability,
+    pub upload: UploadPolicy,
+    pub transfer: TransferPolicy,
+    pub prefetch: PrefetchPolicy,
+    pub compression: CompressionPolicy,
+    /// hold file content in the object store as shared chunks
+    pub dedup: bool,
+    /// how long content replaced or removed is kept in the object store;
+    /// zero keeps none
+    pub retention: Duration,
+    /// how long removed files and directories are kept in the trash; zero
+    /// removes them at once
+    pub trash: Duration,
+    pub gc: GcPolicy,
+}
+
+fn tiering(bp: &str, store: Arc<dyn ObjectStore>, options: &Options) -> Result<Tiering> {
+    Tiering::new(Path::new(bp),
+                 store,
+                 options.policy.clone(),
+                 options.transfer.clone(),
+                 options.compression.clone(),
+                 options.dedup,
+                 options.retention)
+}
+
+/// Versions kept of files at or under `path`, relative to the root of the
+/// filesystem backed by `bp`.
+pub fn versions(bp: &str,
+                store: Arc<dyn ObjectStore>,
+                options: &Options,
+                path: Option<&str>)
+                -> Result<Vec<Version>> {
+    tiering(bp, store, options)?.versions(path)
+}
+
+/// Write version `id` back into the filesystem backed by `bp`, as a new file
+/// at `to` or where it was, and return the backing path written. Refused
+/// while it is mounted at `mp`, whose upload journal this would write behind
+/// its back.
+pub fn restore(mp: &str,
+               bp: &str,
+               store: Arc<dyn ObjectStore>,
+               options: &Options,
+               id: &str,
+               to: Option<&str>)
+               -> Result<PathBuf> {
+    if mounted(mp) {
+        bail!("{} is mounted at {}; unmount it to restore a version", bp, mp);
+    }
+    tiering(bp, store, options)?.restore(id, to)
+}
+
+/// Whether this filesystem is mounted at `mp`, found by asking it for an
+/// attribute only the mount answers.
+fn mounted(mp: &str) -> bool {
+    sys::get_xattr(Path::new(mp), OsStr::new(XATTR_QUEUE_DEPTH)).is_ok()
+}
+
+/// Take snapshot `name` of the filesystem backed by `bp`. If it is mounted
+/// at `mp` the mount takes it, so that nothing changes the namespace while
+/// it is walked, and this waits for it to be listed.
+pub fn take_snapshot(mp: &str,
+                     bp: &str,
+                     store: Arc<dyn ObjectStore>,
+                     options: &Options,
+                     name: &str)
+                     -> Result<Snapshot> {
+    use std::os::unix::ffi::OsStrExt;
+
+    if mounted(mp) {
+        sys::set_xattr(Path::new(mp), OsStr::new(XATTR_SNAPSHOT), OsStr::new(name).as_bytes(), 0)
+            .chain_err(|| format!("taking snapshot {} through {}", name, mp))?;
+        loop {
+            if let Some(s) = snapshot::list(Path::new(bp))?.into_iter().find(|s| s.name == name) {
+                return Ok(s);
+            }
+            if !snapshot::started(Path::new(bp), name) {
+                bail!("snapshot {} failed; see the log of the mount", name);
+            }
+            thread::sleep(Duration::from_secs(1));
+        }
+    }
+    snapshot::take(Path::new(bp), &tiering(bp, store, options)?, name)
+}
+
+/// Snapshots of the filesystem backed by `bp`, oldest first.
+pub fn snapshots(bp: &str) -> Result<Vec<Snapshot>> {
+    snapshot::list(Path::new(bp))
+}
+
+/// Delete snapshot `name`, and the objects only it held. Refused while the
+/// filesystem is mounted at `mp`, whose pins and versions this would change
+/// under it.
+pub fn delete_snapshot(mp: &str,
+                       bp: &str,
+                       store: Arc<dyn ObjectStore>,
+                       options: &Options,
+                       name: &str)
+                       -> Result<()> {
+    if mounted(mp) {
+        bail!("{} is mounted at {}; unmount it to delete a snapshot", bp, mp);
+    }
+    snapshot::delete(Path::new(bp), &tiering(bp, store, options)?, name)
+}
+
+/// Objects in the store of the filesystem backed by `bp` that nothing refers
+/// to, deleting those past their grace period unless `dry_run`. Refused while
+/// it is mounted at `mp`, whose uploads this would not see; the mount
+/// collects by itself.
+pub fn gc(mp: &str,
+          bp: &str,
+          store: Arc<dyn ObjectStore>,
+          options: &Options,
+          dry_run: bool)
+          -> Result<Vec<Orphan>> {
+    if mounted(mp) {
+        bail!("{} is mounted at {}; unmount it to collect garbage", bp, mp);
+    }
+    gc::collect(Path::new(bp), &tiering(bp, store, options)?, options.gc.grace, dry_run)
+}
+
+/// Check the filesystem backed by `bp` against its metadata and the object
+/// store, repairing what can be if `repair`. Refused while it is mounted at
+/// `mp`, which changes all three as they are compared.
+pub fn fsck(mp: &str,
+            bp: &str,
+            store: Arc<dyn ObjectStore>,
+            options: &Options,
+            repair: bool,
+            checksums: bool)
+            -> Result<Vec<Finding>> {
+    if mounted(mp) {
+        bail!("{} is mounted at {}; unmount it to check it", bp, mp);
+    }
+    fsck::check(Path::new(bp), &tiering(bp, store, options)?, repair, checksums)
+}
+
+/// Entries in the trash of the filesystem backed by `bp` removed from at or
+/// under `path`, relative to its root.
+pub fn trashed(bp: &str,
+               store: Arc<dyn ObjectStore>,
+               options: &Options,
+               path: Option<&str>)
+               -> Result<Vec<Trashed>> {
+    let tiering = Arc::new(tiering(bp, store, options)?);
+    Trash::open(Path::new(bp), tiering, options.trash)?.list(path)
+}
+
+/// Move entry `id` out of the trash of the filesystem backed by `bp`, to
+/// `to` or where it was, and return the backing path it is at. Refused while
+/// it is mounted at `mp`, whose upload journal this would write behind its
+/// back.
+pub fn untrash(mp: &str,
+               bp: &str,
+               store: Arc<dyn ObjectStore>,
+               options: &Options,
+               id: &str,
+               to: Option<&str>)
+               -> Result<PathBuf> {
+    if mounted(mp) {
+        bail!("{} is mounted at {}; unmount it to restore from the trash", bp, mp);
+    }
+    let tiering = Arc::new(tiering(bp, store, options)?);
+    Trash::open(Path::new(bp), tiering, options.trash)?.restore(id, to)
+}
 
 pub struct S3HierarchicalFilesystem<'a> {
     _mount_path: &'a str,
     _backing_path: &'a str,
-    ino_paths: HashMap<u64, PathBuf>,
-    files: HashMap<u64, File>,
+    /// shared with the uploader, which resolves the files it uploads
+    inodes: Arc<Mutex<InodeTable>>,
+    files: HashMap<u64, Handle>,
+    /// last file handle number given out; never reused while mounted
+    last_fh: u64,
+    tiering: Arc<Tiering>,
+    uploader: Uploader,
+    prefetcher: Prefetcher,
+    cold_capacity: u64,
+    durability: Durability,
+    /// mounted read-only, answering from this snapshot rather than the
+    /// backing path
+    snapshot: Option<SnapshotView>,
+    /// where removed entries go, if they are not removed at once
+    trash: Option<Arc<Trash>>,
+}
+
+/// The shared pieces a mount is put together from.
+struct Parts {
+    inodes: Arc<Mutex<InodeTable>>,
+    tiering: Arc<Tiering>,
+    uploader: Uploader,
+    prefetcher: Prefetcher,
 }
 
 impl<'a> S3HierarchicalFilesystem<'a> {
-    pub fn mount(mp: &str, bp: &str) -> Result<()> {
-        let mut fs = S3HierarchicalFilesystem {
+    pub fn mount(mp: &str,
+                 bp: &str,
+                 store: Arc<dyn ObjectStore>,
+                 options: Options)
+                 -> Result<()> {
+        let tiering = Arc::new(tiering(bp, store, &options)?);
+        version::start_expiry(tiering.clone())?;
+        gc::start(Path::new(bp), tiering.clone(), options.gc.clone())?;
+        let inodes = Arc::new(Mutex::new(InodeTable::open(Path::new(bp))?));
+        let uploader = Uploader::start(inodes.clone(), tiering.clone(), options.upload.clone())?;
+        let prefetcher = Prefetcher::start(tiering.clone(), options.prefetch.clone())?;
+        let parts = Parts {
+            inodes,
+            tiering,
+            uploader,
+            prefetcher,
+        };
+        let mut fs = S3HierarchicalFilesystem::new(mp, bp, parts, &options, None)?;
+        if !options.trash.is_zero() {
+            let trash = Arc::new(Trash::open(Path::new(bp), fs.tiering.clone(), options.trash)?);
+            trash::start_expiry(trash.clone())?;
+            fs.trash = Some(trash);
+        }
+        fuse::mount(fs, &mp, &[]).chain_err(|| "mounting filesystem")
+    }
+
+    /// Mount snapshot `name` of the filesystem backed by `bp` read-only at
+    /// `mp`, alongside the live mount if there is one. Nothing is uploaded,
+    /// evicted or expired by it, and content is read straight from the
+    /// object store.
+    pub fn mount_snapshot(mp: &str,
+                          bp: &str,
+                          store: Arc<dyn ObjectStore>,
+                          options: Options,
+                          name: &str)
+                          -> Result<()> {
+        let view = SnapshotView::open(Path::new(bp), name)?;
+        let tiering = Arc::new(tiering(bp, store, &options)?);
+        let prefetch = PrefetchPolicy {
+            blocks: 0,
+            threads: 0,
+            tools: Vec::new(),
+        };
+        let prefetcher = Prefetcher::start(tiering.clone(), prefetch)?;
+        let inodes = Arc::new(Mutex::new(InodeTable::open(Path::new(bp))?));
+        let parts = Parts {
+            inodes,
+            tiering,
+            uploader: Uploader::idle(),
+            prefetcher,
+        };
+        let fs = S3HierarchicalFilesystem::new(mp, bp, parts, &options, Some(view))?;
+        fuse::mount(fs, &mp, &[OsStr::new("-o"), OsStr::new("ro")])
+            .chain_err(|| format!("mounting snapshot {}", name))
+    }
+
+    fn new(mp: &'a str,
+           bp: &'a str,
+           parts: Parts,
+           options: &Options,
+           snapshot: Option<SnapshotView>)
+           -> Result<S3HierarchicalFilesystem<'a>> {
+        Ok(S3HierarchicalFilesystem {
             _mount_path: mp,
             _backing_path: bp,
-            ino_paths: HashMap::new(),
+            inodes: parts.inodes,
             files: HashMap::new(),
+            last_fh: 10,
+            tiering: parts.tiering,
+            uploader: parts.uploader,
+            prefetcher: parts.prefetcher,
+            cold_capacity: options.cold_capacity,
+            durability: options.durability,
+            snapshot,
+            trash: None,
+        })
+    }
+
+    /// A file handle number not given out before.
+    fn next_fh(&mut self) -> u64 {
+        self.last_fh += 1;
+        self.last_fh
+    }
+
+    /// Note an inode handed to the kernel in an entry reply.
+    fn remember(&mut self, ino: u64, parent: u64, name: &str) {
+        if let Err(e) = self.inodes.lock().unwrap().looked_up(ino, parent, name) {
+            error!("recording inode {} as {:?} in {}: {}", ino, name, parent, e);
+        }
+    }
+
+    /// Note that `name` in `parent` no longer refers to `ino`.
+    fn forget_name(&mut self, ino: u64, parent: u64, name: &str) {
+        if let Err(e) = self.inodes.lock().unwrap().remove_name(ino, parent, name) {
+            error!("removing {:?} in {} from inode {}: {}", name, parent, ino, e);
+        }
+    }
+
+    /// Value of one of the virtual xattrs, `None` if `name` is not one.
+    fn virtual_xattr(&self, ino: u64, name: &OsStr) -> Option<std::result::Result<Vec<u8>, c_int>> {
+        let record = || {
+            self.tiering.record(ino).map_err(|e| {
+                error!("reading record for {}: {}", ino, e);
+                EIO
+            })
         };
-        fs.ino_paths.insert(1, PathBuf::from(bp));
-        fuse::mount(fs, &mp, &[]).chain_err(|| "mounting filesystem")
+        if name == XATTR_TIER {
+            Some(record().map(|r| match r {
+                Some(ref r) if !r.resident => b"cold".to_vec(),
+                _ => b"hot".to_vec(),
+            }))
+        } else if name == XATTR_OBJECT_KEY {
+            Some(record().and_then(|r| match r {
+                Some(ref r) if !r.resident => Ok(r.key.clone().into_bytes()),
+                _ => Err(ENODATA),
+            }))
+        } else if name == XATTR_QUEUE_DEPTH && ino == ROOT_INO {
+            Some(Ok(self.tiering.queue_depth().to_string().into_bytes()))
+        } else if name == XATTR_UPLOADING && ino == ROOT_INO {
+            Some(Ok(self.uploader.uploading().to_string().into_bytes()))
+        } else {
+            None
+        }
+    }
+
+    /// Move `name` in `parent` to `newname` in `newparent`, replacing what is
+    /// there. Content held only in the cold tier is keyed by inode, so moving
+    /// it touches nothing but the backing entry and the inode table.
+    fn rename_entry(&mut self,
+                    parent: u64,
+                    name: &OsStr,
+                    newparent: u64,
+                    newname: &OsStr)
+                    -> std::result::Result<(), c_int> {
+        use std::os::unix::fs::MetadataExt;
+
+        if (parent == ROOT_INO && name == STATE_DIR) ||
+           (newparent == ROOT_INO && newname == STATE_DIR) {
+            return Err(ENOENT);
+        }
+        let name = name.to_str().ok_or(EINVAL)?;
+        let newname = newname.to_str().ok_or(EINVAL)?;
+        let errno = |e: std::io::Error| e.raw_os_error().unwrap_or(EIO);
+        let resolve = |fs: &mut Self, ino: u64| match fs.inodes.lock().unwrap().path(ino) {
+            Ok(Some(p)) => Ok(p),
+            Ok(None) => Err(ENOENT),
+            Err(e) => {
+                error!("resolving inode {}: {}", ino, e);
+                Err(EIO)
+            }
+        };
+        let from = resolve(self, parent)?.join(name);
+        let to = resolve(self, newparent)?.join(newname);
+
+        let moved = fs::symlink_metadata(&from).map_err(errno)?;
+        let replaced = match fs::symlink_metadata(&to) {
+            Ok(m) => Some(m),
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(errno(e)),
+        };
+        if let Some(ref r) = replaced {
+            if r.ino() != moved.ino() && r.is_file() && r.nlink() <= 1 {
+                if let Err(e) = self.tiering.preserve(r.ino(), &to, &to, true) {
+                    error!("keeping version of {:?} before replacing: {}", to, e);
+                    return Err(EIO);
+                }
+            }
+        }
+
+        fs::rename(&from, &to).map_err(errno)?;
+        debug!("Renamed: {:?} -> {:?}", from, to);
+
+        if let Some(ref r) = replaced {
+            if r.ino() == moved.ino() {
+                // both names were links to the same inode: rename does nothing
+                return Ok(());
+            }
+        }
+        let renamed = self.inodes
+            .lock()
+            .unwrap()
+            .rename(moved.ino(), (parent, name), (newparent, newname));
+        if let Err(e) = renamed {
+            error!("recording rename of inode {}: {}", moved.ino(), e);
+        }
+        if let Some(replaced) = replaced {
+            self.forget_name(replaced.ino(), newparent, newname);
+            if replaced.is_file() && replaced.nlink() <= 1 {
+                if let Err(e) = self.tiering.remove(replaced.ino(), &to) {
+                    warn!("unable to remove cold copy of replaced {:?}: {}", to, e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn mark_dirty(&self, ino: u64) {
+        if let Err(e) = self.tiering.mark_dirty(ino) {
+            error!("recording write to inode {}: {}", ino, e);
+        }
+    }
+
+    /// Hand the prefetcher the window ahead of a sequential reader of an
+    /// evicted file, once the reader has used up half of what was fetched.
+    fn read_ahead(&mut self, fh: u64, offset: u64, len: u64, evicted: bool) {
+        let window = self.prefetcher.window();
+        let (ino, start, end) = match self.files.get_mut(&fh) {
+            Some(h) => {
+                if h.note_read(offset, len) < SEQUENTIAL_READS || !evicted {
+                    return;
+                }
+                let end = offset + len + window;
+                if h.prefetched_to >= offset + len + window / 2 {
+                    return;
+                }
+                let start = h.prefetched_to.max(offset + len);
+                h.prefetched_to = end;
+                (h.ino, start, end)
+            }
+            None => return,
+        };
+        let resolved = self.inodes.lock().unwrap().path(ino);
+        match resolved {
+            Ok(Some(path)) => self.prefetcher.request(ino, path, start, end - start),
+            Ok(None) => {}
+            Err(e) => error!("resolving inode {}: {}", ino, e),
+        }
+    }
+
+    fn evict_if_needed(&mut self) {
+        // the trash goes before anything in use does
+        if let Some(ref trash) = self.trash {
+            if let Err(e) = trash.make_room() {
+                error!("purging trash: {}", e);
+            }
+        }
+        let open: HashSet<u64> = self.files.values().map(|h| h.ino).collect();
+        if let Err(e) = self.tiering.maybe_evict(&open) {
+            error!("eviction failed: {}", e);
+        }
+    }
+
+    /// Take a snapshot named `value`, asked for by setting `XATTR_SNAPSHOT`
+    /// on the root. Nothing else is answered while the namespace is walked;
+    /// the snapshot is listed once the uploader has the files the object
+    /// store did not hold.
+    fn take_snapshot(&mut self, req: &Request, ino: u64, value: &[u8], reply: ReplyEmpty) {
+        use std::os::unix::fs::MetadataExt;
+
+        if ino != ROOT_INO {
+            reply.error(EPERM);
+            return;
+        }
+        let owner = match fs::metadata(self._backing_path) {
+            Ok(m) => m.uid(),
+            Err(e) => {
+                error!("stat {:?}: {}", self._backing_path, e);
+                reply.error(EIO);
+                return;
+            }
+        };
+        if req.uid() != 0 && req.uid() != owner {
+            reply.error(EPERM);
+            return;
+        }
+        let name = match std::str::from_utf8(value) {
+            Ok(n) => n,
+            Err(_) => {
+                reply.error(EINVAL);
+                return;
+            }
+        };
+        let taken = match snapshot::start(Path::new(self._backing_path), &self.tiering, name) {
+            Ok(t) => t,
+            Err(e) => {
+                error!("taking snapshot {}: {}", name, e);
+                reply.error(EIO);
+                return;
+            }
+        };
+        {
+            // the uploader finds the files it uploads by name
+            let mut inodes = self.inodes.lock().unwrap();
+            for (ino, parent, name) in taken.waiting() {
+                if let Err(e) = inodes.insert(ino, parent, name) {
+                    error!("recording inode {} as {:?} in {}: {}", ino, name, parent, e);
+                }
+            }
+        }
+        match taken.finish_later(self.tiering.clone()) {
+            Ok(()) => reply.ok(),
+            Err(e) => {
+                error!("taking snapshot {}: {}", name, e);
+                reply.error(EIO);
+            }
+        }
     }
 }
 
@@ -81,6 +601,80 @@ fn fileattr_from(m: &std::fs::Metadata) -> FileAttr {
     }
 }
 
+fn filetype_of(kind: Kind) -> fuse::FileType {
+    match kind {
+        Kind::File => fuse::FileType::RegularFile,
+        Kind::Directory => fuse::FileType::Directory,
+        Kind::Symlink => fuse::FileType::Symlink,
+    }
+}
+
+fn fileattr_from_node(ino: u64, node: &Node) -> FileAttr {
+    let mtime = Timespec::new(node.mtime.0, node.mtime.1);
+    FileAttr {
+        ino,
+        size: node.size,
+        blocks: node.size.div_ceil(512),
+        atime: Timespec::new(node.atime.0, node.atime.1),
+        mtime,
+        ctime: Timespec::new(node.ctime.0, node.ctime.1),
+        crtime: mtime,
+        kind: filetype_of(node.kind),
+        perm: node.mode as u16,
+        nlink: node.nlink,
+        uid: node.uid,
+        gid: node.gid,
+        rdev: 0,
+        flags: 0,
+    }
+}
+
+/// Reads in a row that carry on from the one before, after which a handle
+/// counts as reading sequentially.
+const SEQUENTIAL_READS: u32 = 2;
+
+/// Read-only attributes describing where a file's data lives.
+const XATTR_TIER: &str = "user.s3hfs.tier";
+const XATTR_OBJECT_KEY: &str = "user.s3hfs.object_key";
+/// on the root: files waiting for upload, and uploads under way
+const XATTR_QUEUE_DEPTH: &str = "user.s3hfs.queue_depth";
+const XATTR_UPLOADING: &str = "user.s3hfs.uploading";
+const VIRTUAL_XATTRS: &[&str] = &[XATTR_TIER, XATTR_OBJECT_KEY, XATTR_QUEUE_DEPTH, XATTR_UPLOADING];
+/// set on the root to take a snapshot of that name
+const XATTR_SNAPSHOT: &str = "user.s3hfs.snapshot";
+
+/// Answer a getxattr or listxattr with `value`, or just its size if the
+/// kernel asked for that.
+fn reply_xattr(reply: ReplyXattr, size: u32, value: &[u8]) {
+    if size == 0 {
+        reply.size(value.len() as u32);
+    } else if value.len() > size as usize {
+        reply.error(ERANGE);
+    } else {
+        reply.data(value);
+    }
+}
+
+/// Whether the caller may set or remove extended attribute `name` of the
+/// entry at `path`: the owner and root may, but only root in the `trusted`
+/// and `security` namespaces. Otherwise the errno to refuse with.
+fn may_change_xattr(req: &Request, path: &Path, name: &OsStr) -> std::result::Result<(), c_int> {
+    use std::os::unix::fs::MetadataExt;
+
+    if req.uid() == 0 {
+        return Ok(());
+    }
+    let name = name.to_string_lossy();
+    if name.starts_with("trusted.") || name.starts_with("security.") {
+        return Err(EPERM);
+    }
+    match fs::symlink_metadata(path) {
+        Ok(ref m) if m.uid() == req.uid() => Ok(()),
+        Ok(_) => Err(EPERM),
+        Err(e) => Err(e.raw_os_error().unwrap_or(ENOENT)),
+    }
+}
+
 fn dir_from(entry_opt: std::io::Result<fs::DirEntry>) -> Option<(u64, fuse::FileType, String)> {
     use std::os::unix::fs::DirEntryExt;
 
@@ -98,38 +692,41 @@ fn dir_from(entry_opt: std::io::Result<fs::DirEntry>) -> Option<(u64, fuse::File
 }
 
 macro_rules! ino_path_or_return {
-    ($self:ident, $parent:expr, $reply:ident) => ({
-        let path = match $self.ino_paths.get($parent) {
-            Some(p) => p,
-            None => {
-                error!("parent not found in cache");
-                $reply.error(ENOSYS);
+    ($self:ident, $ino:expr, $reply:ident) => ({
+        match $self.inodes.lock().unwrap().path(*$ino) {
+            Ok(Some(p)) => p,
+            Ok(None) => {
+                error!("inode not found: {}", $ino);
+                $reply.error(ENOENT);
                 return;
             }
-        };
-        path.clone()
+            Err(e) => {
+                error!("resolving inode {}: {}", $ino, e);
+                $reply.error(EIO);
+                return;
+            }
+        }
     })
 }
 
-macro_rules! full_path_or_return {
-    ($self:ident, $parent:expr, $name:expr, $reply:ident) => ({
-        let parent_path = match $self.ino_paths.get($parent) {
-            Some(p) => p,
-            None => {
-                error!("parent not found in cache");
-                $reply.error(ENOSYS);
-                return;
-            }
-        };
-        let new_name = match $name.to_str() {
-            Some(p) => p,
+macro_rules! name_or_return {
+    ($name:expr, $reply:ident) => (
+        match $name.to_str() {
+            Some(n) => n,
             None => {
                 error!("unable to read name");
-                $reply.error(ENOSYS);
+                $reply.error(EINVAL);
                 return;
             }
-        };
-        Path::new(&parent_path).join(new_name).clone()
+        }
+    )
+}
+
+macro_rules! full_path_or_return {
+    ($self:ident, $parent:expr, $name:expr, $reply:ident) => ({
+        let parent_path = ino_path_or_return!($self, $parent, $reply);
+        let new_name = name_or_return!($name, $reply);
+        parent_path.join(new_name)
     })
 }
 
@@ -159,25 +756,111 @@ macro_rules! ok_or_return_error {
     )
 }
 
-macro_rules! none_or_return_error {
-    ($v:expr, $reply:ident) => (
-        if $v.is_some() {
-            error!("setattr change not implemented: {:?}", $v);
-            $reply.error(ENOSYS);
+macro_rules! read_only_or_return {
+    ($self:ident, $reply:ident) => (
+        if $self.snapshot.is_some() {
+            $reply.error(EROFS);
             return;
         }
     )
 }
 
+macro_rules! node_or_return {
+    ($view:expr, $ino:expr, $reply:ident) => (
+        match $view.node($ino) {
+            Ok(Some(n)) => n,
+            Ok(None) => {
+                error!("inode not in snapshot: {}", $ino);
+                $reply.error(ENOENT);
+                return;
+            }
+            Err(e) => {
+                error!("reading snapshot node {}: {}", $ino, e);
+                $reply.error(EIO);
+                return;
+            }
+        }
+    )
+}
+
+// Answers for a read-only mount of a snapshot, which come from its nodes
+// and the objects they name rather than the backing path.
+
+fn snapshot_lookup(view: &SnapshotView, parent: u64, name: &OsStr, reply: ReplyEntry) {
+    let name = name_or_return!(name, reply);
+    match view.child(parent, name) {
+        Ok(Some((ino, node))) => {
+            let ttl = Timespec::new(1, 0);
+            reply.entry(&ttl, &fileattr_from_node(ino, &node), 0);
+        }
+        Ok(None) => reply.error(ENOENT),
+        Err(e) => {
+            error!("looking up {:?} in snapshot directory {}: {}", name, parent, e);
+            reply.error(EIO);
+        }
+    }
+}
+
+fn snapshot_readdir(view: &SnapshotView, ino: u64, offset: u64, mut reply: ReplyDirectory) {
+    let node = node_or_return!(view, ino, reply);
+    if offset < 1 {
+        reply.add(ino, 0, fuse::FileType::Directory, ".");
+        reply.add(ino, 1, fuse::FileType::Directory, "..");
+    }
+    for (i, (name, child)) in node.children.iter().enumerate() {
+        let entry_offset = (i + 2) as u64;
+        if offset >= entry_offset {
+            continue;
+        }
+        let kind = node_or_return!(view, *child, reply).kind;
+        if reply.add(*child, entry_offset, filetype_of(kind), name) {
+            break;
+        }
+    }
+    reply.ok();
+}
+
+fn snapshot_read(view: &SnapshotView,
+                 tiering: &Tiering,
+                 ino: u64,
+                 offset: u64,
+                 size: u32,
+                 reply: ReplyData) {
+    let node = node_or_return!(view, ino, reply);
+    let end = node.size.min(offset.saturating_add(u64::from(size)));
+    if offset >= end {
+        reply.data(&[]);
+        return;
+    }
+    let mut data = if offset < node.stored {
+        let len = node.stored.min(end) - offset;
+        let read = tiering.read_object(&node.key, node.compressed, node.chunked, offset, len);
+        ok_or_return_error!(read, EIO, reply)
+    } else {
+        Vec::new()
+    };
+    // past the object the file is zeros
+    data.resize((end - offset) as usize, 0);
+    reply.data(&data);
+}
+
 impl<'a> Filesystem for S3HierarchicalFilesystem<'a> {
     fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
         trace!("getattr(ino={})", ino);
 
+        if let Some(ref view) = self.snapshot {
+            let node = node_or_return!(view, ino, reply);
+            let ttl = Timespec::new(1, 0);
+            reply.attr(&ttl, &fileattr_from_node(ino, &node));
+            return;
+        }
+
         let path = ino_path_or_return!(self, &ino, reply);
-        let metadata = ok_or_return_error!(std::fs::metadata(path), ENOENT, reply);
+        let metadata = ok_or_return_error!(fs::symlink_metadata(path), ENOENT, reply);
 
         debug!("{:?}", metadata);
-        let attr = fileattr_from(&metadata);
+        let mut attr = fileattr_from(&metadata);
+        attr.ino = ino;
         let ttl = Timespec::new(1, 0);
         reply.attr(&ttl, &attr);
     }
@@ -185,12 +868,22 @@ impl<'a> Filesystem for S3HierarchicalFilesystem<'a> {
     fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
         trace!("lookup(parent={}, name={:?})", parent, name);
 
+        if let Some(ref view) = self.snapshot {
+            snapshot_lookup(view, parent, name, reply);
+            return;
+        }
+        if parent == ROOT_INO && name == STATE_DIR {
+            reply.error(ENOENT);
+            return;
+        }
+
         let path = full_path_or_return!(self, &parent, name, reply);
 
-        match path.metadata() {
+        match fs::symlink_metadata(&path) {
             Ok(metadata) => {
                 debug!("{:?}", metadata);
                 let attr = fileattr_from(&metadata);
+                self.remember(attr.ino, parent, name_or_return!(name, reply));
                 let ttl = Timespec::new(1, 0);
                 debug!("warning: generation assumed 0");
                 reply.entry(&ttl, &attr, 0);
@@ -203,8 +896,18 @@ impl<'a> Filesystem for S3HierarchicalFilesystem<'a> {
         };
     }
 
+    fn forget(&mut self, _req: &Request, ino: u64, nlookup: u64) {
+        trace!("forget(ino={}, nlookup={})", ino, nlookup);
+
+        // the kernel protocol fuse speaks predates BATCH_FORGET, so batches
+        // arrive here one inode at a time
+        let mut inodes = self.inodes.lock().unwrap();
+        inodes.forget(ino, nlookup);
+        debug!("{} inodes held by the kernel", inodes.held());
+    }
+
     fn readdir(&mut self,
-               _req: &Request,
+               req: &Request,
                ino: u64,
                fh: u64,
                offset: u64,
@@ -212,6 +915,11 @@ impl<'a> Filesystem for S3HierarchicalFilesystem<'a> {
 
         trace!("readdir(ino={}, fh={}, offset={})", ino, fh, offset);
 
+        if let Some(ref view) = self.snapshot {
+            snapshot_readdir(view, ino, offset, reply);
+            return;
+        }
+
         let path: PathBuf = ino_path_or_return!(self, &ino, reply);
 
         if offset < 1 {
@@ -220,13 +928,21 @@ impl<'a> Filesystem for S3HierarchicalFilesystem<'a> {
         };
 
         let rd = ok_or_return_error!(path.read_dir(), ENOENT, reply);
+        // the state directory is not part of the namespace
+        let rd = rd.filter(|e| {
+            ino != ROOT_INO || e.as_ref().map(|e| e.file_name() != STATE_DIR).unwrap_or(true)
+        });
+
+        // listed by a tool known to read everything next: start fetching
+        let prefetch = offset == 0 && self.prefetcher.wanted_by(req.pid());
+        let mut files = Vec::new();
 
         for (i, entry_opt) in rd.enumerate() {
             let entry_offset = (i + 2) as u64;
             if offset >= entry_offset {
                 continue;
             };
-            let (ino, filetype, filename) = match dir_from(entry_opt) {
+            let (child, filetype, filename) = match dir_from(entry_opt) {
                 Some(x) => x,
                 None => {
                     error!("unable to extract directory entry");
@@ -235,30 +951,66 @@ impl<'a> Filesystem for S3HierarchicalFilesystem<'a> {
                 }
             };
             debug!("Adding: {}, {}, {:?}, \"{}\"",
-                   ino,
+                   child,
                    entry_offset,
                    filetype,
                    filename);
-            if reply.add(ino, entry_offset, filetype, &filename) {
+            if prefetch && filetype == fuse::FileType::RegularFile {
+                files.push((child, path.join(&filename)));
+            }
+            // readdir does not add to the kernel's lookup count, so the
+            // child is only recorded once it is looked up
+            if reply.add(child, entry_offset, filetype, &filename) {
                 break;
             }
-            self.ino_paths.insert(ino, path.join(filename));
         }
         reply.ok();
+
+        for (child, file) in files {
+            if let Ok(true) = self.tiering.is_evicted(child) {
+                self.prefetcher.request(child, file, 0, self.prefetcher.window());
+            }
+        }
     }
 
-    fn open(&mut self, _req: &Request, ino: u64, _flags: u32, reply: ReplyOpen) {
-        trace!("open(ino={}, flags={})", ino, _flags);
+    fn open(&mut self, _req: &Request, ino: u64, flags: u32, reply: ReplyOpen) {
+        trace!("open(ino={}, flags={:#o})", ino, flags);
+
+        let writing = flags as c_int & libc::O_ACCMODE != libc::O_RDONLY;
+        if self.snapshot.is_some() {
+            // reads go by inode, so there is nothing to keep in a handle
+            if writing {
+                reply.error(EROFS);
+            } else {
+                reply.opened(0, handle::reply_flags_from(flags));
+            }
+            return;
+        }
 
         let path = ino_path_or_return!(self, &ino, reply);
+        let options = handle::open_options_from(flags);
+        let truncating = writing && flags as c_int & libc::O_TRUNC != 0;
+
+        if truncating {
+            if let Err(e) = self.tiering.preserve(ino, &path, &path, false) {
+                error!("keeping version of {:?} before truncating: {}", path, e);
+                reply.error(EIO);
+                return;
+            }
+        }
+        if let Err(e) = self.tiering.open(ino, &path, writing, truncating) {
+            error!("recalling {:?}: {}", path, e);
+            reply.error(EIO);
+            return;
+        }
 
-        match File::open(path) {
+        match options.open(path) {
             Ok(f) => {
-                // FIXME: race condition
-                let fh: u64 = *self.files.keys().max().unwrap_or(&10u64) + 1;
-                self.files.insert(fh, f);
+                let fh = self.next_fh();
+                self.files.insert(fh, Handle::new(ino, f, flags));
+                self.uploader.opened(ino);
                 trace!("opened file handle: {}", fh);
-                reply.opened(fh, 0);
+                reply.opened(fh, handle::reply_flags_from(flags));
             }
             Err(e) => {
                 error!("File::open: {:?}", e);
@@ -269,32 +1021,146 @@ impl<'a> Filesystem for S3HierarchicalFilesystem<'a> {
 
     fn read(&mut self,
             _req: &Request,
-            _ino: u64,
+            ino: u64,
             fh: u64,
             offset: u64,
             size: u32,
             reply: ReplyData) {
 
-        use std::io::Read;
-
         trace!("read(ino={}, fh={}, offset={}, size={})",
-               _ino,
+               ino,
                fh,
                offset,
                size);
 
-        let mut f = file_handle_or_return!(self, &fh, reply);
-        debug!("File: {:?}", f);
+        if let Some(ref view) = self.snapshot {
+            snapshot_read(view, &self.tiering, ino, offset, size, reply);
+            return;
+        }
 
-        let mut buffer = Vec::with_capacity(size as usize);
-        buffer.resize(size as usize, 0u8);
+        let h = file_handle_or_return!(self, &fh, reply);
+        debug!("File: {:?}", h.file);
+        if !h.readable() {
+            reply.error(EBADF);
+            return;
+        }
 
-        match f.read_exact(&mut buffer[..]) {
-            Ok(_) => {
-                debug!("Read {} bytes", size);
+        let evicted = match self.tiering.is_evicted(h.ino) {
+            Ok(false) => false,
+            Ok(true) => {
+                let ino = h.ino;
+                let path = ino_path_or_return!(self, &ino, reply);
+                if let Err(e) = self.tiering.cache_range(ino, &path, offset, u64::from(size)) {
+                    error!("fetching blocks of {:?}: {}", path, e);
+                    reply.error(EIO);
+                    return;
+                }
+                true
+            }
+            Err(e) => {
+                error!("reading record for {}: {}", h.ino, e);
+                reply.error(EIO);
+                return;
+            }
+        };
+        let h = file_handle_or_return!(self, &fh, reply);
+
+        // positional, so readers sharing the handle never move each other's cursor
+        let mut buffer = vec![0u8; size as usize];
+        match sys::read_full_at(&h.file, &mut buffer, offset) {
+            Ok(n) => {
+                debug!("Read {} bytes", n);
+                buffer.truncate(n);
                 reply.data(&buffer);
+                self.read_ahead(fh, offset, n as u64, evicted);
             }
-            Err(e) => reply.error(e.raw_os_error().unwrap_or(ENOENT)),
+            Err(e) => reply.error(e.raw_os_error().unwrap_or(EIO)),
+        }
+    }
+
+    fn flush(&mut self, _req: &Request, ino: u64, fh: u64, _lock_owner: u64, reply: ReplyEmpty) {
+        trace!("flush(ino={}, fh={})", ino, fh);
+
+        if self.snapshot.is_some() {
+            reply.ok();
+            return;
+        }
+
+        // called on every close of a descriptor; the upload waits for release
+        // and the settle period
+        let dirty = match self.files.get_mut(&fh) {
+            Some(h) => std::mem::replace(&mut h.dirty, false),
+            None => {
+                error!("File handle not found: {}", fh);
+                reply.error(EBADF);
+                return;
+            }
+        };
+        if dirty {
+            self.mark_dirty(ino);
+        }
+        reply.ok();
+    }
+
+    fn fsync(&mut self, _req: &Request, ino: u64, fh: u64, datasync: bool, reply: ReplyEmpty) {
+        trace!("fsync(ino={}, fh={}, datasync={})", ino, fh, datasync);
+
+        if self.snapshot.is_some() {
+            reply.ok();
+            return;
+        }
+
+        let dirty = {
+            let h = match self.files.get_mut(&fh) {
+                Some(h) => h,
+                None => {
+                    error!("File handle not found: {}", fh);
+                    reply.error(EBADF);
+                    return;
+                }
+            };
+            let result = if datasync {
+                h.file.sync_data()
+            } else {
+                h.file.sync_all()
+            };
+            if let Err(e) = result {
+                reply.error(e.raw_os_error().unwrap_or(EIO));
+                return;
+            }
+            std::mem::replace(&mut h.dirty, false)
+        };
+        if dirty {
+            self.mark_dirty(ino);
+        }
+
+        if self.durability == Durability::Object {
+            let path = ino_path_or_return!(self, &ino, reply);
+            // a background upload may predate the writes being synced
+            self.tiering.claim_wait(ino);
+            let result = self.tiering.upload(ino, &path);
+            self.tiering.unclaim(ino);
+            if let Err(e) = result {
+                error!("uploading {:?}: {}", path, e);
+                reply.error(EIO);
+                return;
+            }
+        }
+        reply.ok();
+    }
+
+    fn fsyncdir(&mut self, _req: &Request, ino: u64, _fh: u64, _datasync: bool, reply: ReplyEmpty) {
+        trace!("fsyncdir(ino={})", ino);
+
+        if self.snapshot.is_some() {
+            reply.ok();
+            return;
+        }
+
+        let path = ino_path_or_return!(self, &ino, reply);
+        match fs::File::open(&path).and_then(|d| d.sync_all()) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e.raw_os_error().unwrap_or(EIO)),
         }
     }
 
@@ -313,10 +1179,20 @@ impl<'a> Filesystem for S3HierarchicalFilesystem<'a> {
                _lock_owner,
                _flush);
 
+        if self.snapshot.is_some() {
+            reply.ok();
+            return;
+        }
+
         match self.files.remove(&fh) {
-            Some(_) => {
+            Some(h) => {
                 debug!("closed file handle: {}", fh);
+                if h.dirty {
+                    self.mark_dirty(h.ino);
+                }
+                self.uploader.closed(h.ino);
                 reply.ok();
+                self.evict_if_needed();
             }
             None => {
                 error!("File handle not found: {}", fh);
@@ -329,29 +1205,45 @@ impl<'a> Filesystem for S3HierarchicalFilesystem<'a> {
               _req: &Request,
               parent: u64,
               name: &OsStr,
-              _mode: u32,
-              _flags: u32,
+              mode: u32,
+              flags: u32,
               reply: ReplyCreate) {
-        trace!("create(parent={}, name={:?}, mode={}, flags={})",
+        use std::os::unix::fs::OpenOptionsExt;
+
+        trace!("create(parent={}, name={:?}, mode={:#o}, flags={:#o})",
                parent,
                name,
-               _mode,
-               _flags);
+               mode,
+               flags);
+
+        read_only_or_return!(self, reply);
 
         let path = full_path_or_return!(self, &parent, name, reply);
+        let mut options = handle::open_options_from(flags);
+        options.create(true).mode(mode);
+        if flags as c_int & libc::O_ACCMODE == libc::O_RDONLY {
+            // creating needs write access to the backing file whatever the caller reads
+            options.write(true);
+        }
 
-        match File::create(&path) {
+        match options.open(&path) {
             Ok(f) => {
                 trace!("File created: {:?}", f);
-                let fh: u64 = *self.files.keys().max().unwrap_or(&10u64) + 1;
+                let fh = self.next_fh();
                 debug!("Handle: {}", fh);
-                self.files.insert(fh, f);
-                match path.metadata() {
+                match f.metadata() {
                     Ok(metadata) => {
                         let attr = fileattr_from(&metadata);
-                        self.ino_paths.insert(attr.ino, path);
+                        let mut h = Handle::new(attr.ino, f, flags);
+                        h.dirty = true;
+                        self.files.insert(fh, h);
+                        self.uploader.opened(attr.ino);
+                        if let Err(e) = self.tiering.discard(attr.ino) {
+                            warn!("discarding record for {:?}: {}", path, e);
+                        }
+                        self.remember(attr.ino, parent, name_or_return!(name, reply));
                         let ttl = Timespec::new(1, 0);
-                        reply.created(&ttl, &attr, 0, fh, 0);
+                        reply.created(&ttl, &attr, 0, fh, handle::reply_flags_from(flags));
                     }
                     Err(e) => {
                         error!("std::fs::metadata: {}", e);
@@ -375,7 +1267,8 @@ impl<'a> Filesystem for S3HierarchicalFilesystem<'a> {
              _flags: u32,
              reply: ReplyWrite) {
 
-        use std::io::{Seek, SeekFrom, Write};
+        use std::io::Write;
+        use std::os::unix::fs::FileExt;
 
         trace!("write(ino={}, fh={}, offset={}, data={:?}, flags={})",
                _ino,
@@ -384,27 +1277,38 @@ impl<'a> Filesystem for S3HierarchicalFilesystem<'a> {
                data,
                _flags);
 
-        let mut f = file_handle_or_return!(self, &fh, reply);
-        debug!("File: {:?}", f);
+        read_only_or_return!(self, reply);
 
-        if let Err(e) = f.seek(SeekFrom::Start(offset)) {
-            error!("Unable to seek to position: {}", offset);
-            reply.error(e.raw_os_error().unwrap_or(ENOENT));
+        let h = file_handle_or_return!(self, &fh, reply);
+        debug!("File: {:?}", h.file);
+        if !h.writable() {
+            reply.error(EBADF);
             return;
         }
 
-        match f.write(&data) {
-            Ok(size) => {
-                debug!("Written {} bytes", size);
-                reply.written(size as u32);
+        // O_APPEND writes land at the end whatever offset the kernel computed
+        let result = if h.appending() {
+            (&h.file).write_all(data)
+        } else {
+            h.file.write_all_at(data, offset)
+        };
+        match result {
+            Ok(()) => {
+                debug!("Written {} bytes", data.len());
+                if let Some(h) = self.files.get_mut(&fh) {
+                    h.dirty = true;
+                }
+                reply.written(data.len() as u32);
             }
-            Err(e) => reply.error(e.raw_os_error().unwrap_or(ENOENT)),
+            Err(e) => reply.error(e.raw_os_error().unwrap_or(EIO)),
         }
     }
 
     fn mkdir(&mut self, _req: &Request, parent: u64, name: &OsStr, _mode: u32, reply: ReplyEntry) {
         trace!("mkdir(parent={}, name={:?}, mode={})", parent, name, _mode);
 
+        read_only_or_return!(self, reply);
+
         let path = full_path_or_return!(self, &parent, name, reply);
 
         match fs::create_dir(&path) {
@@ -413,7 +1317,7 @@ impl<'a> Filesystem for S3HierarchicalFilesystem<'a> {
                 match path.metadata() {
                     Ok(metadata) => {
                         let attr = fileattr_from(&metadata);
-                        self.ino_paths.insert(attr.ino, path);
+                        self.remember(attr.ino, parent, name_or_return!(name, reply));
                         let ttl = Timespec::new(1, 0);
                         reply.entry(&ttl, &attr, 0);
                     }
@@ -428,42 +1332,56 @@ impl<'a> Filesystem for S3HierarchicalFilesystem<'a> {
     }
 
     fn setattr(&mut self,
-               _req: &Request,
+               req: &Request,
                ino: u64,
                mode: Option<u32>,
-               _uid: Option<u32>,
-               _gid: Option<u32>,
-               _size: Option<u64>,
-               _atime: Option<Timespec>,
-               _mtime: Option<Timespec>,
-               _fh: Option<u64>,
+               uid: Option<u32>,
+               gid: Option<u32>,
+               size: Option<u64>,
+               atime: Option<Timespec>,
+               mtime: Option<Timespec>,
+               fh: Option<u64>,
                _crtime: Option<Timespec>,
                _chgtime: Option<Timespec>,
                _bkuptime: Option<Timespec>,
                _flags: Option<u32>,
                reply: ReplyAttr) {
+        use std::os::unix::fs::MetadataExt;
+
         trace!("setattr(ino={}, mode={:?}, uid={:?}, gid={:?}, size={:?}, atime={:?}, \
                 mtime={:?}, fh={:?}, crtime={:?}, chgtime={:?}, bkuptime={:?}, flags={:?})",
                ino,
                mode,
-               _uid,
-               _gid,
-               _size,
-               _atime,
-               _mtime,
-               _fh,
+               uid,
+               gid,
+               size,
+               atime,
+               mtime,
+               fh,
                _crtime,
                _chgtime,
                _bkuptime,
                _flags);
 
+        read_only_or_return!(self, reply);
+
         let path = ino_path_or_return!(self, &ino, reply);
-        let old_metadata = ok_or_return_error!(std::fs::metadata(&path), ENOENT, reply);
+        let old_metadata = ok_or_return_error!(fs::symlink_metadata(&path), ENOENT, reply);
         debug!("{:?}", old_metadata);
 
+        let caller = Caller::of(req);
+        let file = Perms::from(&old_metadata);
+
         if let Some(new_mode) = mode {
             use std::os::unix::fs::PermissionsExt;
-            debug!("new_mode: {}", new_mode);
+            debug!("new_mode: {:o}", new_mode);
+            let new_mode = match caller.chmod(&file, new_mode) {
+                Ok(m) => m,
+                Err(errno) => {
+                    reply.error(errno);
+                    return;
+                }
+            };
             let mut perms = old_metadata.permissions();
             perms.set_mode(new_mode);
             if let Err(e) = fs::set_permissions(&path, perms) {
@@ -472,17 +1390,85 @@ impl<'a> Filesystem for S3HierarchicalFilesystem<'a> {
             }
         }
 
-        none_or_return_error!(_uid, reply);
-        none_or_return_error!(_gid, reply);
-        none_or_return_error!(_size, reply);
-        none_or_return_error!(_atime, reply);
-        none_or_return_error!(_mtime, reply);
-        none_or_return_error!(_crtime, reply);
-        none_or_return_error!(_chgtime, reply);
-        none_or_return_error!(_bkuptime, reply);
+        let uid = uid.filter(|u| *u != old_metadata.uid());
+        let gid = gid.filter(|g| *g != old_metadata.gid());
+        if uid.is_some() || gid.is_some() {
+            if !caller.may_chown(&file, uid, gid) {
+                reply.error(EPERM);
+                return;
+            }
+            if let Err(e) = sys::chown(&path, uid, gid) {
+                reply.error(e.raw_os_error().unwrap_or(EIO));
+                return;
+            }
+        }
+
+        if let Some(new_size) = size {
+            let via_handle = fh.and_then(|fh| self.files.get(&fh)).map(|h| h.writable());
+            if via_handle != Some(true) && !caller.may_write(&file) {
+                reply.error(EACCES);
+                return;
+            }
+            if new_size != old_metadata.len() {
+                if let Err(e) = self.tiering.preserve(ino, &path, &path, false) {
+                    error!("keeping version of {:?} before truncating: {}", path, e);
+                    reply.error(EIO);
+                    return;
+                }
+            }
+            match self.tiering.truncate(ino, &path, new_size) {
+                Ok(true) => debug!("truncated evicted {:?} to {}", path, new_size),
+                Ok(false) => {
+                    let result = match fh.and_then(|fh| self.files.get(&fh)) {
+                        Some(h) if h.writable() => h.file.set_len(new_size),
+                        _ => {
+                            fs::OpenOptions::new()
+                                .write(true)
+                                .open(&path)
+                                .and_then(|f| f.set_len(new_size))
+                        }
+                    };
+                    if let Err(e) = result {
+                        reply.error(e.raw_os_error().unwrap_or(EIO));
+                        return;
+                    }
+                    self.mark_dirty(ino);
+                }
+                Err(e) => {
+                    error!("truncating {:?}: {}", path, e);
+                    reply.error(EIO);
+                    return;
+                }
+            }
+        }
+
+        if atime.is_some() || mtime.is_some() {
+            if !caller.may_set_times(&file) {
+                reply.error(EPERM);
+                return;
+            }
+            let current = ok_or_return_error!(fs::symlink_metadata(&path), ENOENT, reply);
+            let atime = atime.unwrap_or_else(|| {
+                Timespec::new(current.atime(), current.atime_nsec() as i32)
+            });
+            let mtime = mtime.unwrap_or_else(|| {
+                Timespec::new(current.mtime(), current.mtime_nsec() as i32)
+            });
+            if let Err(e) = sys::set_times(&path, atime, mtime) {
+                reply.error(e.raw_os_error().unwrap_or(EIO));
+                return;
+            }
+        }
+
+        // crtime, chgtime, bkuptime and flags are only sent by OS X; ctime
+        // follows from the changes above on the backing file
 
-        let new_metadata = ok_or_return_error!(std::fs::metadata(&path), ENOENT, reply);
-        let attr = fileattr_from(&new_metadata);
+        let new_metadata = ok_or_return_error!(fs::symlink_metadata(&path), ENOENT, reply);
+        if let Err(e) = self.tiering.changed(ino, &new_metadata) {
+            error!("recording change to placeholder {:?}: {}", path, e);
+        }
+        let mut attr = fileattr_from(&new_metadata);
+        attr.ino = ino;
         debug!("{:?}", new_metadata);
         let ttl = Timespec::new(1, 0);
         reply.attr(&ttl, &attr);
@@ -491,11 +1477,38 @@ impl<'a> Filesystem for S3HierarchicalFilesystem<'a> {
     fn unlink(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
         trace!("lookup(parent={}, name={:?})", parent, name);
 
+        read_only_or_return!(self, reply);
+
         let path = full_path_or_return!(self, &parent, name, reply);
+        let metadata = ok_or_return_error!(fs::symlink_metadata(&path), ENOENT, reply);
+        if let Some(trash) = self.trash.clone() {
+            use std::os::unix::fs::MetadataExt;
+            ok_or_return_error!(trash.put(&path, &metadata), EIO, reply);
+            self.forget_name(metadata.ino(), parent, name_or_return!(name, reply));
+            reply.ok();
+            return;
+        }
+        {
+            use std::os::unix::fs::MetadataExt;
+            if metadata.is_file() && metadata.nlink() <= 1 {
+                if let Err(e) = self.tiering.preserve(metadata.ino(), &path, &path, true) {
+                    error!("keeping version of {:?} before unlinking: {}", path, e);
+                    reply.error(EIO);
+                    return;
+                }
+            }
+        }
 
         match fs::remove_file(&path) {
             Ok(()) => {
                 debug!("Unlinked: {:?}", path);
+                use std::os::unix::fs::MetadataExt;
+                self.forget_name(metadata.ino(), parent, name_or_return!(name, reply));
+                if metadata.is_file() && metadata.nlink() <= 1 {
+                    if let Err(e) = self.tiering.remove(metadata.ino(), &path) {
+                        warn!("unable to remove cold copy of {:?}: {}", path, e);
+                    }
+                }
                 reply.ok();
             }
             Err(e) => {
@@ -508,11 +1521,34 @@ impl<'a> Filesystem for S3HierarchicalFilesystem<'a> {
     fn rmdir(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
         trace!("rmdir(parent={}, name={:?})", parent, name);
 
+        read_only_or_return!(self, reply);
+
         let path = full_path_or_return!(self, &parent, name, reply);
+        let metadata = ok_or_return_error!(fs::symlink_metadata(&path), ENOENT, reply);
+        if let Some(trash) = self.trash.clone() {
+            use std::os::unix::fs::MetadataExt;
+            match path.read_dir().map(|mut rd| rd.next().is_none()) {
+                Ok(true) => {}
+                Ok(false) => {
+                    reply.error(ENOTEMPTY);
+                    return;
+                }
+                Err(e) => {
+                    reply.error(e.raw_os_error().unwrap_or(EIO));
+                    return;
+                }
+            }
+            ok_or_return_error!(trash.put(&path, &metadata), EIO, reply);
+            self.forget_name(metadata.ino(), parent, name_or_return!(name, reply));
+            reply.ok();
+            return;
+        }
 
         match fs::remove_dir(&path) {
             Ok(()) => {
                 debug!("Removed dir: {:?}", path);
+                use std::os::unix::fs::MetadataExt;
+                self.forget_name(metadata.ino(), parent, name_or_return!(name, reply));
                 reply.ok();
             }
             Err(e) => {
@@ -521,4 +1557,251 @@ impl<'a> Filesystem for S3HierarchicalFilesystem<'a> {
             }
         };
     }
+
+    fn rename(&mut self,
+              _req: &Request,
+              parent: u64,
+              name: &OsStr,
+              newparent: u64,
+              newname: &OsStr,
+              reply: ReplyEmpty) {
+        trace!("rename(parent={}, name={:?}, newparent={}, newname={:?})",
+               parent,
+               name,
+               newparent,
+               newname);
+
+        read_only_or_return!(self, reply);
+
+        // the kernel protocol fuse speaks has no RENAME2, so renameat2 callers
+        // passing RENAME_NOREPLACE or RENAME_EXCHANGE get EINVAL from the kernel
+        match self.rename_entry(parent, name, newparent, newname) {
+            Ok(()) => reply.ok(),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn symlink(&mut self,
+               _req: &Request,
+               parent: u64,
+               name: &OsStr,
+               link: &Path,
+               reply: ReplyEntry) {
+        trace!("symlink(parent={}, name={:?}, link={:?})", parent, name, link);
+
+        read_only_or_return!(self, reply);
+
+        let path = full_path_or_return!(self, &parent, name, reply);
+
+        // symlinks are never evicted, so the target always lives in the backing tier
+        match std::os::unix::fs::symlink(link, &path) {
+            Ok(()) => {
+                let metadata = ok_or_return_error!(fs::symlink_metadata(&path), ENOENT, reply);
+                let attr = fileattr_from(&metadata);
+                self.remember(attr.ino, parent, name_or_return!(name, reply));
+                let ttl = Timespec::new(1, 0);
+                reply.entry(&ttl, &attr, 0);
+            }
+            Err(e) => {
+                error!("symlink: {}", e);
+                reply.error(e.raw_os_error().unwrap_or(EIO));
+            }
+        }
+    }
+
+    fn readlink(&mut self, _req: &Request, ino: u64, reply: ReplyData) {
+        use std::os::unix::ffi::OsStrExt;
+
+        trace!("readlink(ino={})", ino);
+
+        if let Some(ref view) = self.snapshot {
+            let node = node_or_return!(view, ino, reply);
+            if node.kind != Kind::Symlink {
+                reply.error(EINVAL);
+                return;
+            }
+            reply.data(&node.target);
+            return;
+        }
+
+        let path = ino_path_or_return!(self, &ino, reply);
+
+        match fs::read_link(&path) {
+            Ok(target) => reply.data(target.as_os_str().as_bytes()),
+            Err(e) => {
+                error!("fs::read_link: {}", e);
+                reply.error(e.raw_os_error().unwrap_or(EIO));
+            }
+        }
+    }
+
+    fn link(&mut self,
+            _req: &Request,
+            ino: u64,
+            newparent: u64,
+            newname: &OsStr,
+            reply: ReplyEntry) {
+        trace!("link(ino={}, newparent={}, newname={:?})", ino, newparent, newname);
+
+        read_only_or_return!(self, reply);
+
+        let path = ino_path_or_return!(self, &ino, reply);
+        let new_path = full_path_or_return!(self, &newparent, newname, reply);
+
+        match fs::hard_link(&path, &new_path) {
+            Ok(()) => {
+                // nlink comes back from the backing filesystem, already counting the new name
+                let metadata = ok_or_return_error!(fs::symlink_metadata(&new_path), ENOENT, reply);
+                let attr = fileattr_from(&metadata);
+                self.remember(attr.ino, newparent, name_or_return!(newname, reply));
+                let ttl = Timespec::new(1, 0);
+                reply.entry(&ttl, &attr, 0);
+            }
+            Err(e) => {
+                error!("fs::hard_link: {}", e);
+                reply.error(e.raw_os_error().unwrap_or(EIO));
+            }
+        }
+    }
+
+    fn statfs(&mut self, _req: &Request, ino: u64, reply: ReplyStatfs) {
+        trace!("statfs(ino={})", ino);
+
+        let usage = ok_or_return_error!(sys::statvfs(Path::new(self._backing_path)), EIO, reply);
+        let cold_bytes = ok_or_return_error!(self.tiering.cold_bytes(), EIO, reply);
+        let inodes = ok_or_return_error!(self.inodes.lock().unwrap().len(), EIO, reply);
+
+        // the object store stretches the backing path by a virtual capacity,
+        // of which evicted content has used some
+        let cold_free = self.cold_capacity.saturating_sub(cold_bytes);
+        let frsize = u64::from(usage.fragment_size.max(1));
+        let blocks = (usage.total_bytes + self.cold_capacity) / frsize;
+        let bfree = (usage.free_bytes + cold_free) / frsize;
+        let bavail = (usage.available_bytes + cold_free) / frsize;
+        let files = inodes + usage.free_inodes;
+        debug!("statfs: {} blocks of {}, {} free, {} files", blocks, frsize, bfree, files);
+        reply.statfs(blocks,
+                     bfree,
+                     bavail,
+                     files,
+                     usage.free_inodes,
+                     usage.block_size,
+                     usage.name_max,
+                     usage.fragment_size);
+    }
+
+    fn setxattr(&mut self,
+                req: &Request,
+                ino: u64,
+                name: &OsStr,
+                value: &[u8],
+                flags: u32,
+                _position: u32,
+                reply: ReplyEmpty) {
+        trace!("setxattr(ino={}, name={:?}, len={}, flags={})",
+               ino,
+               name,
+               value.len(),
+               flags);
+
+        read_only_or_return!(self, reply);
+        if name == XATTR_SNAPSHOT {
+            self.take_snapshot(req, ino, value, reply);
+            return;
+        }
+        if VIRTUAL_XATTRS.iter().any(|v| name == *v) {
+            reply.error(EPERM);
+            return;
+        }
+        let path = ino_path_or_return!(self, &ino, reply);
+        if let Err(errno) = may_change_xattr(req, &path, name) {
+            reply.error(errno);
+            return;
+        }
+        match sys::set_xattr(&path, name, value, flags) {
+            Ok(()) => {
+                if let Err(e) = self.tiering.xattrs_changed(ino, &path) {
+                    warn!("updating sidecar of {:?}: {}", path, e);
+                }
+                reply.ok();
+            }
+            Err(e) => reply.error(e.raw_os_error().unwrap_or(EIO)),
+        }
+    }
+
+    fn getxattr(&mut self, _req: &Request, ino: u64, name: &OsStr, size: u32, reply: ReplyXattr) {
+        trace!("getxattr(ino={}, name={:?}, size={})", ino, name, size);
+
+        if let Some(ref view) = self.snapshot {
+            let node = node_or_return!(view, ino, reply);
+            match node.xattrs.get(&*name.to_string_lossy()) {
+                Some(v) => reply_xattr(reply, size, v),
+                None => reply.error(ENODATA),
+            }
+            return;
+        }
+
+        let path = ino_path_or_return!(self, &ino, reply);
+        if let Some(value) = self.virtual_xattr(ino, name) {
+            match value {
+                Ok(v) => reply_xattr(reply, size, &v),
+                Err(errno) => reply.error(errno),
+            }
+            return;
+        }
+        match sys::get_xattr(&path, name) {
+            Ok(v) => reply_xattr(reply, size, &v),
+            Err(e) => reply.error(e.raw_os_error().unwrap_or(EIO)),
+        }
+    }
+
+    fn listxattr(&mut self, _req: &Request, ino: u64, size: u32, reply: ReplyXattr) {
+        trace!("listxattr(ino={}, size={})", ino, size);
+
+        if let Some(ref view) = self.snapshot {
+            let node = node_or_return!(view, ino, reply);
+            let mut names = Vec::new();
+            for name in node.xattrs.keys() {
+                names.extend_from_slice(name.as_bytes());
+                names.push(0);
+            }
+            reply_xattr(reply, size, &names);
+            return;
+        }
+
+        let path = ino_path_or_return!(self, &ino, reply);
+        let mut names = ok_or_return_error!(sys::list_xattr(&path), EIO, reply);
+        for name in VIRTUAL_XATTRS {
+            if let Some(Ok(_)) = self.virtual_xattr(ino, OsStr::new(name)) {
+                names.extend_from_slice(name.as_bytes());
+                names.push(0);
+            }
+        }
+        reply_xattr(reply, size, &names);
+    }
+
+    fn removexattr(&mut self, req: &Request, ino: u64, name: &OsStr, reply: ReplyEmpty) {
+        trace!("removexattr(ino={}, name={:?})", ino, name);
+
+        read_only_or_return!(self, reply);
+
+        if VIRTUAL_XATTRS.iter().any(|v| name == *v) {
+            reply.error(EPERM);
+            return;
+        }
+        let path = ino_path_or_return!(self, &ino, reply);
+        if let Err(errno) = may_change_xattr(req, &path, name) {
+            reply.error(errno);
+            return;
+        }
+        match sys::remove_xattr(&path, name) {
+            Ok(()) => {
+                if let Err(e) = self.tiering.xattrs_changed(ino, &path) {
+                    warn!("updating sidecar of {:?}: {}", path, e);
+                }
+                reply.ok();
+            }
+            Err(e) => reply.error(e.raw_os_error().unwrap_or(EIO)),
+        }
+    }
 }
@@ -0,0 +1,114 @@
+use errors::*;
+
+use super::tier::Tiering;
+
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::thread;
+
+/// How far ahead of readers evicted files are fetched.
+#[derive(Debug, Clone)]
+pub struct PrefetchPolicy {
+    /// blocks fetched ahead of a sequential reader; none turns prefetch off
+    pub blocks: u64,
+    /// prefetches running at once
+    pub threads: usize,
+    /// commands whose listing of a directory fetches the first blocks of
+    /// the evicted files in it
+    pub tools: Vec<String>,
+}
+
+struct Job {
+    ino: u64,
+    path: PathBuf,
+    offset: u64,
+    len: u64,
+}
+
+/// Fetches blocks of evicted files into the hot tier in the background,
+/// ahead of the reads that will want them. Requests beyond what the
+/// workers keep up with are dropped: the read fetches the block itself.
+pub struct Prefetcher {
+    tx: Option<SyncSender<Job>>,
+    window: u64,
+    tools: Vec<String>,
+}
+
+/// Name of the command running as `pid`, as the kernel reports it.
+fn command_of(pid: u32) -> Option<String> {
+    fs::read_to_string(format!("/proc/{}/comm", pid)).ok().map(|c| c.trim_end().to_string())
+}
+
+impl Prefetcher {
+    pub fn start(tiering: Arc<Tiering>, policy: PrefetchPolicy) -> Result<Prefetcher> {
+        let window = policy.blocks * tiering.block_size();
+        if policy.blocks == 0 || policy.threads == 0 {
+            return Ok(Prefetcher {
+                tx: None,
+                window,
+                tools: policy.tools,
+            });
+        }
+
+        let (tx, rx) = sync_channel::<Job>(policy.threads * 4);
+        let rx = Arc::new(Mutex::new(rx));
+        for n in 0..policy.threads {
+            let rx = rx.clone();
+            let tiering = tiering.clone();
+            thread::Builder::new()
+                .name(format!("prefetch-{}", n))
+                .spawn(move || work(&rx, &tiering))
+                .chain_err(|| "starting prefetch thread")?;
+        }
+        Ok(Prefetcher {
+            tx: Some(tx),
+            window,
+            tools: policy.tools,
+        })
+    }
+
+    /// Bytes fetched ahead of a sequential reader.
+    pub fn window(&self) -> u64 {
+        self.window
+    }
+
+    /// Whether a directory listing by `pid` should prefetch its files.
+    pub fn wanted_by(&self, pid: u32) -> bool {
+        if self.tx.is_none() || self.tools.is_empty() {
+            return false;
+        }
+        command_of(pid).is_some_and(|c| self.tools.contains(&c))
+    }
+
+    /// Fetch `len` bytes at `offset` of evicted `ino` in the background.
+    pub fn request(&self, ino: u64, path: PathBuf, offset: u64, len: u64) {
+        let tx = match self.tx {
+            Some(ref tx) => tx,
+            None => return,
+        };
+        match tx.try_send(Job { ino, path, offset, len }) {
+            Ok(()) => {}
+            Err(TrySendError::Full(job)) => trace!("prefetch queue full, dropping {:?}", job.path),
+            Err(TrySendError::Disconnected(_)) => warn!("prefetch threads are gone"),
+        }
+    }
+}
+
+fn work(rx: &Mutex<Receiver<Job>>, tiering: &Tiering) {
+    loop {
+        let job = match rx.lock().unwrap().recv() {
+            Ok(job) => job,
+            Err(_) => return,
+        };
+        if !tiering.claim(job.ino) {
+            // being recalled, truncated or evicted; the reader will manage
+            continue;
+        }
+        if let Err(e) = tiering.cache_range(job.ino, &job.path, job.offset, job.len) {
+            debug!("prefetching {:?} at {}: {}", job.path, job.offset, e);
+        }
+        tiering.unclaim(job.ino);
+    }
+}
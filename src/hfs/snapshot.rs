@@ -0,0 +1,544 @@
+use errors::*;
+
+use super::inode::ROOT_INO;
+use super::kv::KvStore;
+use super::stub;
+use super::tier::{self, Tiering, STATE_DIR};
+
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+// A snapshot is the namespace as it stood when it was taken: a node per
+// inode, under the inode's number, with its attributes, the entries of a
+// directory, the target of a symlink and, for a file, the object in the
+// cold tier holding its content. Objects are pinned rather than copied, so
+// that neither a later upload nor a removal touches them until the last
+// snapshot naming them is deleted. Files the object store does not hold yet
+// are journalled for upload and wait; the snapshot is listed once they are
+// all uploaded. One written again before that is taken as first uploaded.
+
+/// Catalogue of the snapshots taken.
+const CATALOGUE: &str = "snapshots";
+/// Directory under which each snapshot keeps its nodes.
+const NODES: &str = "snapshot";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum Kind {
+    File,
+    Directory,
+    Symlink,
+}
+
+/// An inode as it was when the snapshot was taken.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Node {
+    pub kind: Kind,
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub nlink: u32,
+    pub size: u64,
+    pub atime: (i64, i32),
+    pub mtime: (i64, i32),
+    pub ctime: (i64, i32),
+    pub xattrs: BTreeMap<String, Vec<u8>>,
+    /// entries of a directory, by name
+    #[serde(default)]
+    pub children: BTreeMap<String, u64>,
+    /// target of a symlink
+    #[serde(default)]
+    pub target: Vec<u8>,
+    /// the object holding a file's content, of which the first `stored`
+    /// bytes belong to the file; the rest of it is zeros
+    #[serde(default)]
+    pub key: String,
+    #[serde(default)]
+    pub stored: u64,
+    #[serde(default)]
+    pub compressed: bool,
+    #[serde(default)]
+    pub chunked: bool,
+}
+
+impl Node {
+    fn new(kind: Kind, metadata: &fs::Metadata, path: &Path) -> Result<Node> {
+        Ok(Node {
+            kind,
+            mode: metadata.mode(),
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+            nlink: metadata.nlink() as u32,
+            size: metadata.len(),
+            atime: (metadata.atime(), metadata.atime_nsec() as i32),
+            mtime: stub::mtime_of(metadata),
+            ctime: (metadata.ctime(), metadata.ctime_nsec() as i32),
+            xattrs: tier::xattrs_of(path)?,
+            children: BTreeMap::new(),
+            target: Vec::new(),
+            key: String::new(),
+            stored: 0,
+            compressed: false,
+            chunked: false,
+        })
+    }
+}
+
+/// A snapshot as listed.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Snapshot {
+    pub name: String,
+    /// milliseconds since the epoch at which it was taken
+    pub taken: u64,
+    pub files: u64,
+    pub bytes: u64,
+}
+
+fn node_key(ino: u64) -> String {
+    format!("{:016x}", ino)
+}
+
+fn nodes_path(backing: &Path, name: &str) -> PathBuf {
+    backing.join(STATE_DIR).join(NODES).join(name)
+}
+
+fn catalogue(backing: &Path) -> Result<KvStore> {
+    KvStore::open(backing.join(STATE_DIR).join(CATALOGUE))
+}
+
+fn check_name(name: &str) -> Result<()> {
+    let valid = !name.is_empty() && !name.starts_with('.') &&
+                name.chars().all(|c| c.is_ascii_alphanumeric() || "._-".contains(c));
+    if !valid {
+        bail!("snapshot names are letters, digits, '.', '_' and '-': {:?}", name);
+    }
+    Ok(())
+}
+
+/// A file the object store did not hold when it was walked.
+struct Waiting {
+    ino: u64,
+    path: PathBuf,
+    /// inode, parent and name of each directory down from the root to the
+    /// file, and of the file last, for the uploader to find it by and to
+    /// drop it should it be removed before uploaded
+    names: Vec<(u64, u64, String)>,
+}
+
+struct Taking<'a> {
+    backing: &'a Path,
+    tiering: &'a Tiering,
+    nodes: KvStore,
+    snapshot: Snapshot,
+    /// files already taken, under another name
+    seen: HashSet<u64>,
+    /// inode, parent and name of each directory down to the one walked
+    parents: Vec<(u64, u64, String)>,
+    waiting: Vec<Waiting>,
+}
+
+impl<'a> Taking<'a> {
+    fn directory(&mut self, path: &Path, ino: u64) -> Result<()> {
+        let metadata = fs::symlink_metadata(path).chain_err(|| format!("stat {:?}", path))?;
+        let mut node = Node::new(Kind::Directory, &metadata, path)?;
+        let rd = fs::read_dir(path).chain_err(|| format!("listing {:?}", path))?;
+        for entry in rd {
+            let entry = entry.chain_err(|| format!("listing {:?}", path))?;
+            if path == self.backing && entry.file_name() == STATE_DIR {
+                continue;
+            }
+            let child = entry.path();
+            let name = match entry.file_name().into_string() {
+                Ok(n) => n,
+                Err(_) => {
+                    warn!("leaving {:?} out of the snapshot: name is not UTF-8", child);
+                    continue;
+                }
+            };
+            let metadata = match fs::symlink_metadata(&child) {
+                Ok(m) => m,
+                // removed since listed
+                Err(_) => continue,
+            };
+            if metadata.is_dir() {
+                self.parents.push((metadata.ino(), ino, name.clone()));
+                let walked = self.directory(&child, metadata.ino());
+                self.parents.pop();
+                walked?;
+            } else if metadata.is_file() {
+                if self.seen.insert(metadata.ino()) {
+                    self.file(&child, &metadata, ino, &name)?;
+                }
+            } else if metadata.file_type().is_symlink() {
+                let mut link = Node::new(Kind::Symlink, &metadata, &child)?;
+                link.target = fs::read_link(&child)
+                    .chain_err(|| format!("reading link {:?}", child))?
+                    .as_os_str()
+                    .as_bytes()
+                    .to_vec();
+                self.nodes.put(&node_key(metadata.ino()), &link)?;
+            } else {
+                warn!("leaving {:?} out of the snapshot: not a file, directory or symlink",
+                      child);
+                continue;
+            }
+            node.children.insert(name, metadata.ino());
+        }
+        self.nodes.put(&node_key(ino), &node)
+    }
+
+    /// Pin the object holding the content of the file, or journal the file
+    /// for upload and leave it waiting.
+    fn file(&mut self,
+            path: &Path,
+            metadata: &fs::Metadata,
+            parent: u64,
+            name: &str)
+            -> Result<()> {
+        let ino = metadata.ino();
+        let mut node = Node::new(Kind::File, metadata, path)?;
+        match self.tiering.pin_current(ino, &self.snapshot.name)? {
+            Some(record) => {
+                node.key = record.key;
+                node.stored = record.size.min(metadata.len());
+                node.compressed = record.compressed;
+                node.chunked = record.chunked;
+            }
+            // nothing to hold
+            None if metadata.len() == 0 => {}
+            None => {
+                self.tiering.queue(ino)?;
+                let mut names = self.parents.clone();
+                names.push((ino, parent, name.to_string()));
+                self.waiting.push(Waiting {
+                    ino,
+                    path: path.to_path_buf(),
+                    names,
+                });
+            }
+        }
+        self.snapshot.files += 1;
+        self.snapshot.bytes += node.size;
+        self.nodes.put(&node_key(ino), &node)
+    }
+}
+
+/// A snapshot walked, waiting for files to be uploaded before it is listed.
+pub struct Taken {
+    backing: PathBuf,
+    nodes: KvStore,
+    snapshot: Snapshot,
+    waiting: Vec<Waiting>,
+}
+
+impl Taken {
+    /// Names leading to the files waiting, as inode, parent and name, from
+    /// the root down.
+    pub fn waiting(&self) -> Vec<(u64, u64, &str)> {
+        self.waiting
+            .iter()
+            .flat_map(|w| w.names.iter().map(|&(ino, parent, ref name)| (ino, parent, &name[..])))
+            .collect()
+    }
+
+    /// Take in the waiting files the object store now holds, and drop those
+    /// removed before it did. Returns whether any are still waiting.
+    fn settle(&mut self, tiering: &Tiering) -> Result<bool> {
+        let mut still = Vec::new();
+        for waiting in self.waiting.drain(..) {
+            let key = node_key(waiting.ino);
+            let mut node = match self.nodes.get::<Node>(&key)? {
+                Some(n) => n,
+                None => bail!("node of waiting inode {} is missing", waiting.ino),
+            };
+            match tiering.pin_current(waiting.ino, &self.snapshot.name)? {
+                Some(record) => {
+                    self.snapshot.bytes = self.snapshot.bytes - node.size + record.length;
+                    node.size = record.length;
+                    node.mtime = record.mtime;
+                    node.key = record.key;
+                    node.stored = record.size.min(record.length);
+                    node.compressed = record.compressed;
+                    node.chunked = record.chunked;
+                    self.nodes.put(&key, &node)?;
+                }
+                None if tiering.record(waiting.ino)?.is_none() && !tiering.queued(waiting.ino) => {
+                    warn!("leaving {:?} out of snapshot {}: removed before it was uploaded",
+                          waiting.path,
+                          self.snapshot.name);
+                    if let Some(&(_, parent, ref name)) = waiting.names.last() {
+                        let parent_key = node_key(parent);
+                        if let Some(mut node) = self.nodes.get::<Node>(&parent_key)? {
+                            node.children.remove(name);
+                            self.nodes.put(&parent_key, &node)?;
+                        }
+                    }
+                    self.nodes.delete(&key)?;
+                    self.snapshot.files -= 1;
+                    self.snapshot.bytes -= node.size;
+                }
+                None => still.push(waiting),
+            }
+        }
+        self.waiting = still;
+        Ok(!self.waiting.is_empty())
+    }
+
+    /// List the snapshot, now that nothing is waiting.
+    fn finish(self) -> Result<Snapshot> {
+        // listed only once complete; an interrupted one is cleared by deleting it
+        catalogue(&self.backing)?.put(&self.snapshot.name, &self.snapshot)?;
+        info!("took snapshot {} of {} files, {} bytes",
+              self.snapshot.name,
+              self.snapshot.files,
+              self.snapshot.bytes);
+        Ok(self.snapshot)
+    }
+
+    /// Upload the waiting files here and now and list the snapshot, for a
+    /// filesystem that is not mounted.
+    pub fn upload(mut self, tiering: &Tiering) -> Result<Snapshot> {
+        for waiting in &self.waiting {
+            tiering.claim_wait(waiting.ino);
+            let result = tiering.upload(waiting.ino, &waiting.path);
+            tiering.unclaim(waiting.ino);
+            result?;
+        }
+        if self.settle(tiering)? {
+            bail!("files changed while snapshot {} was taken", self.snapshot.name);
+        }
+        self.finish()
+    }
+
+    /// Leave the waiting files to the uploader and list the snapshot once it
+    /// has uploaded them, from a thread of its own. Should that fail, what
+    /// was taken is deleted.
+    pub fn finish_later(mut self, tiering: Arc<Tiering>) -> Result<()> {
+        let name = self.snapshot.name.clone();
+        let starting = format!("starting to complete snapshot {}", name);
+        thread::Builder::new()
+            .name(format!("snapshot-{}", name))
+            .spawn(move || {
+                loop {
+                    match self.settle(&tiering) {
+                        Ok(true) => thread::sleep(Duration::from_secs(1)),
+                        Ok(false) => break,
+                        Err(e) => {
+                            error!("completing snapshot {}: {}", name, e);
+                            if let Err(e) = delete(&self.backing, &tiering, &name) {
+                                error!("deleting incomplete snapshot {}: {}", name, e);
+                            }
+                            return;
+                        }
+                    }
+                }
+                let backing = self.backing.clone();
+                if let Err(e) = self.finish() {
+                    error!("listing snapshot {}: {}", name, e);
+                    if let Err(e) = delete(&backing, &tiering, &name) {
+                        error!("deleting incomplete snapshot {}: {}", name, e);
+                    }
+                }
+            })
+            .chain_err(|| starting)?;
+        Ok(())
+    }
+}
+
+/// Walk the filesystem backed by `backing` for snapshot `name`, pinning the
+/// objects holding files' current content and journalling the rest for
+/// upload.
+pub fn start(backing: &Path, tiering: &Tiering, name: &str) -> Result<Taken> {
+    check_name(name)?;
+    let dir = nodes_path(backing, name);
+    if dir.exists() {
+        bail!("snapshot {} exists", name);
+    }
+    let mut taking = Taking {
+        backing,
+        tiering,
+        nodes: KvStore::open(&dir)?,
+        snapshot: Snapshot {
+            name: name.to_string(),
+            taken: tier::now_millis(),
+            files: 0,
+            bytes: 0,
+        },
+        seen: HashSet::new(),
+        parents: Vec::new(),
+        waiting: Vec::new(),
+    };
+    taking.directory(backing, ROOT_INO)?;
+    Ok(Taken {
+        backing: backing.to_path_buf(),
+        nodes: taking.nodes,
+        snapshot: taking.snapshot,
+        waiting: taking.waiting,
+    })
+}
+
+/// Take snapshot `name` of the filesystem backed by `backing`, which is not
+/// mounted, uploading whatever the object store does not yet hold.
+pub fn take(backing: &Path, tiering: &Tiering, name: &str) -> Result<Snapshot> {
+    start(backing, tiering, name)?.upload(tiering)
+}
+
+/// Whether snapshot `name` has been started, listed or not.
+pub fn started(backing: &Path, name: &str) -> bool {
+    nodes_path(backing, name).exists()
+}
+
+/// Snapshots taken, oldest first.
+pub fn list(backing: &Path) -> Result<Vec<Snapshot>> {
+    let catalogue = catalogue(backing)?;
+    let mut found = Vec::new();
+    for name in catalogue.keys()? {
+        if let Some(snapshot) = catalogue.get::<Snapshot>(&name)? {
+            found.push(snapshot);
+        }
+    }
+    found.sort_by_key(|s| s.taken);
+    Ok(found)
+}
+
+/// Delete snapshot `name`, or what was taken of it, releasing the objects
+/// it held.
+pub fn delete(backing: &Path, tiering: &Tiering, name: &str) -> Result<()> {
+    check_name(name)?;
+    let dir = nodes_path(backing, name);
+    if !dir.exists() {
+        bail!("no snapshot {}", name);
+    }
+    let nodes = KvStore::open(&dir)?;
+    for key in nodes.keys()? {
+        if let Some(node) = nodes.get::<Node>(&key)? {
+            if node.kind == Kind::File && !node.key.is_empty() {
+                tiering.unpin(&node.key, name)?;
+            }
+        }
+    }
+    catalogue(backing)?.delete(name)?;
+    fs::remove_dir_all(&dir).chain_err(|| format!("removing {:?}", dir))
+}
+
+/// A snapshot opened to be mounted: nodes are read as they are asked for.
+pub struct SnapshotView {
+    nodes: KvStore,
+}
+
+impl SnapshotView {
+    pub fn open(backing: &Path, name: &str) -> Result<SnapshotView> {
+        check_name(name)?;
+        if catalogue(backing)?.get::<Snapshot>(name)?.is_none() {
+            bail!("no snapshot {}", name);
+        }
+        Ok(SnapshotView { nodes: KvStore::open(nodes_path(backing, name))? })
+    }
+
+    pub fn node(&self, ino: u64) -> Result<Option<Node>> {
+        self.nodes.get(&node_key(ino))
+    }
+
+    /// Inode and node of `name` in directory `parent`.
+    pub fn child(&self, parent: u64, name: &str) -> Result<Option<(u64, Node)>> {
+        let ino = match self.node(parent)? {
+            Some(ref p) => {
+                match p.children.get(name) {
+                    Some(ino) => *ino,
+                    None => return Ok(None),
+                }
+            }
+            None => return Ok(None),
+        };
+        Ok(self.node(ino)?.map(|n| (ino, n)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::store::ObjectStore;
+    use super::super::tier::tests::{content, file, tiering};
+
+    use tempfile;
+
+    fn read(tiering: &Tiering, node: &Node) -> Vec<u8> {
+        tiering.read_object(&node.key, node.compressed, node.chunked, 0, node.stored).unwrap()
+    }
+
+    #[test]
+    fn snapshot_keeps_content_as_it_was_taken() {
+        let dir = tempfile::tempdir().unwrap();
+        let (tiering, _) = tiering(dir.path(), Duration::from_secs(0));
+        let backing = dir.path().join("backing");
+        let (a, a_ino) = file(&tiering, "a", &content(100));
+        fs::create_dir(backing.join("d")).unwrap();
+        let (b, b_ino) = file(&tiering, "d/b", &content(200));
+
+        let snapshot = take(&backing, &tiering, "s1").unwrap();
+        assert_eq!((snapshot.files, snapshot.bytes), (2, 300));
+        assert_eq!(list(&backing).unwrap().len(), 1);
+
+        fs::write(&a, content(50)).unwrap();
+        tiering.mark_dirty(a_ino).unwrap();
+        tiering.upload(a_ino, &a).unwrap();
+        fs::remove_file(&b).unwrap();
+        tiering.remove(b_ino, &b).unwrap();
+
+        let view = SnapshotView::open(&backing, "s1").unwrap();
+        let (ino, node) = view.child(ROOT_INO, "a").unwrap().unwrap();
+        assert_eq!((ino, node.kind, node.size), (a_ino, Kind::File, 100));
+        assert_eq!(read(&tiering, &node), content(100));
+        let (d_ino, _) = view.child(ROOT_INO, "d").unwrap().unwrap();
+        let (_, node) = view.child(d_ino, "b").unwrap().unwrap();
+        assert_eq!(read(&tiering, &node), content(200));
+    }
+
+    #[test]
+    fn deleted_snapshot_releases_the_objects_only_it_held() {
+        let dir = tempfile::tempdir().unwrap();
+        let (tiering, store) = tiering(dir.path(), Duration::from_secs(0));
+        let backing = dir.path().join("backing");
+        let (a, a_ino) = file(&tiering, "a", &content(100));
+        file(&tiering, "c", &content(100));
+        take(&backing, &tiering, "s1").unwrap();
+        let old = SnapshotView::open(&backing, "s1")
+            .unwrap()
+            .child(ROOT_INO, "a")
+            .unwrap()
+            .unwrap()
+            .1
+            .key;
+
+        fs::write(&a, content(50)).unwrap();
+        tiering.mark_dirty(a_ino).unwrap();
+        tiering.upload(a_ino, &a).unwrap();
+        let current = tiering.record(a_ino).unwrap().unwrap().key;
+        assert!(current != old);
+        assert!(store.head(&old).unwrap().is_some());
+
+        delete(&backing, &tiering, "s1").unwrap();
+        assert!(store.head(&old).unwrap().is_none());
+        assert!(store.head(&current).unwrap().is_some());
+        assert_eq!(store.list("data/").unwrap().len(), 2);
+        assert!(list(&backing).unwrap().is_empty());
+        assert!(SnapshotView::open(&backing, "s1").is_err());
+        assert!(delete(&backing, &tiering, "s1").is_err());
+    }
+
+    #[test]
+    fn names_are_checked_and_not_reused() {
+        let dir = tempfile::tempdir().unwrap();
+        let (tiering, _) = tiering(dir.path(), Duration::from_secs(0));
+        let backing = dir.path().join("backing");
+        assert!(take(&backing, &tiering, "../s1").is_err());
+        assert!(take(&backing, &tiering, ".s1").is_err());
+        take(&backing, &tiering, "s1").unwrap();
+        assert!(take(&backing, &tiering, "s1").is_err());
+    }
+}
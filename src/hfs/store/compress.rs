@@ -0,0 +1,384 @@
+use errors::*;
+
+use super::{ObjectInfo, ObjectStore};
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+// A compressed object is a small manifest under its own key, giving the
+// codec, frame size and uncompressed length, and the content cut into
+// frames of that size, each compressed on its own and stored under
+// `<key>.frames/<generation>/<n>`. A range is served by fetching and
+// expanding only the frames it covers. Each frame starts with a byte saying
+// how it is held, so that frames which do not shrink are kept as they are.
+//
+// Every put or upload writes its frames under a new generation, which the
+// manifest names once they are all stored. Until it is swapped the object
+// stays as it was, and a reader never sees old and new frames mixed; the
+// frames of other generations are dropped after.
+
+const RAW: u8 = 0;
+const ZSTD: u8 = 1;
+const LZ4: u8 = 2;
+
+/// How frames are compressed.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum Codec {
+    Zstd,
+    Lz4,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Manifest {
+    codec: Codec,
+    frame_size: u64,
+    length: u64,
+    /// the frames of the object; empty for objects stored before frames
+    /// had generations, whose frames are under `<key>.frames/<n>`
+    #[serde(default)]
+    generation: String,
+}
+
+fn frames_prefix(key: &str) -> String {
+    format!("{}.frames/", key)
+}
+
+fn frame_key(key: &str, generation: &str, n: u64) -> String {
+    if generation.is_empty() {
+        format!("{}{:08x}", frames_prefix(key), n)
+    } else {
+        format!("{}{}/{:08x}", frames_prefix(key), generation, n)
+    }
+}
+
+fn new_generation() -> String {
+    use rand::Rng;
+
+    format!("{:016x}", ::rand::thread_rng().gen::<u64>())
+}
+
+/// The object a frame belongs to, if `key` is a frame.
+pub fn frame_of(key: &str) -> Option<&str> {
+    key.find(".frames/").map(|i| &key[..i])
+}
+
+fn is_frame(key: &str) -> bool {
+    key.contains(".frames/")
+}
+
+/// Compress `data` into a frame, or keep it as it is if `codec` is `None`
+/// or it does not shrink.
+pub fn pack(codec: Option<Codec>, level: i32, data: &[u8]) -> Result<Vec<u8>> {
+    let (method, packed) = match codec {
+        Some(Codec::Zstd) => {
+            (ZSTD, ::zstd::bulk::compress(data, level).chain_err(|| "compressing frame")?)
+        }
+        Some(Codec::Lz4) => (LZ4, ::lz4_flex::compress(data)),
+        None => (RAW, Vec::new()),
+    };
+    let mut frame = Vec::with_capacity(packed.len().min(data.len()) + 1);
+    if method != RAW && packed.len() < data.len() {
+        frame.push(method);
+        frame.extend_from_slice(&packed);
+    } else {
+        frame.push(RAW);
+        frame.extend_from_slice(data);
+    }
+    Ok(frame)
+}
+
+/// The `len` bytes packed into `frame`.
+pub fn unpack(frame: &[u8], len: u64) -> Result<Vec<u8>> {
+    let data = match frame.split_first() {
+        Some((&RAW, data)) => data.to_vec(),
+        Some((&ZSTD, packed)) => {
+            ::zstd::bulk::decompress(packed, len as usize).chain_err(|| "frame is corrupt")?
+        }
+        Some((&LZ4, packed)) => {
+            ::lz4_flex::decompress(packed, len as usize).chain_err(|| "frame is corrupt")?
+        }
+        Some((method, _)) => bail!("frame held by unknown method {}", method),
+        None => bail!("frame is empty"),
+    };
+    if data.len() as u64 != len {
+        bail!("frame holds {} bytes rather than {}", data.len(), len);
+    }
+    Ok(data)
+}
+
+/// Wraps another store so that content is kept compressed in frames.
+pub struct CompressedStore {
+    inner: Arc<dyn ObjectStore>,
+    codec: Codec,
+    /// zstd level; lz4 has none
+    level: i32,
+    frame_size: u64,
+    /// manifests of objects read recently
+    manifests: Mutex<HashMap<String, Manifest>>,
+}
+
+impl CompressedStore {
+    pub fn new(inner: Arc<dyn ObjectStore>,
+               codec: Codec,
+               level: i32,
+               frame_size: u64)
+               -> CompressedStore {
+        CompressedStore {
+            inner,
+            codec,
+            level,
+            frame_size,
+            manifests: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Compress and store the frames of `data`, which starts at frame `first`.
+    fn put_frames(&self, key: &str, generation: &str, first: u64, data: &[u8]) -> Result<()> {
+        for (i, plain) in data.chunks(self.frame_size as usize).enumerate() {
+            let frame = pack(Some(self.codec), self.level, plain)?;
+            self.inner.put(&frame_key(key, generation, first + i as u64), &frame)?;
+        }
+        Ok(())
+    }
+
+    fn put_manifest(&self, key: &str, length: u64, generation: &str) -> Result<()> {
+        let manifest = Manifest {
+            codec: self.codec,
+            frame_size: self.frame_size,
+            length,
+            generation: generation.to_string(),
+        };
+        let encoded = ::serde_json::to_vec(&manifest).chain_err(|| "encoding manifest")?;
+        self.forget_manifest(key);
+        self.inner.put(key, &encoded)
+    }
+
+    fn manifest(&self, key: &str) -> Result<Option<Manifest>> {
+        if let Some(manifest) = self.manifests.lock().unwrap().get(key) {
+            return Ok(Some(manifest.clone()));
+        }
+        if self.inner.head(key)?.is_none() {
+            return Ok(None);
+        }
+        let manifest: Manifest = ::serde_json::from_slice(&self.inner.get(key)?)
+            .chain_err(|| format!("{} is not a compressed object", key))?;
+        if manifest.frame_size == 0 {
+            bail!("{} has frames of no size", key);
+        }
+        self.manifests.lock().unwrap().insert(key.to_string(), manifest.clone());
+        Ok(Some(manifest))
+    }
+
+    fn forget_manifest(&self, key: &str) {
+        self.manifests.lock().unwrap().remove(key);
+    }
+
+    /// Remove the frames of `key` of the generations `dropped` picks.
+    fn drop_frames<F: Fn(&str) -> bool>(&self, key: &str, dropped: F) -> Result<()> {
+        let prefix = frames_prefix(key);
+        for info in self.inner.list(&prefix)? {
+            let generation = info.key[prefix.len()..].rsplit_once('/').map(|g| g.0).unwrap_or("");
+            if dropped(generation) {
+                self.inner.delete(&info.key)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn read_range(&self, key: &str, offset: u64, len: u64) -> Result<Vec<u8>> {
+        let manifest = match self.manifest(key)? {
+            Some(m) => m,
+            None => bail!("get {}: no such object", key),
+        };
+        let end = manifest.length.min(offset.saturating_add(len));
+        if offset >= end {
+            return Ok(Vec::new());
+        }
+        let first = offset / manifest.frame_size;
+        let last = (end - 1) / manifest.frame_size;
+        let mut plain = Vec::with_capacity((end - first * manifest.frame_size) as usize);
+        for n in first..last + 1 {
+            let frame_len = manifest.frame_size.min(manifest.length - n * manifest.frame_size);
+            let frame = self.inner.get(&frame_key(key, &manifest.generation, n))?;
+            plain.extend(unpack(&frame, frame_len)
+                .chain_err(|| format!("reading frame {} of {}", n, key))?);
+        }
+        let skip = (offset - first * manifest.frame_size) as usize;
+        plain.truncate((end - first * manifest.frame_size) as usize);
+        Ok(plain.split_off(skip))
+    }
+
+    /// Upload ids carry the part size, length and generation, all a part or
+    /// the manifest needs, so that an upload can be resumed by another
+    /// process. Those of uploads started before generations have none.
+    fn parse_upload_id(&self, key: &str, upload_id: &str) -> Result<(u64, u64, String)> {
+        let mut fields = upload_id.splitn(3, ':');
+        match (fields.next().map(str::parse), fields.next().map(str::parse), fields.next()) {
+            (Some(Ok(part_size)), Some(Ok(length)), generation) => {
+                Ok((part_size, length, generation.unwrap_or("").to_string()))
+            }
+            _ => bail!("invalid upload id for {}: {:?}", key, upload_id),
+        }
+    }
+}
+
+impl ObjectStore for CompressedStore {
+    fn get(&self, key: &str) -> Result<Vec<u8>> {
+        self.get_range(key, 0, u64::MAX)
+    }
+
+    fn get_range(&self, key: &str, offset: u64, len: u64) -> Result<Vec<u8>> {
+        let cached = self.manifests.lock().unwrap().contains_key(key);
+        match self.read_range(key, offset, len) {
+            Ok(data) => Ok(data),
+            Err(e) => {
+                // the object may have been replaced since its manifest was read
+                if cached {
+                    self.forget_manifest(key);
+                    return self.read_range(key, offset, len);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    fn put(&self, key: &str, data: &[u8]) -> Result<()> {
+        let generation = new_generation();
+        self.put_frames(key, &generation, 0, data)?;
+        self.put_manifest(key, data.len() as u64, &generation)?;
+        self.drop_frames(key, |g| g != generation)
+    }
+
+    /// Sizes are of the content before compression.
+    fn head(&self, key: &str) -> Result<Option<ObjectInfo>> {
+        let info = match self.inner.head(key)? {
+            Some(info) => info,
+            None => return Ok(None),
+        };
+        Ok(self.manifest(key)?.map(|m| ObjectInfo { size: m.length, ..info }))
+    }
+
+    /// Frames are not listed; sizes are of the content before compression.
+    fn list(&self, prefix: &str) -> Result<Vec<ObjectInfo>> {
+        let mut found = Vec::new();
+        for info in self.inner.list(prefix)?.into_iter().filter(|i| !is_frame(&i.key)) {
+            if let Some(manifest) = self.manifest(&info.key)? {
+                found.push(ObjectInfo { size: manifest.length, ..info });
+            }
+        }
+        Ok(found)
+    }
+
+    fn delete(&self, key: &str) -> Result<()> {
+        self.forget_manifest(key);
+        self.inner.delete(key)?;
+        self.drop_frames(key, |_| true)
+    }
+
+    fn start_multipart(&self, key: &str, length: u64, part_size: u64) -> Result<String> {
+        if !part_size.is_multiple_of(self.frame_size) {
+            bail!("part size for {} must be a multiple of {} to compress", key, self.frame_size);
+        }
+        // frames are objects of their own, so parts need no upload to join them
+        Ok(format!("{}:{}:{}", part_size, length, new_generation()))
+    }
+
+    fn put_part(&self, key: &str, upload_id: &str, number: u32, data: &[u8]) -> Result<String> {
+        let (part_size, _, generation) = self.parse_upload_id(key, upload_id)?;
+        let first = u64::from(number - 1) * part_size / self.frame_size;
+        self.put_frames(key, &generation, first, data)?;
+        Ok(format!("{}-{}", number, data.len()))
+    }
+
+    fn finish_multipart(&self, key: &str, upload_id: &str, _parts: &[(u32, String)]) -> Result<()> {
+        let (_, length, generation) = self.parse_upload_id(key, upload_id)?;
+        self.put_manifest(key, length, &generation)?;
+        self.drop_frames(key, |g| g != generation)
+    }
+
+    fn abort_multipart(&self, key: &str, upload_id: &str) -> Result<()> {
+        let (_, _, generation) = self.parse_upload_id(key, upload_id)?;
+        if self.manifest(key)?.is_some_and(|m| m.generation == generation) {
+            return Ok(());
+        }
+        self.drop_frames(key, |g| g == generation)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::LocalStore;
+
+    use tempfile;
+
+    const FRAME: u64 = 1024;
+
+    fn content(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i / 100 % 251) as u8).collect()
+    }
+
+    fn compressed(dir: &::std::path::Path) -> (CompressedStore, Arc<LocalStore>) {
+        let inner = Arc::new(LocalStore::new(dir).unwrap());
+        (CompressedStore::new(inner.clone(), Codec::Zstd, 3, FRAME), inner)
+    }
+
+    fn generations(inner: &LocalStore, key: &str) -> Vec<String> {
+        let prefix = frames_prefix(key);
+        let mut found: Vec<String> = inner.list(&prefix)
+            .unwrap()
+            .into_iter()
+            .map(|i| i.key[prefix.len()..].rsplit_once('/').unwrap().0.to_string())
+            .collect();
+        found.sort();
+        found.dedup();
+        found
+    }
+
+    #[test]
+    fn ranges_are_read_across_frames() {
+        let dir = tempfile::tempdir().unwrap();
+        let (store, _) = compressed(dir.path());
+        let data = content(3 * FRAME as usize + 10);
+        store.put("a", &data).unwrap();
+        assert_eq!(store.head("a").unwrap().unwrap().size, data.len() as u64);
+        assert_eq!(store.get_range("a", FRAME - 5, FRAME + 10).unwrap(),
+                   &data[FRAME as usize - 5..2 * FRAME as usize + 5]);
+        assert_eq!(store.get("a").unwrap(), data);
+    }
+
+    #[test]
+    fn put_replaces_the_generation_of_frames() {
+        let dir = tempfile::tempdir().unwrap();
+        let (store, inner) = compressed(dir.path());
+        store.put("a", &content(3 * FRAME as usize)).unwrap();
+        let first = generations(&inner, "a");
+        assert_eq!(first.len(), 1);
+
+        let data = content(FRAME as usize);
+        store.put("a", &data).unwrap();
+        let second = generations(&inner, "a");
+        assert_eq!(second.len(), 1);
+        assert!(first != second);
+        assert_eq!(inner.list(&frames_prefix("a")).unwrap().len(), 1);
+        assert_eq!(store.get("a").unwrap(), data);
+
+        store.delete("a").unwrap();
+        assert!(inner.list(&frames_prefix("a")).unwrap().is_empty());
+    }
+
+    #[test]
+    fn aborted_upload_leaves_the_current_content() {
+        let dir = tempfile::tempdir().unwrap();
+        let (store, inner) = compressed(dir.path());
+        let data = content(2 * FRAME as usize);
+        store.put("a", &data).unwrap();
+
+        let upload_id = store.start_multipart("a", 4 * FRAME, 2 * FRAME).unwrap();
+        store.put_part("a", &upload_id, 1, &content(2 * FRAME as usize)).unwrap();
+        assert_eq!(generations(&inner, "a").len(), 2);
+        store.abort_multipart("a", &upload_id).unwrap();
+        assert_eq!(generations(&inner, "a").len(), 1);
+        assert_eq!(store.get("a").unwrap(), data);
+    }
+}
@@ -0,0 +1,536 @@
+use errors::*;
+
+use super::{ObjectInfo, ObjectStore};
+
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::rand::rand_bytes;
+use openssl::sign::Signer;
+use openssl::symm::{decrypt_aead, encrypt_aead, Cipher};
+
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::sync::{Arc, Mutex};
+
+// An encrypted object is a header followed by the content in chunks sealed
+// one by one with AES-256-GCM, so that a range can be fetched and checked
+// without the rest of the object:
+//
+//   magic, version, plaintext length (u64 BE)
+//   nonce, data key sealed with the master key (authenticating the above), tag
+//   chunk 0 ciphertext, tag
+//   chunk 1 ciphertext, tag
+//   ...
+//
+// Every object has a data key of its own, so the chunk number serves as the
+// chunk's nonce, and is authenticated with it so chunks cannot be reordered.
+// Object keys carry inode numbers only, never names or paths, so they are
+// stored as they are. Chunks, which are named by content, are named with
+// HMAC-SHA-256 under a key derived from the master key, so that equal names
+// tell a reader of the bucket no more than that the chunks are equal.
+
+const MAGIC: &[u8; 8] = b"S3HFSENC";
+const VERSION: u8 = 1;
+/// Plaintext bytes per chunk; every chunk but the last is full.
+const CHUNK: u64 = 64 * 1024;
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+/// magic, version and length, authenticated along with the data key
+const PREFIX_LEN: usize = 8 + 1 + 8;
+const HEADER_LEN: u64 = (PREFIX_LEN + NONCE_LEN + KEY_LEN + TAG_LEN) as u64;
+const SEALED_CHUNK: u64 = CHUNK + TAG_LEN as u64;
+
+type Key = [u8; KEY_LEN];
+
+/// Derives the key chunks are named with from the master key.
+const NAMING_LABEL: &[u8] = b"s3hfs chunk names";
+
+/// Headers of objects kept opened at most, the least recently used going first.
+const HEADERS: usize = 1024;
+
+/// Plaintext length and data key of objects read recently, by key, with
+/// when each was last used.
+struct Headers {
+    opened: HashMap<String, ((u64, Key), u64)>,
+    clock: u64,
+}
+
+impl Headers {
+    fn get(&mut self, key: &str) -> Option<(u64, Key)> {
+        self.clock += 1;
+        let clock = self.clock;
+        self.opened.get_mut(key).map(|entry| {
+            entry.1 = clock;
+            entry.0
+        })
+    }
+
+    fn contains(&self, key: &str) -> bool {
+        self.opened.contains_key(key)
+    }
+
+    fn insert(&mut self, key: &str, header: (u64, Key)) {
+        if self.opened.len() >= HEADERS && !self.opened.contains_key(key) {
+            let oldest = self.opened.iter().min_by_key(|e| (e.1).1).map(|e| e.0.clone());
+            if let Some(oldest) = oldest {
+                self.opened.remove(&oldest);
+            }
+        }
+        self.clock += 1;
+        self.opened.insert(key.to_string(), (header, self.clock));
+    }
+
+    fn remove(&mut self, key: &str) {
+        self.opened.remove(key);
+    }
+}
+
+/// The master key: the first 32 bytes of `file` if given, raw or as 64 hex
+/// digits, otherwise the hex in `S3HFS_KEY`. `None` if neither is set.
+pub fn load_key(file: Option<&str>) -> Result<Option<Key>> {
+    let bytes = match file {
+        Some(path) => fs::read(path).chain_err(|| format!("reading key file {}", path))?,
+        None => {
+            match env::var("S3HFS_KEY") {
+                Ok(hex) => hex.into_bytes(),
+                Err(_) => return Ok(None),
+            }
+        }
+    };
+    let mut key = [0u8; KEY_LEN];
+    if bytes.len() == KEY_LEN {
+        key.copy_from_slice(&bytes);
+        return Ok(Some(key));
+    }
+    let hex = String::from_utf8_lossy(&bytes);
+    let hex = hex.trim();
+    if hex.len() != KEY_LEN * 2 {
+        bail!("key must be {} bytes or {} hex digits", KEY_LEN, KEY_LEN * 2);
+    }
+    for (i, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)
+            .chain_err(|| "key is not hexadecimal")?;
+    }
+    Ok(Some(key))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(hex: &str) -> Result<Vec<u8>> {
+    if !hex.is_ascii() || hex.len() & 1 != 0 {
+        bail!("invalid hex: {:?}", hex);
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).chain_err(|| "invalid hex"))
+        .collect()
+}
+
+/// Size of the object holding `length` bytes of plaintext.
+fn sealed_len(length: u64) -> u64 {
+    HEADER_LEN + length + length.div_ceil(CHUNK) * TAG_LEN as u64
+}
+
+/// Bytes of plaintext in an object of `size` bytes.
+fn plain_len(size: u64) -> u64 {
+    let body = size.saturating_sub(HEADER_LEN);
+    let rest = body % SEALED_CHUNK;
+    body / SEALED_CHUNK * CHUNK + rest.saturating_sub(TAG_LEN as u64)
+}
+
+fn chunk_nonce(index: u64) -> [u8; NONCE_LEN] {
+    let mut nonce = [0u8; NONCE_LEN];
+    nonce[4..].copy_from_slice(&index.to_be_bytes());
+    nonce
+}
+
+fn seal_chunk(data_key: &Key, index: u64, plain: &[u8]) -> Result<Vec<u8>> {
+    let mut tag = [0u8; TAG_LEN];
+    let nonce = chunk_nonce(index);
+    let mut sealed = encrypt_aead(Cipher::aes_256_gcm(),
+                                  data_key,
+                                  Some(&nonce),
+                                  &index.to_be_bytes(),
+                                  plain,
+                                  &mut tag)
+        .chain_err(|| "encrypting chunk")?;
+    sealed.extend_from_slice(&tag);
+    Ok(sealed)
+}
+
+fn open_chunk(data_key: &Key, index: u64, sealed: &[u8]) -> Result<Vec<u8>> {
+    if sealed.len() < TAG_LEN {
+        bail!("chunk {} cut short", index);
+    }
+    let (body, tag) = sealed.split_at(sealed.len() - TAG_LEN);
+    let nonce = chunk_nonce(index);
+    decrypt_aead(Cipher::aes_256_gcm(),
+                 data_key,
+                 Some(&nonce),
+                 &index.to_be_bytes(),
+                 body,
+                 tag)
+        .chain_err(|| format!("chunk {} failed authentication", index))
+}
+
+/// Seal `plain`, which starts at chunk `first`, into whole chunks.
+fn seal_chunks(data_key: &Key, first: u64, plain: &[u8]) -> Result<Vec<u8>> {
+    let mut sealed = Vec::with_capacity(plain.len() + plain.len() / CHUNK as usize * TAG_LEN +
+                                        TAG_LEN);
+    for (i, chunk) in plain.chunks(CHUNK as usize).enumerate() {
+        sealed.extend(seal_chunk(data_key, first + i as u64, chunk)?);
+    }
+    Ok(sealed)
+}
+
+/// HMAC-SHA-256 of `data` under `key`.
+pub fn keyed_hash(key: &Key, data: &[u8]) -> Result<Key> {
+    let pkey = PKey::hmac(key).chain_err(|| "loading HMAC key")?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &pkey)
+        .chain_err(|| "starting HMAC")?;
+    signer.update(data).chain_err(|| "computing HMAC")?;
+    let mac = signer.sign_to_vec().chain_err(|| "computing HMAC")?;
+    let mut hash = [0u8; KEY_LEN];
+    hash.copy_from_slice(&mac);
+    Ok(hash)
+}
+
+/// Wraps another store so that everything it holds is encrypted under
+/// per-object data keys, themselves sealed with a master key. Ranges are
+/// fetched a chunk at a time and checked on the way.
+pub struct EncryptedStore {
+    inner: Arc<dyn ObjectStore>,
+    master: Key,
+    /// names chunks, derived from `master`
+    naming: Key,
+    headers: Mutex<Headers>,
+}
+
+impl EncryptedStore {
+    pub fn new(inner: Arc<dyn ObjectStore>, master: Key) -> Result<EncryptedStore> {
+        Ok(EncryptedStore {
+            inner,
+            master,
+            naming: keyed_hash(&master, NAMING_LABEL)?,
+            headers: Mutex::new(Headers {
+                opened: HashMap::new(),
+                clock: 0,
+            }),
+        })
+    }
+
+    /// A header for `length` bytes of plaintext under a fresh data key.
+    fn seal_header(&self, length: u64) -> Result<(Vec<u8>, Key)> {
+        let mut data_key = [0u8; KEY_LEN];
+        let mut nonce = [0u8; NONCE_LEN];
+        rand_bytes(&mut data_key).chain_err(|| "generating data key")?;
+        rand_bytes(&mut nonce).chain_err(|| "generating nonce")?;
+
+        let mut header = Vec::with_capacity(HEADER_LEN as usize);
+        header.extend_from_slice(MAGIC);
+        header.push(VERSION);
+        header.extend_from_slice(&length.to_be_bytes());
+        let mut tag = [0u8; TAG_LEN];
+        let wrapped = encrypt_aead(Cipher::aes_256_gcm(),
+                                   &self.master,
+                                   Some(&nonce),
+                                   &header[..PREFIX_LEN],
+                                   &data_key,
+                                   &mut tag)
+            .chain_err(|| "sealing data key")?;
+        header.extend_from_slice(&nonce);
+        header.extend_from_slice(&wrapped);
+        header.extend_from_slice(&tag);
+        Ok((header, data_key))
+    }
+
+    fn open_header(&self, key: &str, header: &[u8]) -> Result<(u64, Key)> {
+        if header.len() < HEADER_LEN as usize || &header[..8] != MAGIC {
+            bail!("{} is not an encrypted object", key);
+        }
+        if header[8] != VERSION {
+            bail!("{} is encrypted with unknown version {}", key, header[8]);
+        }
+        let mut length = [0u8; 8];
+        length.copy_from_slice(&header[9..PREFIX_LEN]);
+        let nonce = &header[PREFIX_LEN..PREFIX_LEN + NONCE_LEN];
+        let wrapped = &header[PREFIX_LEN + NONCE_LEN..PREFIX_LEN + NONCE_LEN + KEY_LEN];
+        let tag = &header[PREFIX_LEN + NONCE_LEN + KEY_LEN..HEADER_LEN as usize];
+        let plain = decrypt_aead(Cipher::aes_256_gcm(),
+                                 &self.master,
+                                 Some(nonce),
+                                 &header[..PREFIX_LEN],
+                                 wrapped,
+                                 tag)
+            .chain_err(|| format!("data key of {} failed authentication: wrong key?", key))?;
+        let mut data_key = [0u8; KEY_LEN];
+        data_key.copy_from_slice(&plain);
+        Ok((u64::from_be_bytes(length), data_key))
+    }
+
+    fn header(&self, key: &str) -> Result<(u64, Key)> {
+        if let Some(header) = self.headers.lock().unwrap().get(key) {
+            return Ok(header);
+        }
+        let header = self.inner.get_range(key, 0, HEADER_LEN)?;
+        let opened = self.open_header(key, &header)?;
+        self.headers.lock().unwrap().insert(key, opened);
+        Ok(opened)
+    }
+
+    fn forget_header(&self, key: &str) {
+        self.headers.lock().unwrap().remove(key);
+    }
+
+    fn read_range(&self, key: &str, offset: u64, len: u64) -> Result<Vec<u8>> {
+        let (length, data_key) = self.header(key)?;
+        let end = length.min(offset.saturating_add(len));
+        if offset >= end {
+            return Ok(Vec::new());
+        }
+        let first = offset / CHUNK;
+        let last = (end - 1) / CHUNK;
+        let sealed = self.inner
+            .get_range(key, HEADER_LEN + first * SEALED_CHUNK, (last - first + 1) * SEALED_CHUNK)?;
+        let mut plain = Vec::with_capacity((end - first * CHUNK) as usize);
+        for (i, chunk) in sealed.chunks(SEALED_CHUNK as usize).enumerate() {
+            plain.extend(open_chunk(&data_key, first + i as u64, chunk)?);
+        }
+        if (plain.len() as u64) < end - first * CHUNK {
+            bail!("{} is shorter than its header says", key);
+        }
+        let skip = (offset - first * CHUNK) as usize;
+        plain.truncate((end - first * CHUNK) as usize);
+        Ok(plain.split_off(skip))
+    }
+
+    /// Upload ids carry the part size and sealed header the parts need,
+    /// so that another process can finish or abort an upload.
+    fn parse_upload_id<'a>(&self,
+                           key: &str,
+                           upload_id: &'a str)
+                           -> Result<(u64, Vec<u8>, Key, &'a str)> {
+        let mut fields = upload_id.splitn(3, ':');
+        let (part_size, header, inner_id) = match (fields.next(), fields.next(), fields.next()) {
+            (Some(p), Some(h), Some(i)) => (p, h, i),
+            _ => bail!("invalid upload id for {}: {:?}", key, upload_id),
+        };
+        let part_size = part_size.parse().chain_err(|| "invalid part size in upload id")?;
+        let header = from_hex(header)?;
+        let (_, data_key) = self.open_header(key, &header)?;
+        Ok((part_size, header, data_key, inner_id))
+    }
+}
+
+impl ObjectStore for EncryptedStore {
+    fn get(&self, key: &str) -> Result<Vec<u8>> {
+        let sealed = self.inner.get(key)?;
+        if (sealed.len() as u64) < HEADER_LEN {
+            bail!("{} is not an encrypted object", key);
+        }
+        let (length, data_key) = self.open_header(key, &sealed[..HEADER_LEN as usize])?;
+        let mut plain = Vec::with_capacity(length as usize);
+        for (i, chunk) in sealed[HEADER_LEN as usize..].chunks(SEALED_CHUNK as usize).enumerate() {
+            plain.extend(open_chunk(&data_key, i as u64, chunk)?);
+        }
+        if plain.len() as u64 != length {
+            bail!("{} holds {} bytes, its header says {}", key, plain.len(), length);
+        }
+        Ok(plain)
+    }
+
+    fn get_range(&self, key: &str, offset: u64, len: u64) -> Result<Vec<u8>> {
+        let cached = self.headers.lock().unwrap().contains(key);
+        match self.read_range(key, offset, len) {
+            Ok(data) => Ok(data),
+            Err(e) => {
+                // the object may have been replaced since its header was read
+                if cached {
+                    self.forget_header(key);
+                    return self.read_range(key, offset, len);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    fn put(&self, key: &str, data: &[u8]) -> Result<()> {
+        let (mut sealed, data_key) = self.seal_header(data.len() as u64)?;
+        sealed.extend(seal_chunks(&data_key, 0, data)?);
+        self.forget_header(key);
+        self.inner.put(key, &sealed)
+    }
+
+    fn head(&self, key: &str) -> Result<Option<ObjectInfo>> {
+        Ok(self.inner.head(key)?.map(|mut info| {
+            info.size = plain_len(info.size);
+            info
+        }))
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<ObjectInfo>> {
+        let mut found = self.inner.list(prefix)?;
+        for info in &mut found {
+            info.size = plain_len(info.size);
+        }
+        Ok(found)
+    }
+
+    fn delete(&self, key: &str) -> Result<()> {
+        self.forget_header(key);
+        self.inner.delete(key)
+    }
+
+    fn start_multipart(&self, key: &str, length: u64, part_size: u64) -> Result<String> {
+        if part_size & (CHUNK - 1) != 0 {
+            bail!("part size must be a multiple of {} to encrypt", CHUNK);
+        }
+        let (header, _) = self.seal_header(length)?;
+        let sealed_part = part_size / CHUNK * SEALED_CHUNK;
+        let inner_id = self.inner.start_multipart(key, sealed_len(length), sealed_part)?;
+        Ok(format!("{}:{}:{}", part_size, to_hex(&header), inner_id))
+    }
+
+    fn put_part(&self, key: &str, upload_id: &str, number: u32, data: &[u8]) -> Result<String> {
+        let (part_size, header, data_key, inner_id) = self.parse_upload_id(key, upload_id)?;
+        let first = u64::from(number - 1) * part_size / CHUNK;
+        let mut sealed = if number == 1 { header } else { Vec::new() };
+        sealed.extend(seal_chunks(&data_key, first, data)?);
+        self.inner.put_part(key, inner_id, number, &sealed)
+    }
+
+    fn finish_multipart(&self, key: &str, upload_id: &str, parts: &[(u32, String)]) -> Result<()> {
+        let (_, _, _, inner_id) = self.parse_upload_id(key, upload_id)?;
+        self.forget_header(key);
+        self.inner.finish_multipart(key, inner_id, parts)
+    }
+
+    fn abort_multipart(&self, key: &str, upload_id: &str) -> Result<()> {
+        let (_, _, _, inner_id) = self.parse_upload_id(key, upload_id)?;
+        self.inner.abort_multipart(key, inner_id)
+    }
+
+    /// Parts are sealed under the data key and chunk nonces fixed when the
+    /// upload started: a part sent again with other content would reuse a
+    /// nonce, so every attempt starts over under a fresh data key.
+    fn resumable(&self) -> bool {
+        false
+    }
+
+    fn naming_key(&self) -> Option<Key> {
+        Some(self.naming)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::LocalStore;
+
+    use tempfile;
+
+    fn content(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 13 % 251) as u8).collect()
+    }
+
+    fn encrypted(dir: &::std::path::Path, master: Key) -> (EncryptedStore, Arc<LocalStore>) {
+        let inner = Arc::new(LocalStore::new(dir).unwrap());
+        (EncryptedStore::new(inner.clone(), master).unwrap(), inner)
+    }
+
+    #[test]
+    fn objects_are_read_back_whole_and_in_ranges() {
+        let dir = tempfile::tempdir().unwrap();
+        let (store, inner) = encrypted(dir.path(), [7; KEY_LEN]);
+        let data = content(2 * CHUNK as usize + 100);
+        store.put("a", &data).unwrap();
+
+        let sealed = inner.get("a").unwrap();
+        assert_eq!(sealed.len() as u64, sealed_len(data.len() as u64));
+        assert!(!sealed.windows(64).any(|w| w == &data[CHUNK as usize..CHUNK as usize + 64]));
+        assert_eq!(store.head("a").unwrap().unwrap().size, data.len() as u64);
+        assert_eq!(store.get("a").unwrap(), data);
+        assert_eq!(store.get_range("a", CHUNK - 10, 20).unwrap(),
+                   &data[CHUNK as usize - 10..CHUNK as usize + 10]);
+        assert_eq!(store.get_range("a", 2 * CHUNK, CHUNK).unwrap(),
+                   &data[2 * CHUNK as usize..]);
+    }
+
+    #[test]
+    fn multipart_upload_is_read_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let (store, _) = encrypted(dir.path(), [7; KEY_LEN]);
+        let data = content(2 * CHUNK as usize + 100);
+        let upload_id = store.start_multipart("a", data.len() as u64, CHUNK).unwrap();
+        let mut tags = Vec::new();
+        for (i, part) in data.chunks(CHUNK as usize).enumerate() {
+            let number = i as u32 + 1;
+            tags.push((number, store.put_part("a", &upload_id, number, part).unwrap()));
+        }
+        store.finish_multipart("a", &upload_id, &tags).unwrap();
+
+        assert_eq!(store.get("a").unwrap(), data);
+        assert_eq!(store.get_range("a", CHUNK + 50, 100).unwrap(),
+                   &data[CHUNK as usize + 50..CHUNK as usize + 150]);
+    }
+
+    #[test]
+    fn tampered_chunk_fails_authentication() {
+        let dir = tempfile::tempdir().unwrap();
+        let (store, inner) = encrypted(dir.path(), [7; KEY_LEN]);
+        let data = content(2 * CHUNK as usize);
+        store.put("a", &data).unwrap();
+
+        let mut sealed = inner.get("a").unwrap();
+        sealed[(HEADER_LEN + SEALED_CHUNK + 5) as usize] ^= 1;
+        inner.put("a", &sealed).unwrap();
+        assert!(store.get("a").is_err());
+        assert!(store.get_range("a", CHUNK, 10).is_err());
+        assert_eq!(store.get_range("a", 0, 10).unwrap(), &data[..10]);
+    }
+
+    #[test]
+    fn chunks_cannot_be_reordered() {
+        let dir = tempfile::tempdir().unwrap();
+        let (store, inner) = encrypted(dir.path(), [7; KEY_LEN]);
+        store.put("a", &content(2 * CHUNK as usize)).unwrap();
+
+        let sealed = inner.get("a").unwrap();
+        let (header, chunks) = sealed.split_at(HEADER_LEN as usize);
+        let (first, second) = chunks.split_at(SEALED_CHUNK as usize);
+        let swapped = [header, second, first].concat();
+        inner.put("a", &swapped).unwrap();
+        assert!(store.get("a").is_err());
+    }
+
+    #[test]
+    fn other_master_key_cannot_read() {
+        let dir = tempfile::tempdir().unwrap();
+        let (store, inner) = encrypted(dir.path(), [7; KEY_LEN]);
+        store.put("a", &content(100)).unwrap();
+
+        let other = EncryptedStore::new(inner, [8; KEY_LEN]).unwrap();
+        assert!(other.get("a").is_err());
+        assert!(other.get_range("a", 0, 10).is_err());
+    }
+
+    #[test]
+    fn naming_key_is_derived_from_the_master_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let (store, inner) = encrypted(dir.path(), [7; KEY_LEN]);
+        let other = EncryptedStore::new(inner.clone(), [8; KEY_LEN]).unwrap();
+        let naming = store.naming_key().unwrap();
+        assert!(naming != [7; KEY_LEN]);
+        assert!(naming != other.naming_key().unwrap());
+        assert_eq!(naming, keyed_hash(&[7; KEY_LEN], NAMING_LABEL).unwrap());
+        assert!(inner.naming_key().is_none());
+
+        assert_eq!(keyed_hash(&naming, b"chunk").unwrap(),
+                   keyed_hash(&naming, b"chunk").unwrap());
+        assert!(keyed_hash(&naming, b"chunk").unwrap() != keyed_hash(&naming, b"other").unwrap());
+    }
+}
@@ -0,0 +1,212 @@
+use errors::*;
+
+use super::{ObjectInfo, ObjectStore};
+
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Where parts of unfinished uploads wait, hidden from listings like other
+/// dot files.
+const MULTIPART_DIR: &str = ".multipart";
+
+/// Numbers the files objects are written to before being renamed into place.
+static NEXT_TEMP: AtomicUsize = AtomicUsize::new(0);
+
+/// A dot file in `dir` to write `name` to aside, which no other put, in this
+/// process or another, writes to at the same time.
+fn temp_path(dir: &Path, name: &str) -> PathBuf {
+    let n = NEXT_TEMP.fetch_add(1, Ordering::Relaxed);
+    dir.join(format!(".{}.{}-{}.part", name, process::id(), n))
+}
+
+/// Write `data` to `tmp` and rename it to `path`, removing `tmp` if that fails.
+fn write_into_place(tmp: &Path, path: &Path, data: &[u8]) -> io::Result<()> {
+    let result = File::create(tmp)
+        .and_then(|mut f| f.write_all(data).and_then(|_| f.sync_all()))
+        .and_then(|_| fs::rename(tmp, path));
+    if result.is_err() {
+        let _ = fs::remove_file(tmp);
+    }
+    result
+}
+
+/// Object store kept in a local directory, one file per key. Behaves like a
+/// bucket for the purposes of tiering, so the whole cold path can be driven
+/// without a network.
+pub struct LocalStore {
+    root: PathBuf,
+}
+
+impl LocalStore {
+    pub fn new<P: AsRef<Path>>(root: P) -> Result<LocalStore> {
+        let root = root.as_ref().to_path_buf();
+        fs::create_dir_all(&root).chain_err(|| format!("creating store at {:?}", root))?;
+        Ok(LocalStore { root })
+    }
+
+    fn object_path(&self, key: &str) -> Result<PathBuf> {
+        if key.is_empty() || key.split('/').any(|c| c.is_empty() || c == "." || c == "..") {
+            bail!("invalid object key: {:?}", key);
+        }
+        Ok(self.root.join(key))
+    }
+
+    fn upload_dir(&self, upload_id: &str) -> Result<PathBuf> {
+        if upload_id.is_empty() || !upload_id.chars().all(|c| c.is_ascii_hexdigit()) {
+            bail!("invalid upload id: {:?}", upload_id);
+        }
+        Ok(self.root.join(MULTIPART_DIR).join(upload_id))
+    }
+
+    fn walk(&self, dir: &Path, prefix: &str, found: &mut Vec<ObjectInfo>) -> Result<()> {
+        let rd = match fs::read_dir(dir) {
+            Ok(rd) => rd,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e).chain_err(|| format!("listing {:?}", dir)),
+        };
+        for entry in rd {
+            let entry = entry.chain_err(|| format!("listing {:?}", dir))?;
+            if entry.file_name().to_string_lossy().starts_with('.') {
+                // in-flight puts
+                continue;
+            }
+            let path = entry.path();
+            let key = match path.strip_prefix(&self.root).ok().and_then(|p| p.to_str()) {
+                Some(k) => k.to_string(),
+                None => continue,
+            };
+            let metadata = entry.metadata().chain_err(|| format!("stat {:?}", path))?;
+            if metadata.is_dir() {
+                // only descend where the prefix could still match
+                let as_dir = format!("{}/", key);
+                if as_dir.starts_with(prefix) || prefix.starts_with(&as_dir) {
+                    self.walk(&path, prefix, found)?;
+                }
+            } else if key.starts_with(prefix) {
+                found.push(ObjectInfo {
+                    key,
+                    size: metadata.len(),
+                    modified: metadata.modified().ok(),
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+impl ObjectStore for LocalStore {
+    fn get(&self, key: &str) -> Result<Vec<u8>> {
+        let path = self.object_path(key)?;
+        let mut buffer = Vec::new();
+        File::open(&path)
+            .and_then(|mut f| f.read_to_end(&mut buffer))
+            .chain_err(|| format!("get {}", key))?;
+        Ok(buffer)
+    }
+
+    fn get_range(&self, key: &str, offset: u64, len: u64) -> Result<Vec<u8>> {
+        let path = self.object_path(key)?;
+        let mut f = File::open(&path).chain_err(|| format!("get {}", key))?;
+        f.seek(SeekFrom::Start(offset)).chain_err(|| format!("get {} at {}", key, offset))?;
+        let mut buffer = Vec::new();
+        f.take(len).read_to_end(&mut buffer).chain_err(|| format!("get {} at {}", key, offset))?;
+        Ok(buffer)
+    }
+
+    fn put(&self, key: &str, data: &[u8]) -> Result<()> {
+        let path = self.object_path(key)?;
+        let dir = path.parent().unwrap_or(&self.root).to_path_buf();
+        fs::create_dir_all(&dir).chain_err(|| format!("put {}", key))?;
+
+        // write aside and rename so readers never see a partial object
+        let name = path.file_name().unwrap().to_string_lossy().into_owned();
+        let tmp = temp_path(&dir, &name);
+        write_into_place(&tmp, &path, data).chain_err(|| format!("put {}", key))
+    }
+
+    fn head(&self, key: &str) -> Result<Option<ObjectInfo>> {
+        let path = self.object_path(key)?;
+        match fs::metadata(&path) {
+            Ok(ref m) if m.is_file() => {
+                Ok(Some(ObjectInfo {
+                    key: key.to_string(),
+                    size: m.len(),
+                    modified: m.modified().ok(),
+                }))
+            }
+            Ok(_) => Ok(None),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).chain_err(|| format!("head {}", key)),
+        }
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<ObjectInfo>> {
+        let mut found = Vec::new();
+        self.walk(&self.root, prefix, &mut found)?;
+        found.sort_by(|a, b| a.key.cmp(&b.key));
+        Ok(found)
+    }
+
+    fn delete(&self, key: &str) -> Result<()> {
+        let path = self.object_path(key)?;
+        match fs::remove_file(&path) {
+            Ok(()) => Ok(()),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e).chain_err(|| format!("delete {}", key)),
+        }
+    }
+
+    fn start_multipart(&self, key: &str, _length: u64, _part_size: u64) -> Result<String> {
+        use rand::Rng;
+
+        self.object_path(key)?;
+        let upload_id = format!("{:016x}", ::rand::thread_rng().gen::<u64>());
+        let dir = self.upload_dir(&upload_id)?;
+        fs::create_dir_all(&dir).chain_err(|| format!("starting upload to {}", key))?;
+        Ok(upload_id)
+    }
+
+    fn put_part(&self, key: &str, upload_id: &str, number: u32, data: &[u8]) -> Result<String> {
+        let dir = self.upload_dir(upload_id)?;
+        let path = dir.join(format!("{:05}", number));
+        let tmp = temp_path(&dir, &format!("{:05}", number));
+        write_into_place(&tmp, &path, data).chain_err(|| format!("put {} part {}", key, number))?;
+        Ok(format!("{}-{}", number, data.len()))
+    }
+
+    fn finish_multipart(&self, key: &str, upload_id: &str, parts: &[(u32, String)]) -> Result<()> {
+        let path = self.object_path(key)?;
+        let dir = self.upload_dir(upload_id)?;
+        let target = path.parent().unwrap_or(&self.root).to_path_buf();
+        fs::create_dir_all(&target).chain_err(|| format!("put {}", key))?;
+
+        let mut parts = parts.to_vec();
+        parts.sort_by_key(|p| p.0);
+        let name = path.file_name().unwrap().to_string_lossy().into_owned();
+        let tmp = temp_path(&target, &name);
+        let mut out = File::create(&tmp).chain_err(|| format!("put {}", key))?;
+        for (number, _) in parts {
+            let mut part = File::open(dir.join(format!("{:05}", number)))
+                .chain_err(|| format!("put {}: part {} missing", key, number))?;
+            io::copy(&mut part, &mut out).chain_err(|| format!("put {}", key))?;
+        }
+        out.sync_all()
+            .and_then(|_| fs::rename(&tmp, &path))
+            .chain_err(|| format!("put {}", key))?;
+        self.abort_multipart(key, upload_id)
+    }
+
+    fn abort_multipart(&self, key: &str, upload_id: &str) -> Result<()> {
+        let dir = self.upload_dir(upload_id)?;
+        match fs::remove_dir_all(&dir) {
+            Ok(()) => Ok(()),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e).chain_err(|| format!("aborting upload to {}", key)),
+        }
+    }
+}
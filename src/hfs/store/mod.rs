@@ -0,0 +1,76 @@
+use errors::*;
+
+use std::sync::Arc;
+use std::time::SystemTime;
+
+mod compress;
+mod crypt;
+mod local;
+mod s3;
+
+pub use self::compress::{Codec, CompressedStore, frame_of, pack, unpack};
+pub use self::crypt::{EncryptedStore, keyed_hash, load_key};
+pub use self::local::LocalStore;
+pub use self::s3::S3Store;
+
+#[derive(Debug, Clone)]
+pub struct ObjectInfo {
+    pub key: String,
+    pub size: u64,
+    pub modified: Option<SystemTime>,
+}
+
+/// The cold tier: a flat namespace of immutable objects addressed by key.
+pub trait ObjectStore: Send + Sync {
+    fn get(&self, key: &str) -> Result<Vec<u8>>;
+    /// Fetch `len` bytes starting at `offset`; short if the object ends first.
+    fn get_range(&self, key: &str, offset: u64, len: u64) -> Result<Vec<u8>>;
+    fn put(&self, key: &str, data: &[u8]) -> Result<()>;
+    /// `None` if there is no object under `key`.
+    fn head(&self, key: &str) -> Result<Option<ObjectInfo>>;
+    fn list(&self, prefix: &str) -> Result<Vec<ObjectInfo>>;
+    /// Deleting a missing key is not an error.
+    fn delete(&self, key: &str) -> Result<()>;
+
+    /// Begin an upload of `length` bytes to `key` in parts of `part_size`,
+    /// the last perhaps shorter, returning its id. Nothing appears under
+    /// `key` until the upload is finished.
+    fn start_multipart(&self, key: &str, length: u64, part_size: u64) -> Result<String>;
+    /// Upload part `number`, counting from 1, returning the tag that
+    /// identifies it when finishing. Parts may be sent in any order and again.
+    fn put_part(&self, key: &str, upload_id: &str, number: u32, data: &[u8]) -> Result<String>;
+    /// Join the parts, in order of number, into the object under `key`.
+    fn finish_multipart(&self, key: &str, upload_id: &str, parts: &[(u32, String)]) -> Result<()>;
+    /// Drop the parts of an unfinished upload.
+    fn abort_multipart(&self, key: &str, upload_id: &str) -> Result<()>;
+
+    /// Whether an interrupted upload may be carried on, sending the parts
+    /// not yet acknowledged again with whatever they hold by then.
+    fn resumable(&self) -> bool {
+        true
+    }
+
+    /// Key to name objects held by content under, with `keyed_hash`, so that
+    /// the names give nothing away about the content; `None` if the store
+    /// keeps nothing secret and plain hashes do.
+    fn naming_key(&self) -> Option<[u8; 32]> {
+        None
+    }
+}
+
+/// Open the store named by `url`: `file:///some/dir` or `s3://bucket/prefix`.
+pub fn open(url: &str) -> Result<Arc<dyn ObjectStore>> {
+    if let Some(path) = url.strip_prefix("file://") {
+        return Ok(Arc::new(LocalStore::new(path)?));
+    }
+    if let Some(rest) = url.strip_prefix("s3://") {
+        let mut parts = rest.splitn(2, '/');
+        let bucket = parts.next().unwrap_or("");
+        let prefix = parts.next().unwrap_or("");
+        if bucket.is_empty() {
+            bail!("no bucket in store url: {}", url);
+        }
+        return Ok(Arc::new(S3Store::new(bucket, prefix)?));
+    }
+    bail!("unsupported store url: {}", url)
+}
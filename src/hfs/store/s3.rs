@@ -0,0 +1,273 @@
+use errors::*;
+
+use super::{ObjectInfo, ObjectStore};
+
+use rusoto_core::{Region, RusotoError};
+use rusoto_s3::{S3, S3Client, GetObjectRequest, PutObjectRequest, HeadObjectRequest,
+                ListObjectsV2Request, DeleteObjectRequest, CreateMultipartUploadRequest,
+                UploadPartRequest, CompleteMultipartUploadRequest, CompletedMultipartUpload,
+                CompletedPart, AbortMultipartUploadRequest};
+
+use std::io::Read;
+use std::time::SystemTime;
+
+/// Objects in an S3 bucket, all keys placed under `prefix`. Credentials and
+/// region come from the usual AWS environment and profile sources.
+pub struct S3Store {
+    client: S3Client,
+    bucket: String,
+    prefix: String,
+}
+
+fn time_from_http_date(s: &str) -> Option<SystemTime> {
+    use time;
+    time::strptime(s, "%a, %d %b %Y %H:%M:%S GMT")
+        .ok()
+        .map(|tm| {
+            let ts = tm.to_timespec();
+            std::time::UNIX_EPOCH + std::time::Duration::new(ts.sec as u64, ts.nsec as u32)
+        })
+}
+
+fn time_from_iso8601(s: &str) -> Option<SystemTime> {
+    use time;
+    time::strptime(s, "%Y-%m-%dT%H:%M:%S")
+        .ok()
+        .map(|tm| {
+            let ts = tm.to_timespec();
+            std::time::UNIX_EPOCH + std::time::Duration::new(ts.sec as u64, 0)
+        })
+}
+
+impl S3Store {
+    pub fn new(bucket: &str, prefix: &str) -> Result<S3Store> {
+        let region = match std::env::var("S3HFS_S3_ENDPOINT") {
+            Ok(endpoint) => {
+                Region::Custom {
+                    name: Region::default().name().to_string(),
+                    endpoint,
+                }
+            }
+            Err(_) => Region::default(),
+        };
+        let mut prefix = prefix.trim_matches('/').to_string();
+        if !prefix.is_empty() {
+            prefix.push('/');
+        }
+        Ok(S3Store {
+            client: S3Client::new(region),
+            bucket: bucket.to_string(),
+            prefix,
+        })
+    }
+
+    fn full_key(&self, key: &str) -> String {
+        format!("{}{}", self.prefix, key)
+    }
+
+    fn fetch(&self, key: &str, range: Option<String>) -> Result<Vec<u8>> {
+        let request = GetObjectRequest {
+            bucket: self.bucket.clone(),
+            key: self.full_key(key),
+            range,
+            ..Default::default()
+        };
+        let output = self.client
+            .get_object(request)
+            .sync()
+            .chain_err(|| format!("get s3://{}/{}", self.bucket, self.full_key(key)))?;
+        let mut buffer = Vec::new();
+        if let Some(body) = output.body {
+            body.into_blocking_read()
+                .read_to_end(&mut buffer)
+                .chain_err(|| format!("reading s3://{}/{}", self.bucket, self.full_key(key)))?;
+        }
+        Ok(buffer)
+    }
+}
+
+impl ObjectStore for S3Store {
+    fn get(&self, key: &str) -> Result<Vec<u8>> {
+        self.fetch(key, None)
+    }
+
+    fn get_range(&self, key: &str, offset: u64, len: u64) -> Result<Vec<u8>> {
+        if len == 0 {
+            return Ok(Vec::new());
+        }
+        match self.fetch(key, Some(format!("bytes={}-{}", offset, offset + len - 1))) {
+            Ok(data) => Ok(data),
+            Err(e) => {
+                // a range starting at or past the end is refused by S3; mirror a short read
+                match self.head(key)? {
+                    Some(ref info) if offset >= info.size => Ok(Vec::new()),
+                    _ => Err(e),
+                }
+            }
+        }
+    }
+
+    fn put(&self, key: &str, data: &[u8]) -> Result<()> {
+        let request = PutObjectRequest {
+            bucket: self.bucket.clone(),
+            key: self.full_key(key),
+            body: Some(data.to_vec().into()),
+            content_length: Some(data.len() as i64),
+            ..Default::default()
+        };
+        self.client
+            .put_object(request)
+            .sync()
+            .map(|_| ())
+            .chain_err(|| format!("put s3://{}/{}", self.bucket, self.full_key(key)))
+    }
+
+    fn head(&self, key: &str) -> Result<Option<ObjectInfo>> {
+        let request = HeadObjectRequest {
+            bucket: self.bucket.clone(),
+            key: self.full_key(key),
+            ..Default::default()
+        };
+        match self.client.head_object(request).sync() {
+            Ok(output) => {
+                Ok(Some(ObjectInfo {
+                    key: key.to_string(),
+                    size: output.content_length.unwrap_or(0) as u64,
+                    modified: output.last_modified.as_ref().and_then(|s| time_from_http_date(s)),
+                }))
+            }
+            // HEAD responses carry no body, so a missing key surfaces as a bare 404
+            Err(RusotoError::Unknown(ref response)) if response.status.as_u16() == 404 => Ok(None),
+            Err(RusotoError::Service(_)) => Ok(None),
+            Err(e) => {
+                Err(e).chain_err(|| format!("head s3://{}/{}", self.bucket, self.full_key(key)))
+            }
+        }
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<ObjectInfo>> {
+        let mut found = Vec::new();
+        let mut token = None;
+        loop {
+            let request = ListObjectsV2Request {
+                bucket: self.bucket.clone(),
+                prefix: Some(self.full_key(prefix)),
+                continuation_token: token.clone(),
+                ..Default::default()
+            };
+            let output = self.client
+                .list_objects_v2(request)
+                .sync()
+                .chain_err(|| format!("list s3://{}/{}", self.bucket, self.full_key(prefix)))?;
+            for object in output.contents.unwrap_or_default() {
+                let key = match object.key {
+                    Some(ref k) if k.starts_with(&self.prefix) => {
+                        k[self.prefix.len()..].to_string()
+                    }
+                    _ => continue,
+                };
+                found.push(ObjectInfo {
+                    key,
+                    size: object.size.unwrap_or(0) as u64,
+                    modified: object.last_modified.as_ref().and_then(|s| time_from_iso8601(s)),
+                });
+            }
+            if output.is_truncated != Some(true) {
+                break;
+            }
+            token = output.next_continuation_token;
+        }
+        Ok(found)
+    }
+
+    fn delete(&self, key: &str) -> Result<()> {
+        let request = DeleteObjectRequest {
+            bucket: self.bucket.clone(),
+            key: self.full_key(key),
+            ..Default::default()
+        };
+        self.client
+            .delete_object(request)
+            .sync()
+            .map(|_| ())
+            .chain_err(|| format!("delete s3://{}/{}", self.bucket, self.full_key(key)))
+    }
+
+    fn start_multipart(&self, key: &str, _length: u64, _part_size: u64) -> Result<String> {
+        let request = CreateMultipartUploadRequest {
+            bucket: self.bucket.clone(),
+            key: self.full_key(key),
+            ..Default::default()
+        };
+        let output = self.client
+            .create_multipart_upload(request)
+            .sync()
+            .chain_err(|| format!("start upload to s3://{}/{}", self.bucket, self.full_key(key)))?;
+        match output.upload_id {
+            Some(id) => Ok(id),
+            None => bail!("no upload id for s3://{}/{}", self.bucket, self.full_key(key)),
+        }
+    }
+
+    fn put_part(&self, key: &str, upload_id: &str, number: u32, data: &[u8]) -> Result<String> {
+        let request = UploadPartRequest {
+            bucket: self.bucket.clone(),
+            key: self.full_key(key),
+            upload_id: upload_id.to_string(),
+            part_number: i64::from(number),
+            body: Some(data.to_vec().into()),
+            content_length: Some(data.len() as i64),
+            ..Default::default()
+        };
+        let output = self.client
+            .upload_part(request)
+            .sync()
+            .chain_err(|| {
+                format!("put s3://{}/{} part {}", self.bucket, self.full_key(key), number)
+            })?;
+        Ok(output.e_tag.unwrap_or_default())
+    }
+
+    fn finish_multipart(&self, key: &str, upload_id: &str, parts: &[(u32, String)]) -> Result<()> {
+        let mut parts: Vec<CompletedPart> = parts.iter()
+            .map(|&(number, ref tag)| {
+                CompletedPart {
+                    e_tag: Some(tag.clone()),
+                    part_number: Some(i64::from(number)),
+                }
+            })
+            .collect();
+        parts.sort_by_key(|p| p.part_number);
+        let request = CompleteMultipartUploadRequest {
+            bucket: self.bucket.clone(),
+            key: self.full_key(key),
+            upload_id: upload_id.to_string(),
+            multipart_upload: Some(CompletedMultipartUpload { parts: Some(parts) }),
+            ..Default::default()
+        };
+        self.client
+            .complete_multipart_upload(request)
+            .sync()
+            .map(|_| ())
+            .chain_err(|| format!("finish upload to s3://{}/{}", self.bucket, self.full_key(key)))
+    }
+
+    fn abort_multipart(&self, key: &str, upload_id: &str) -> Result<()> {
+        let request = AbortMultipartUploadRequest {
+            bucket: self.bucket.clone(),
+            key: self.full_key(key),
+            upload_id: upload_id.to_string(),
+            ..Default::default()
+        };
+        match self.client.abort_multipart_upload(request).sync() {
+            Ok(_) => Ok(()),
+            // already finished or aborted
+            Err(RusotoError::Service(_)) => Ok(()),
+            Err(e) => {
+                Err(e).chain_err(|| {
+                    format!("abort upload to s3://{}/{}", self.bucket, self.full_key(key))
+                })
+            }
+        }
+    }
+}
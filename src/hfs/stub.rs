@@ -0,0 +1,62 @@
+use errors::*;
+
+use super::store::ObjectStore;
+use super::sys;
+use super::transfer;
+
+use time::Timespec;
+
+use std::fs;
+use std::fs::OpenOptions;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+// An evicted file keeps its directory entry, mode, ownership, size and times
+// in the backing directory: its content is replaced by a hole of the same
+// length. Together with the tiering record this is enough to answer lookup,
+// getattr and readdir without asking the object store.
+
+pub fn mtime_of(metadata: &fs::Metadata) -> (i64, i32) {
+    (metadata.mtime(), metadata.mtime_nsec() as i32)
+}
+
+fn restore_times(path: &Path, before: &fs::Metadata) -> Result<()> {
+    sys::set_times(path,
+                   Timespec::new(before.atime(), before.atime_nsec() as i32),
+                   Timespec::new(before.mtime(), before.mtime_nsec() as i32))
+        .chain_err(|| format!("restoring times on {:?}", path))
+}
+
+/// Drop `len` bytes at `offset` of `path`, leaving a hole in the
+/// placeholder, which keeps its size and times.
+pub fn punch(path: &Path, offset: u64, len: u64) -> Result<()> {
+    let before = fs::metadata(path).chain_err(|| format!("stat {:?}", path))?;
+    OpenOptions::new()
+        .write(true)
+        .open(path)
+        .and_then(|f| sys::punch_hole(&f, offset, len))
+        .chain_err(|| format!("punching {:?} at {}", path, offset))?;
+    restore_times(path, &before)
+}
+
+/// Fetch the given `(offset, len)` ranges of the object under `key` back
+/// into the placeholder at `path`, which must still be inode `ino`.
+pub fn fill(store: &dyn ObjectStore,
+            key: &str,
+            ino: u64,
+            path: &Path,
+            ranges: &[(u64, u64)],
+            threads: usize)
+            -> Result<()> {
+    let before = fs::metadata(path).chain_err(|| format!("stat {:?}", path))?;
+    let f = OpenOptions::new()
+        .write(true)
+        .open(path)
+        .chain_err(|| format!("opening {:?}", path))?;
+    if f.metadata().map(|m| m.ino()).ok() != Some(ino) {
+        bail!("{:?} is no longer inode {}", path, ino);
+    }
+    transfer::download(store, key, &f, ranges, threads)?;
+    f.sync_data().chain_err(|| format!("restoring {:?}", path))?;
+    restore_times(path, &before)
+}
@@ -0,0 +1,230 @@
+use std::ffi::CString;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+use libc;
+use time::Timespec;
+
+fn cstring_from(path: &Path) -> io::Result<CString> {
+    CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "path contains nul"))
+}
+
+pub struct FsUsage {
+    pub total_bytes: u64,
+    pub free_bytes: u64,
+    pub available_bytes: u64,
+    pub block_size: u32,
+    pub fragment_size: u32,
+    pub free_inodes: u64,
+    pub name_max: u32,
+}
+
+impl FsUsage {
+    /// Fraction of the filesystem in use, from 0 to 100.
+    pub fn used_percent(&self) -> u64 {
+        if self.total_bytes == 0 {
+            return 0;
+        }
+        100 - self.available_bytes.saturating_mul(100) / self.total_bytes
+    }
+}
+
+pub fn statvfs(path: &Path) -> io::Result<FsUsage> {
+    let cpath = cstring_from(path)?;
+    let mut st: libc::statvfs = unsafe { ::std::mem::zeroed() };
+    if unsafe { libc::statvfs(cpath.as_ptr(), &mut st) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let frsize = st.f_frsize as u64;
+    Ok(FsUsage {
+        total_bytes: st.f_blocks as u64 * frsize,
+        free_bytes: st.f_bfree as u64 * frsize,
+        available_bytes: st.f_bavail as u64 * frsize,
+        block_size: st.f_bsize as u32,
+        fragment_size: st.f_frsize as u32,
+        free_inodes: st.f_ffree as u64,
+        name_max: st.f_namemax as u32,
+    })
+}
+
+/// Set access and modification times without following a final symlink.
+pub fn set_times(path: &Path, atime: Timespec, mtime: Timespec) -> io::Result<()> {
+    let cpath = cstring_from(path)?;
+    let times = [libc::timespec {
+                     tv_sec: atime.sec as libc::time_t,
+                     tv_nsec: atime.nsec as libc::c_long,
+                 },
+                 libc::timespec {
+                     tv_sec: mtime.sec as libc::time_t,
+                     tv_nsec: mtime.nsec as libc::c_long,
+                 }];
+    let rc = unsafe {
+        libc::utimensat(libc::AT_FDCWD,
+                        cpath.as_ptr(),
+                        times.as_ptr(),
+                        libc::AT_SYMLINK_NOFOLLOW)
+    };
+    if rc != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// rename(2) with renameat2 flags such as `RENAME_NOREPLACE` and `RENAME_EXCHANGE`.
+pub fn rename(from: &Path, to: &Path, flags: u32) -> io::Result<()> {
+    if flags == 0 {
+        return ::std::fs::rename(from, to);
+    }
+    let cfrom = cstring_from(from)?;
+    let cto = cstring_from(to)?;
+    let rc = unsafe {
+        libc::renameat2(libc::AT_FDCWD,
+                        cfrom.as_ptr(),
+                        libc::AT_FDCWD,
+                        cto.as_ptr(),
+                        flags as libc::c_uint)
+    };
+    if rc != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// pread until `buf` is full or the file ends, without moving the file's
+/// cursor. Returns the number of bytes read, short only at end of file.
+pub fn read_full_at(f: &::std::fs::File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    use std::os::unix::fs::FileExt;
+
+    let mut done = 0;
+    while done < buf.len() {
+        match f.read_at(&mut buf[done..], offset + done as u64) {
+            Ok(0) => break,
+            Ok(n) => done += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(done)
+}
+
+/// Deallocate `len` bytes at `offset`, which then read as zeros; the file
+/// keeps its length.
+pub fn punch_hole(f: &::std::fs::File, offset: u64, len: u64) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let rc = unsafe {
+        libc::fallocate(f.as_raw_fd(),
+                        libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+                        offset as libc::off_t,
+                        len as libc::off_t)
+    };
+    if rc == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// lchown(2); `None` leaves that id unchanged.
+pub fn chown(path: &Path, uid: Option<u32>, gid: Option<u32>) -> io::Result<()> {
+    let cpath = cstring_from(path)?;
+    let rc = unsafe {
+        libc::lchown(cpath.as_ptr(),
+                     uid.map(|u| u as libc::uid_t).unwrap_or(!0),
+                     gid.map(|g| g as libc::gid_t).unwrap_or(!0))
+    };
+    if rc != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn xattr_name(name: &::std::ffi::OsStr) -> io::Result<CString> {
+    CString::new(name.as_bytes())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "name contains nul"))
+}
+
+/// Value of extended attribute `name` of `path`, not following symlinks.
+pub fn get_xattr(path: &Path, name: &::std::ffi::OsStr) -> io::Result<Vec<u8>> {
+    let cpath = cstring_from(path)?;
+    let cname = xattr_name(name)?;
+    loop {
+        let len = unsafe {
+            libc::lgetxattr(cpath.as_ptr(), cname.as_ptr(), ::std::ptr::null_mut(), 0)
+        };
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut value = vec![0u8; len as usize];
+        let got = unsafe {
+            libc::lgetxattr(cpath.as_ptr(),
+                            cname.as_ptr(),
+                            value.as_mut_ptr() as *mut libc::c_void,
+                            value.len())
+        };
+        if got >= 0 {
+            value.truncate(got as usize);
+            return Ok(value);
+        }
+        let e = io::Error::last_os_error();
+        if e.raw_os_error() != Some(libc::ERANGE) {
+            return Err(e);
+        }
+        // grew between the two calls
+    }
+}
+
+/// Names of the extended attributes of `path`, each followed by a nul.
+pub fn list_xattr(path: &Path) -> io::Result<Vec<u8>> {
+    let cpath = cstring_from(path)?;
+    loop {
+        let len = unsafe { libc::llistxattr(cpath.as_ptr(), ::std::ptr::null_mut(), 0) };
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut names = vec![0u8; len as usize];
+        let got = unsafe {
+            libc::llistxattr(cpath.as_ptr(), names.as_mut_ptr() as *mut libc::c_char, names.len())
+        };
+        if got >= 0 {
+            names.truncate(got as usize);
+            return Ok(names);
+        }
+        let e = io::Error::last_os_error();
+        if e.raw_os_error() != Some(libc::ERANGE) {
+            return Err(e);
+        }
+    }
+}
+
+/// Set extended attribute `name`; `flags` takes XATTR_CREATE or XATTR_REPLACE.
+pub fn set_xattr(path: &Path,
+                 name: &::std::ffi::OsStr,
+                 value: &[u8],
+                 flags: u32)
+                 -> io::Result<()> {
+    let cpath = cstring_from(path)?;
+    let cname = xattr_name(name)?;
+    let rc = unsafe {
+        libc::lsetxattr(cpath.as_ptr(),
+                        cname.as_ptr(),
+                        value.as_ptr() as *const libc::c_void,
+                        value.len(),
+                        flags as libc::c_int)
+    };
+    if rc != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+pub fn remove_xattr(path: &Path, name: &::std::ffi::OsStr) -> io::Result<()> {
+    let cpath = cstring_from(path)?;
+    let cname = xattr_name(name)?;
+    if unsafe { libc::lremovexattr(cpath.as_ptr(), cname.as_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
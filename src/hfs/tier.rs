@@ -0,0 +1,1842 @@
+use errors::*;
+
+use super::blocks::BlockMap;
+use super::dedup::DedupStore;
+use super::kv::KvStore;
+use super::store::{Codec, CompressedStore, ObjectInfo, ObjectStore};
+use super::stub;
+use super::sys;
+use super::transfer::{self, Multipart, TransferPolicy};
+use super::version::Version;
+
+use time::Timespec;
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::fs::File;
+use std::io::{self, Read};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Which files leave the hot tier first.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Order {
+    /// least recently accessed, by atime
+    Lru,
+    /// least often opened through the mount, oldest atime breaking ties
+    Lfu,
+}
+
+/// What fsync promises.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Durability {
+    /// the backing file is on disk
+    Local,
+    /// the object store holds the file's current content
+    Object,
+}
+
+#[derive(Debug, Clone)]
+pub struct EvictionPolicy {
+    /// percentage of the backing filesystem in use above which eviction starts
+    pub high_watermark: u64,
+    /// percentage eviction works down to once started
+    pub low_watermark: u64,
+    pub order: Order,
+}
+
+/// Which files are compressed on their way to the object store.
+#[derive(Debug, Clone)]
+pub struct CompressionPolicy {
+    /// `None` stores files as they are
+    pub codec: Option<Codec>,
+    /// zstd level; lz4 has none
+    pub level: i32,
+    /// extensions, without the dot, of files that are stored as they are
+    /// because they are compressed already
+    pub skip: Vec<String>,
+}
+
+/// What is known about a file beyond what the backing directory records.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Record {
+    /// the object holding the content, empty if none has been uploaded
+    pub key: String,
+    /// bytes at the start of the object that belong to the file; the rest
+    /// of the placeholder, if longer, is zeros
+    pub size: u64,
+    /// false once the content lives in the object store, with at most some
+    /// blocks of it cached in the placeholder
+    pub resident: bool,
+    /// the object holds the content the file had when `length` and `mtime`
+    /// were taken
+    #[serde(default)]
+    pub synced: bool,
+    /// length and mtime of the file when last uploaded, or of the
+    /// placeholder when last changed through the mount, to notice changes
+    /// made behind our back
+    #[serde(default = "unknown_length")]
+    pub length: u64,
+    #[serde(default)]
+    pub mtime: (i64, i32),
+    /// number of opens, for LFU
+    pub hits: u64,
+    /// the object is held compressed, in frames
+    #[serde(default)]
+    pub compressed: bool,
+    /// the object is a manifest of shared chunks
+    #[serde(default)]
+    pub chunked: bool,
+}
+
+/// Length of a record saved before lengths were, taken to be its size: the
+/// placeholder was left exactly that long.
+const UNKNOWN_LENGTH: u64 = u64::MAX;
+
+fn unknown_length() -> u64 {
+    UNKNOWN_LENGTH
+}
+
+/// A record as saved, with what older versions did not save filled in.
+fn upgraded(mut record: Record) -> Record {
+    if record.length == UNKNOWN_LENGTH {
+        record.length = record.size;
+    }
+    record
+}
+
+/// Stored next to a file's object so that what the placeholder carries
+/// besides its content survives in the cold tier too.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Sidecar {
+    pub xattrs: BTreeMap<String, Vec<u8>>,
+}
+
+/// What the metadata refers to in the object store.
+pub struct Live {
+    /// objects of files, versions, snapshots and unfinished uploads, by
+    /// key, with whether each is a manifest of chunks
+    pub objects: HashMap<String, bool>,
+    /// files with records, whose sidecars are in use
+    pub inos: HashSet<u64>,
+}
+
+/// Snapshots naming an object, which is kept while any do.
+#[derive(Serialize, Deserialize, Debug)]
+struct Pin {
+    ino: u64,
+    compressed: bool,
+    chunked: bool,
+    snapshots: Vec<String>,
+}
+
+/// Journal entry for a file written through the mount and not yet uploaded.
+#[derive(Serialize, Deserialize, Debug)]
+struct Dirty {
+    /// milliseconds since the epoch at the last write
+    since: u64,
+}
+
+struct Candidate {
+    ino: u64,
+    path: PathBuf,
+    /// whole file present, rather than some cached blocks
+    resident: bool,
+    atime: Timespec,
+    hits: u64,
+}
+
+pub struct Tiering {
+    backing: PathBuf,
+    store: Arc<dyn ObjectStore>,
+    /// `store` seen through compression, which objects compressed earlier
+    /// are read through even once compression is off
+    compressed: CompressedStore,
+    compression: CompressionPolicy,
+    /// `store` seen through deduplication, which likewise reads chunked
+    /// objects once it is off
+    chunked: DedupStore,
+    dedup: bool,
+    records: KvStore,
+    policy: EvictionPolicy,
+    transfer: TransferPolicy,
+    last_check: Mutex<Option<Instant>>,
+    /// bytes held only in the object store, counted on first use
+    cold_bytes: Mutex<Option<u64>>,
+    /// files written through the mount and not yet uploaded, persisted so
+    /// that they are uploaded after a crash or remount too
+    journal: KvStore,
+    /// the journal in memory, with the time of the last write
+    pending: Mutex<BTreeMap<u64, u64>>,
+    /// files being uploaded or evicted, which nothing else may do to them
+    busy: Mutex<HashSet<u64>>,
+    /// files removed while claimed, with the path each was last at, which
+    /// the holder of the claim finishes removing as it lets go
+    removed: Mutex<HashMap<u64, PathBuf>>,
+    /// held to read, change and save a record that another thread may save too
+    update: Mutex<()>,
+    /// multipart uploads under way, to resume after an interruption
+    uploads: KvStore,
+    /// blocks of evicted files cached in their placeholders, persisted and
+    /// kept in memory once read
+    blocks: KvStore,
+    block_maps: Mutex<HashMap<u64, BlockMap>>,
+    /// objects that stopped being current, by version id
+    versions: KvStore,
+    /// versions whose content is still held under `kept_dir`, waiting to be
+    /// moved to the object store, with the inode each was of
+    kept: Mutex<BTreeMap<String, u64>>,
+    /// how long they are kept; zero keeps none
+    retention: Duration,
+    /// objects held by snapshots, by key
+    pins: KvStore,
+}
+
+pub const STATE_DIR: &str = ".s3hfs";
+
+/// Most parts an upload may be made of, as S3 allows.
+const MAX_PARTS: u64 = 10_000;
+
+/// Directory under the state directory holding versions not yet uploaded.
+const KEPT_DIR: &str = "kept";
+
+/// Key under which the cold copy of a backing file is stored.
+pub fn object_key(ino: u64) -> String {
+    format!("data/{:016x}", ino)
+}
+
+/// Key under which an upload of `ino` is stored when earlier uploads are
+/// kept as versions: a new one every time.
+fn versioned_key(ino: u64) -> String {
+    format!("{}@{:016x}", object_key(ino), now_millis())
+}
+
+/// Key of the sidecar stored alongside `object_key(ino)`.
+pub fn sidecar_key(ino: u64) -> String {
+    format!("meta/{:016x}", ino)
+}
+
+fn record_key(ino: u64) -> String {
+    format!("{:016x}", ino)
+}
+
+fn ino_of_key(key: &str) -> Option<u64> {
+    u64::from_str_radix(key, 16).ok()
+}
+
+/// Milliseconds since the epoch, as kept in the dirty journal.
+pub fn now_millis() -> u64 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    now.as_secs() * 1000 + u64::from(now.subsec_millis())
+}
+
+fn cold_bytes_of(record: Option<&Record>) -> u64 {
+    match record {
+        Some(r) if !r.resident => r.size,
+        _ => 0,
+    }
+}
+
+/// Extended attributes of `path`, not following a symlink.
+pub fn xattrs_of(path: &Path) -> Result<BTreeMap<String, Vec<u8>>> {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+
+    let mut xattrs = BTreeMap::new();
+    let names = sys::list_xattr(path).chain_err(|| format!("listing xattrs of {:?}", path))?;
+    for name in names.split(|b| *b == 0).filter(|n| !n.is_empty()) {
+        let name = OsStr::from_bytes(name);
+        match sys::get_xattr(path, name) {
+            Ok(value) => {
+                xattrs.insert(name.to_string_lossy().into_owned(), value);
+            }
+            Err(e) => warn!("reading xattr {:?} of {:?}: {}", name, path, e),
+        }
+    }
+    Ok(xattrs)
+}
+
+impl Tiering {
+    pub fn new(backing: &Path,
+               store: Arc<dyn ObjectStore>,
+               policy: EvictionPolicy,
+               transfer: TransferPolicy,
+               compression: CompressionPolicy,
+               dedup: bool,
+               retention: Duration)
+               -> Result<Tiering> {
+        if policy.low_watermark > policy.high_watermark || policy.high_watermark > 100 {
+            bail!("watermarks must satisfy low <= high <= 100: {:?}", policy);
+        }
+        if transfer.part_size == 0 || transfer.block_size == 0 {
+            bail!("part and block sizes must not be zero");
+        }
+        // a block is fetched as one frame, and a part is whole frames
+        if compression.codec.is_some() && !transfer.part_size.is_multiple_of(transfer.block_size) {
+            bail!("part size must be a multiple of the block size to compress");
+        }
+        let compressed = CompressedStore::new(store.clone(),
+                                              compression.codec.unwrap_or(Codec::Zstd),
+                                              compression.level,
+                                              transfer.block_size);
+        let chunked = DedupStore::new(store.clone(),
+                                      KvStore::open(backing.join(STATE_DIR).join("chunks"))?,
+                                      compression.codec,
+                                      compression.level);
+        let records = KvStore::open(backing.join(STATE_DIR).join("records"))?;
+        let journal = KvStore::open(backing.join(STATE_DIR).join("dirty"))?;
+        let uploads = KvStore::open(backing.join(STATE_DIR).join("uploads"))?;
+        let blocks = KvStore::open(backing.join(STATE_DIR).join("blocks"))?;
+        let versions = KvStore::open(backing.join(STATE_DIR).join("versions"))?;
+        let pins = KvStore::open(backing.join(STATE_DIR).join("pins"))?;
+        let mut pending = BTreeMap::new();
+        for key in journal.keys()? {
+            match (ino_of_key(&key), journal.get::<Dirty>(&key)?) {
+                (Some(ino), Some(dirty)) => {
+                    pending.insert(ino, dirty.since);
+                }
+                _ => warn!("ignoring journal entry {:?}", key),
+            }
+        }
+        if !pending.is_empty() {
+            info!("{} files still to upload", pending.len());
+        }
+        let kept_dir = backing.join(STATE_DIR).join(KEPT_DIR);
+        fs::create_dir_all(&kept_dir).chain_err(|| format!("creating {:?}", kept_dir))?;
+        let mut kept = BTreeMap::new();
+        for id in versions.keys()? {
+            match versions.get::<Version>(&id)? {
+                Some(ref v) if v.key.is_empty() => {
+                    kept.insert(id, v.ino);
+                }
+                _ => {}
+            }
+        }
+        Ok(Tiering {
+            backing: backing.to_path_buf(),
+            store,
+            compressed,
+            compression,
+            chunked,
+            dedup,
+            records,
+            policy,
+            transfer,
+            last_check: Mutex::new(None),
+            cold_bytes: Mutex::new(None),
+            journal,
+            pending: Mutex::new(pending),
+            busy: Mutex::new(HashSet::new()),
+            removed: Mutex::new(HashMap::new()),
+            update: Mutex::new(()),
+            uploads,
+            blocks,
+            block_maps: Mutex::new(HashMap::new()),
+            versions,
+            kept: Mutex::new(kept),
+            retention,
+            pins,
+        })
+    }
+
+    /// The store beneath compression and deduplication.
+    pub fn store(&self) -> &dyn ObjectStore {
+        &*self.store
+    }
+
+    /// Everything the records, versions, snapshots and unfinished uploads
+    /// refer to in the object store.
+    pub fn live(&self) -> Result<Live> {
+        let mut live = Live {
+            objects: HashMap::new(),
+            inos: HashSet::new(),
+        };
+        for key in self.records.keys()? {
+            let record = self.records.get::<Record>(&key)?;
+            if let (Some(ino), Some(record)) = (ino_of_key(&key), record) {
+                live.inos.insert(ino);
+                if !record.key.is_empty() {
+                    live.objects.insert(record.key, record.chunked);
+                }
+            }
+        }
+        for id in self.versions.keys()? {
+            match self.versions.get::<Version>(&id)? {
+                // still held under the state directory
+                Some(ref v) if v.key.is_empty() => {}
+                Some(v) => {
+                    live.objects.insert(v.key, v.chunked);
+                }
+                None => {}
+            }
+        }
+        for key in self.pins.keys()? {
+            if let Some(pin) = self.pins.get::<Pin>(&key)? {
+                live.objects.insert(key, pin.chunked);
+            }
+        }
+        for key in self.uploads.keys()? {
+            let upload = self.uploads.get::<Multipart>(&key)?;
+            if let (Some(ino), Some(upload)) = (ino_of_key(&key), upload) {
+                let key = if upload.key.is_empty() { object_key(ino) } else { upload.key };
+                live.objects.insert(key, upload.chunked);
+            }
+        }
+        Ok(live)
+    }
+
+    /// Uses of chunk `hash` counted by deduplication.
+    pub fn chunk_count(&self, hash: &str) -> Result<u64> {
+        self.chunked.count(hash)
+    }
+
+    /// Delete chunk `hash`, found named by no manifest when its count was
+    /// `count`, unless that has changed. Returns whether it was deleted.
+    pub fn drop_chunk(&self, hash: &str, count: u64) -> Result<bool> {
+        self.chunked.drop_chunk(hash, count)
+    }
+
+    /// The store holding objects that are, or are not, compressed or chunked.
+    fn object_store(&self, compressed: bool, chunked: bool) -> &dyn ObjectStore {
+        if chunked {
+            &self.chunked
+        } else if compressed {
+            &self.compressed
+        } else {
+            &*self.store
+        }
+    }
+
+    /// Whether the file at `path` is to be compressed when uploaded.
+    fn compresses(&self, path: &Path) -> bool {
+        if self.compression.codec.is_none() {
+            return false;
+        }
+        let extension = path.extension().map(|e| e.to_string_lossy().to_lowercase());
+        !extension.is_some_and(|e| self.compression.skip.contains(&e))
+    }
+
+    pub fn record(&self, ino: u64) -> Result<Option<Record>> {
+        Ok(self.records.get(&record_key(ino))?.map(upgraded))
+    }
+
+    /// Every record, by inode.
+    pub fn records(&self) -> Result<Vec<(u64, Record)>> {
+        let mut found = Vec::new();
+        for key in self.records.keys()? {
+            if let (Some(ino), Some(record)) = (ino_of_key(&key), self.records.get(&key)?) {
+                found.push((ino, upgraded(record)));
+            }
+        }
+        Ok(found)
+    }
+
+    /// Size and time of the object of a record, `None` if it is missing.
+    pub fn object_info(&self, record: &Record) -> Result<Option<ObjectInfo>> {
+        self.object_store(record.compressed, record.chunked).head(&record.key)
+    }
+
+    /// Take what the backing file of `ino` holds now as its content: it is
+    /// resident again and waits for upload. For a placeholder written
+    /// outside the mount, or a file whose object is lost.
+    pub fn reset(&self, ino: u64) -> Result<()> {
+        {
+            let _update = self.update.lock().unwrap();
+            let before = match self.record(ino)? {
+                Some(r) => r,
+                None => return Ok(()),
+            };
+            let mut record = before.clone();
+            record.resident = true;
+            record.synced = false;
+            self.save(ino, Some(&before), Some(&record))?;
+        }
+        self.drop_block_map(ino)?;
+        self.mark_dirty(ino)
+    }
+
+    /// Write `after` over `before`, keeping the cold byte count in step.
+    fn save(&self, ino: u64, before: Option<&Record>, after: Option<&Record>) -> Result<()> {
+        match after {
+            Some(r) => self.records.put(&record_key(ino), r)?,
+            None => self.records.delete(&record_key(ino))?,
+        }
+        if let Some(ref mut n) = *self.cold_bytes.lock().unwrap() {
+            *n = (*n + cold_bytes_of(after)).saturating_sub(cold_bytes_of(before));
+        }
+        Ok(())
+    }
+
+    /// Blocks of `ino` cached in its placeholder.
+    fn block_map(&self, ino: u64) -> Result<BlockMap> {
+        if let Some(map) = self.block_maps.lock().unwrap().get(&ino) {
+            return Ok(map.clone());
+        }
+        let map = self.blocks
+            .get(&record_key(ino))?
+            .unwrap_or_else(|| BlockMap::new(self.transfer.block_size));
+        self.block_maps.lock().unwrap().insert(ino, map.clone());
+        Ok(map)
+    }
+
+    fn save_block_map(&self, ino: u64, map: &BlockMap) -> Result<()> {
+        if map.is_empty() {
+            self.blocks.delete(&record_key(ino))?;
+        } else {
+            self.blocks.put(&record_key(ino), map)?;
+        }
+        self.block_maps.lock().unwrap().insert(ino, map.clone());
+        Ok(())
+    }
+
+    fn drop_block_map(&self, ino: u64) -> Result<()> {
+        self.block_maps.lock().unwrap().remove(&ino);
+        self.blocks.delete(&record_key(ino))
+    }
+
+    /// Bytes of file content held only in the object store.
+    pub fn cold_bytes(&self) -> Result<u64> {
+        let mut cold_bytes = self.cold_bytes.lock().unwrap();
+        if let Some(n) = *cold_bytes {
+            return Ok(n);
+        }
+        let mut n = 0;
+        for key in self.records.keys()? {
+            n += cold_bytes_of(self.records.get::<Record>(&key)?.as_ref());
+        }
+        *cold_bytes = Some(n);
+        Ok(n)
+    }
+
+    /// Count an open for the LFU order and bring the content back if it was
+    /// evicted and is about to be written, unless the open is about to
+    /// truncate it anyway. Readers of an evicted file fetch the blocks they
+    /// need through `cache_range`.
+    pub fn open(&self, ino: u64, path: &Path, writing: bool, truncating: bool) -> Result<()> {
+        // a prefetch must not write into the placeholder once writers can
+        let claim = writing && self.is_evicted(ino)?;
+        if claim {
+            self.claim_wait(ino);
+        }
+        let result = self.open_claimed(ino, path, writing, truncating);
+        if claim {
+            self.unclaim(ino);
+        }
+        result
+    }
+
+    fn open_claimed(&self, ino: u64, path: &Path, writing: bool, truncating: bool) -> Result<()> {
+        let before = self.record(ino)?;
+        let mut record = match before.clone() {
+            Some(r) => r,
+            None if self.policy.order == Order::Lfu => {
+                Record {
+                    resident: true,
+                    ..Record::default()
+                }
+            }
+            None => return Ok(()),
+        };
+        record.hits += 1;
+        if !record.resident && truncating {
+            self.retire(ino, path, &record)?;
+            record.key = String::new();
+            record.resident = true;
+            record.synced = false;
+        } else if !record.resident && writing {
+            let metadata = fs::metadata(path).chain_err(|| format!("stat {:?}", path))?;
+            if metadata.len() != record.length || stub::mtime_of(&metadata) != record.mtime {
+                // written outside the mount since eviction: what is there now wins
+                warn!("stale placeholder {:?}, keeping local content", path);
+                record.synced = false;
+            } else {
+                let map = self.block_map(ino)?;
+                let missing: Vec<(u64, u64)> = (0..map.count(record.size))
+                    .filter(|n| !map.contains(*n))
+                    .map(|n| map.range(n, record.size))
+                    .collect();
+                debug!("recalling {} blocks of {:?} from {}", missing.len(), path, record.key);
+                stub::fill(self.object_store(record.compressed, record.chunked),
+                           &record.key,
+                           ino,
+                           path,
+                           &missing,
+                           self.transfer.threads)?;
+            }
+            record.resident = true;
+        }
+        self.save(ino, before.as_ref(), Some(&record))?;
+        if record.resident {
+            self.drop_block_map(ino)?;
+        }
+        Ok(())
+    }
+
+    /// Size of the blocks evicted files are cached in.
+    pub fn block_size(&self) -> u64 {
+        self.transfer.block_size
+    }
+
+    /// Whether `ino` has been evicted, so that reads go through `cache_range`.
+    pub fn is_evicted(&self, ino: u64) -> Result<bool> {
+        Ok(self.record(ino)?.is_some_and(|r| !r.resident))
+    }
+
+    /// Make sure the blocks of evicted `ino` under `len` bytes at `offset`
+    /// are cached in the placeholder at `path`, fetching the missing ones,
+    /// so that reads can be served from it.
+    pub fn cache_range(&self, ino: u64, path: &Path, offset: u64, len: u64) -> Result<()> {
+        let record = match self.record(ino)? {
+            Some(ref r) if !r.resident => r.clone(),
+            _ => return Ok(()),
+        };
+        // past the object the placeholder holds the zeros the file does
+        let end = record.size.min(offset.saturating_add(len));
+        if offset >= end {
+            return Ok(());
+        }
+        let metadata = fs::metadata(path).chain_err(|| format!("stat {:?}", path))?;
+        if metadata.len() != record.length || stub::mtime_of(&metadata) != record.mtime {
+            // written outside the mount since eviction: what is there now wins
+            warn!("stale placeholder {:?}, keeping local content", path);
+            return self.reset(ino);
+        }
+        let mut map = self.block_map(ino)?;
+        let missing: Vec<u64> = (offset / map.block_size..(end - 1) / map.block_size + 1)
+            .filter(|n| !map.contains(*n))
+            .collect();
+        if missing.is_empty() {
+            return Ok(());
+        }
+        let ranges: Vec<(u64, u64)> = missing.iter().map(|n| map.range(*n, record.size)).collect();
+        debug!("fetching {} blocks of {:?} from {}", ranges.len(), path, record.key);
+        stub::fill(self.object_store(record.compressed, record.chunked),
+                   &record.key,
+                   ino,
+                   path,
+                   &ranges,
+                   self.transfer.threads)?;
+        for n in missing {
+            map.insert(n);
+        }
+        self.save_block_map(ino, &map)
+    }
+
+    /// Truncate or extend an evicted file without recalling it: only the
+    /// placeholder and the record change. Returns false if the file is
+    /// resident and should be truncated as usual.
+    pub fn truncate(&self, ino: u64, path: &Path, size: u64) -> Result<bool> {
+        if !self.is_evicted(ino)? {
+            return Ok(false);
+        }
+        self.claim_wait(ino);
+        let result = self.truncate_claimed(ino, path, size);
+        self.unclaim(ino);
+        result
+    }
+
+    fn truncate_claimed(&self, ino: u64, path: &Path, size: u64) -> Result<bool> {
+        let before = self.record(ino)?;
+        let mut record = match before.clone() {
+            Some(ref r) if r.resident => return Ok(false),
+            Some(r) => r,
+            None => return Ok(false),
+        };
+        fs::OpenOptions::new()
+            .write(true)
+            .open(path)
+            .and_then(|f| f.set_len(size))
+            .chain_err(|| format!("truncating {:?}", path))?;
+        if size == 0 {
+            self.retire(ino, path, &record)?;
+            record.key = String::new();
+            record.resident = true;
+        }
+        record.size = record.size.min(size);
+        record.synced = false;
+        self.save(ino, before.as_ref(), Some(&record))?;
+        if record.resident {
+            self.drop_block_map(ino)?;
+        } else {
+            let mut map = self.block_map(ino)?;
+            map.truncate(size);
+            self.save_block_map(ino, &map)?;
+        }
+        let metadata = fs::metadata(path).chain_err(|| format!("stat {:?}", path))?;
+        self.changed(ino, &metadata)?;
+        Ok(true)
+    }
+
+    /// The placeholder of `ino` was changed through the mount (times, length),
+    /// so the change must not be mistaken for one made behind our back.
+    pub fn changed(&self, ino: u64, metadata: &fs::Metadata) -> Result<()> {
+        let _update = self.update.lock().unwrap();
+        let mut record = match self.record(ino)? {
+            Some(ref r) if r.resident => return Ok(()),
+            Some(r) => r,
+            None => return Ok(()),
+        };
+        record.length = metadata.len();
+        record.mtime = stub::mtime_of(metadata);
+        self.records.put(&record_key(ino), &record)
+    }
+
+    /// The file's content was replaced, so neither the record nor any cold copy applies.
+    pub fn discard(&self, ino: u64) -> Result<()> {
+        let before = self.record(ino)?;
+        if before.is_none() {
+            return Ok(());
+        }
+        self.drop_block_map(ino)?;
+        self.save(ino, before.as_ref(), None)
+    }
+
+    /// The file last at `path` is gone: drop the record, the cold copy, or
+    /// keep it as a version, and any pending upload. If an upload or eviction
+    /// is under way, which would otherwise record the file again, it is left
+    /// to finish the removal when it lets go of the file rather than waited
+    /// for.
+    pub fn remove(&self, ino: u64, path: &Path) -> Result<()> {
+        {
+            let mut busy = self.busy.lock().unwrap();
+            if !busy.insert(ino) {
+                debug!("{:?} removed while claimed; removing it once released", path);
+                self.removed.lock().unwrap().insert(ino, path.to_path_buf());
+                return Ok(());
+            }
+        }
+        let result = self.remove_claimed(ino, path);
+        self.unclaim(ino);
+        result
+    }
+
+    fn remove_claimed(&self, ino: u64, path: &Path) -> Result<()> {
+        if let Some(upload) = self.uploads.get::<Multipart>(&record_key(ino))? {
+            let key = if upload.key.is_empty() { object_key(ino) } else { upload.key };
+            self.object_store(upload.compressed, upload.chunked)
+                .abort_multipart(&key, &upload.upload_id)?;
+            self.uploads.delete(&record_key(ino))?;
+        }
+        match self.record(ino)? {
+            Some(record) => self.retire(ino, path, &record)?,
+            None => self.store.delete(&object_key(ino))?,
+        }
+        self.store.delete(&sidecar_key(ino))?;
+        self.settled(ino, None)?;
+        self.discard(ino)
+    }
+
+    fn keeps_versions(&self) -> bool {
+        !self.retention.is_zero()
+    }
+
+    /// The object of `record` no longer holds the content of `ino`, last at
+    /// `path`: keep it as a version, or delete it if none are kept.
+    fn retire(&self, ino: u64, path: &Path, record: &Record) -> Result<()> {
+        if record.key.is_empty() {
+            return Ok(());
+        }
+        if !self.keeps_versions() {
+            if self.pinned(&record.key)? {
+                debug!("{} still held by a snapshot", record.key);
+                return Ok(());
+            }
+            return self.object_store(record.compressed, record.chunked).delete(&record.key);
+        }
+        let mut version = Version {
+            ino,
+            path: path.strip_prefix(&self.backing).unwrap_or(path).to_string_lossy().into_owned(),
+            retired: now_millis(),
+            key: record.key.clone(),
+            size: record.size,
+            mtime: record.mtime,
+            compressed: record.compressed,
+            chunked: record.chunked,
+        };
+        let id = self.unused_id(&mut version)?;
+        debug!("keeping {} as version {} of {}", version.key, id, version.path);
+        self.versions.put(&id, &version)
+    }
+
+    /// Id under which to record `version`, retired a millisecond later if
+    /// another of the same file was retired at the same time.
+    fn unused_id(&self, version: &mut Version) -> Result<String> {
+        while self.versions.get::<Version>(&version.id())?.is_some() {
+            version.retired += 1;
+        }
+        Ok(version.id())
+    }
+
+    fn kept_path(&self, id: &str) -> PathBuf {
+        self.backing.join(STATE_DIR).join(KEPT_DIR).join(id)
+    }
+
+    /// Before `ino` at `path` is truncated or, with `removing`, removed, keep
+    /// its content as a version of the file at `at` if the object store does
+    /// not hold it already. It is kept under the state directory, linked if
+    /// the file is going and copied if not, until the uploader moves it to
+    /// the store. Does nothing unless versions are kept.
+    pub fn preserve(&self, ino: u64, path: &Path, at: &Path, removing: bool) -> Result<()> {
+        if !self.keeps_versions() {
+            return Ok(());
+        }
+        let metadata = fs::metadata(path).chain_err(|| format!("stat {:?}", path))?;
+        if let Some(r) = self.record(ino)? {
+            let unchanged = r.length == metadata.len() && r.mtime == stub::mtime_of(&metadata);
+            if !r.resident || r.synced && unchanged {
+                // the object is the content, and becomes a version once retired
+                return Ok(());
+            }
+        }
+        let mut version = Version {
+            ino,
+            path: at.strip_prefix(&self.backing).unwrap_or(at).to_string_lossy().into_owned(),
+            retired: now_millis(),
+            key: String::new(),
+            size: metadata.len(),
+            mtime: stub::mtime_of(&metadata),
+            compressed: false,
+            chunked: false,
+        };
+        let id = self.unused_id(&mut version)?;
+        let kept = self.kept_path(&id);
+        if removing {
+            fs::hard_link(path, &kept).chain_err(|| format!("linking {:?} to {:?}", path, kept))?;
+        } else {
+            fs::copy(path, &kept).chain_err(|| format!("copying {:?} to {:?}", path, kept))?;
+        }
+        self.versions.put(&id, &version)?;
+        debug!("keeping {:?} as version {} of {} until uploaded", kept, id, version.path);
+        self.kept.lock().unwrap().insert(id, ino);
+        Ok(())
+    }
+
+    /// Versions still held under the state directory, with their inodes.
+    pub fn kept_versions(&self) -> Vec<(String, u64)> {
+        self.kept.lock().unwrap().iter().map(|(id, ino)| (id.clone(), *ino)).collect()
+    }
+
+    /// Move the content of version `id` from under the state directory to
+    /// the object store. The caller holds the claim on its inode.
+    pub fn upload_version(&self, id: &str) -> Result<()> {
+        let mut version = match self.versions.get::<Version>(id)? {
+            Some(ref v) if !v.key.is_empty() => {
+                self.kept.lock().unwrap().remove(id);
+                return Ok(());
+            }
+            Some(v) => v,
+            None => {
+                // expired before it was moved
+                self.kept.lock().unwrap().remove(id);
+                return Ok(());
+            }
+        };
+        let kept = self.kept_path(id);
+        let mut file = match File::open(&kept) {
+            Ok(f) => f,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                warn!("content of version {} of {} is lost", id, version.path);
+                self.versions.delete(id)?;
+                self.kept.lock().unwrap().remove(id);
+                return Ok(());
+            }
+            Err(e) => return Err(e).chain_err(|| format!("opening {:?}", kept)),
+        };
+        // written through a handle still open when it was removed
+        let metadata = file.metadata().chain_err(|| format!("stat {:?}", kept))?;
+        let key = versioned_key(version.ino);
+        let chunked = self.dedup;
+        let compressed = !chunked && self.compresses(Path::new(&version.path));
+        let store = self.object_store(compressed, chunked);
+        if metadata.len() > self.transfer.part_size {
+            let part_size = self.part_size(metadata.len());
+            let upload = Multipart {
+                upload_id: store.start_multipart(&key, metadata.len(), part_size)?,
+                key: key.clone(),
+                part_size,
+                length: metadata.len(),
+                mtime: stub::mtime_of(&metadata),
+                parts: Vec::new(),
+                compressed,
+                chunked,
+            };
+            let upload_id = upload.upload_id.clone();
+            // not resumed: the kept file stays until it is uploaded whole
+            let sent = transfer::upload_parts(store,
+                                              &key,
+                                              &file,
+                                              upload,
+                                              self.transfer.threads,
+                                              |_| Ok(()));
+            if let Err(e) = sent {
+                if let Err(e) = store.abort_multipart(&key, &upload_id) {
+                    warn!("unable to abort upload to {}: {}", key, e);
+                }
+                return Err(e);
+            }
+        } else {
+            let mut data = Vec::new();
+            file.read_to_end(&mut data).chain_err(|| format!("reading {:?}", kept))?;
+            store.put(&key, &data)?;
+        }
+        version.key = key;
+        version.size = metadata.len();
+        version.mtime = stub::mtime_of(&metadata);
+        version.compressed = compressed;
+        version.chunked = chunked;
+        self.versions.put(id, &version)?;
+        debug!("moved version {} of {} to {}", id, version.path, version.key);
+        fs::remove_file(&kept).chain_err(|| format!("removing {:?}", kept))?;
+        self.kept.lock().unwrap().remove(id);
+        Ok(())
+    }
+
+    /// Versions kept of files at or under `path`, or of all files, by path
+    /// and age.
+    pub fn versions(&self, path: Option<&str>) -> Result<Vec<Version>> {
+        let path = path.map(|p| p.trim_matches('/'));
+        let mut found = Vec::new();
+        for id in self.versions.keys()? {
+            if let Some(version) = self.versions.get::<Version>(&id)? {
+                let wanted = path.is_none_or(|p| {
+                    p.is_empty() || version.path == p ||
+                    version.path.starts_with(p) && version.path[p.len()..].starts_with('/')
+                });
+                if wanted {
+                    found.push(version);
+                }
+            }
+        }
+        found.sort_by(|a, b| (&a.path, a.retired).cmp(&(&b.path, b.retired)));
+        Ok(found)
+    }
+
+    /// Drop versions that have been kept for the retention period.
+    pub fn expire_versions(&self) -> Result<()> {
+        let now = now_millis();
+        let keep = self.retention.as_secs() * 1000 + u64::from(self.retention.subsec_millis());
+        for id in self.versions.keys()? {
+            let version = match self.versions.get::<Version>(&id)? {
+                Some(v) => v,
+                None => continue,
+            };
+            if now < version.retired + keep {
+                continue;
+            }
+            if version.key.is_empty() {
+                // being moved to the store: the next pass has it
+                if !self.claim(version.ino) {
+                    continue;
+                }
+                let expired = self.expire_kept(&id);
+                self.unclaim(version.ino);
+                expired?;
+                continue;
+            }
+            self.expire(&id, &version)?;
+        }
+        Ok(())
+    }
+
+    fn expire(&self, id: &str, version: &Version) -> Result<()> {
+        if !self.pinned(&version.key)? {
+            self.object_store(version.compressed, version.chunked).delete(&version.key)?;
+        }
+        self.versions.delete(id)?;
+        debug!("expired version {} of {}", id, version.path);
+        Ok(())
+    }
+
+    /// Expire version `id`, which was held under the state directory when
+    /// listed. The caller holds the claim on its inode.
+    fn expire_kept(&self, id: &str) -> Result<()> {
+        let version = match self.versions.get::<Version>(id)? {
+            Some(v) => v,
+            None => return Ok(()),
+        };
+        if !version.key.is_empty() {
+            return self.expire(id, &version);
+        }
+        let kept = self.kept_path(id);
+        match fs::remove_file(&kept) {
+            Ok(()) => {}
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e).chain_err(|| format!("removing {:?}", kept)),
+        }
+        self.kept.lock().unwrap().remove(id);
+        self.versions.delete(id)?;
+        debug!("expired version {} of {}", id, version.path);
+        Ok(())
+    }
+
+    fn pinned(&self, key: &str) -> Result<bool> {
+        Ok(self.pins.get::<Pin>(key)?.is_some())
+    }
+
+    /// Keep the object of `record`, the content of `ino`, for `snapshot`:
+    /// it is neither overwritten nor deleted until the snapshot is.
+    pub fn pin(&self, ino: u64, record: &Record, snapshot: &str) -> Result<()> {
+        let mut pin = self.pins.get::<Pin>(&record.key)?.unwrap_or(Pin {
+            ino,
+            compressed: record.compressed,
+            chunked: record.chunked,
+            snapshots: Vec::new(),
+        });
+        if !pin.snapshots.iter().any(|s| s == snapshot) {
+            pin.snapshots.push(snapshot.to_string());
+        }
+        self.pins.put(&record.key, &pin)
+    }
+
+    /// Pin the object of `ino` for `snapshot` if it holds the file's current
+    /// content, and return the record naming it. Under the update lock, so
+    /// that an upload finishing meanwhile cannot retire it unpinned.
+    pub fn pin_current(&self, ino: u64, snapshot: &str) -> Result<Option<Record>> {
+        let _update = self.update.lock().unwrap();
+        match self.record(ino)? {
+            Some(r) if !r.key.is_empty() && (!r.resident || r.synced) => {
+                self.pin(ino, &r, snapshot)?;
+                Ok(Some(r))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// `snapshot` no longer holds the object under `key`. Once no snapshot
+    /// does, it is deleted unless it is still current or kept as a version.
+    pub fn unpin(&self, key: &str, snapshot: &str) -> Result<()> {
+        let _update = self.update.lock().unwrap();
+        let mut pin = match self.pins.get::<Pin>(key)? {
+            Some(p) => p,
+            None => return Ok(()),
+        };
+        pin.snapshots.retain(|s| s != snapshot);
+        if !pin.snapshots.is_empty() {
+            return self.pins.put(key, &pin);
+        }
+        self.pins.delete(key)?;
+        if self.record(pin.ino)?.is_some_and(|r| r.key == key) {
+            return Ok(());
+        }
+        for id in self.versions.keys()? {
+            if self.versions.get::<Version>(&id)?.is_some_and(|v| v.key == key) {
+                return Ok(());
+            }
+        }
+        debug!("deleting {}, no longer held by a snapshot", key);
+        self.object_store(pin.compressed, pin.chunked).delete(key)
+    }
+
+    /// `len` bytes at `offset` of the object under `key`, held compressed or
+    /// chunked or not, for reads that do not go through a placeholder.
+    pub fn read_object(&self,
+                       key: &str,
+                       compressed: bool,
+                       chunked: bool,
+                       offset: u64,
+                       len: u64)
+                       -> Result<Vec<u8>> {
+        self.object_store(compressed, chunked).get_range(key, offset, len)
+    }
+
+    /// Write version `id` to a new file at `to`, relative to the root of the
+    /// filesystem, or where the file it was of was. Returns the file's path.
+    pub fn restore(&self, id: &str, to: Option<&str>) -> Result<PathBuf> {
+        let version = match self.versions.get::<Version>(id)? {
+            Some(v) => v,
+            None => bail!("no version {}", id),
+        };
+        // held under the state directory until uploaded, when it moves
+        let kept = if version.key.is_empty() {
+            File::open(self.kept_path(id)).ok()
+        } else {
+            None
+        };
+        let version = match (&kept, version.key.is_empty()) {
+            (&None, true) => {
+                match self.versions.get::<Version>(id)? {
+                    Some(ref v) if v.key.is_empty() => bail!("content of version {} is lost", id),
+                    Some(v) => v,
+                    None => bail!("no version {}", id),
+                }
+            }
+            _ => version,
+        };
+        let relative = to.unwrap_or(&version.path).trim_start_matches('/');
+        if relative.is_empty() || relative.split('/').any(|c| c == ".." || c == STATE_DIR) {
+            bail!("cannot restore to {:?}", relative);
+        }
+        let target = self.backing.join(relative);
+        let file = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&target)
+            .chain_err(|| format!("creating {:?}", target))?;
+        let map = BlockMap::new(self.transfer.block_size);
+        let ranges: Vec<(u64, u64)> =
+            (0..map.count(version.size)).map(|n| map.range(n, version.size)).collect();
+        let restored = match kept {
+            Some(mut kept) => {
+                io::copy(&mut kept, &mut &file)
+                    .map(|_| ())
+                    .chain_err(|| format!("copying version {} to {:?}", id, target))
+            }
+            None => {
+                transfer::download(self.object_store(version.compressed, version.chunked),
+                                   &version.key,
+                                   &file,
+                                   &ranges,
+                                   self.transfer.threads)
+            }
+        };
+        let restored =
+            restored.and_then(|_| file.sync_all().chain_err(|| format!("writing {:?}", target)));
+        if let Err(e) = restored {
+            let _ = fs::remove_file(&target);
+            return Err(e);
+        }
+        let mtime = Timespec::new(version.mtime.0, version.mtime.1);
+        sys::set_times(&target, mtime, mtime)
+            .chain_err(|| format!("setting times on {:?}", target))?;
+        Ok(target)
+    }
+
+    /// `ino` was written through the mount: the cold copy, if any, is out of
+    /// date and the file is journalled for upload.
+    pub fn mark_dirty(&self, ino: u64) -> Result<()> {
+        let _update = self.update.lock().unwrap();
+        if let Some(before) = self.record(ino)? {
+            if before.synced {
+                let mut record = before.clone();
+                record.synced = false;
+                self.save(ino, Some(&before), Some(&record))?;
+            }
+        }
+        let since = now_millis();
+        self.journal.put(&record_key(ino), &Dirty { since })?;
+        self.pending.lock().unwrap().insert(ino, since);
+        Ok(())
+    }
+
+    /// Journal `ino` for upload unless it already is, leaving the time of
+    /// its last write alone.
+    pub fn queue(&self, ino: u64) -> Result<()> {
+        if self.queued(ino) {
+            return Ok(());
+        }
+        self.mark_dirty(ino)
+    }
+
+    /// Whether `ino` is journalled for upload.
+    pub fn queued(&self, ino: u64) -> bool {
+        self.pending.lock().unwrap().contains_key(&ino)
+    }
+
+    /// Files waiting for upload, with the time of their last write in
+    /// milliseconds since the epoch.
+    pub fn pending(&self) -> Vec<(u64, u64)> {
+        self.pending.lock().unwrap().iter().map(|(ino, since)| (*ino, *since)).collect()
+    }
+
+    /// Number of files waiting for upload.
+    pub fn queue_depth(&self) -> usize {
+        self.pending.lock().unwrap().len()
+    }
+
+    /// Drop `ino` from the journal, unless it was written again after
+    /// `since`. `None` drops it whatever its time.
+    fn settled(&self, ino: u64, since: Option<u64>) -> Result<()> {
+        let mut pending = self.pending.lock().unwrap();
+        match (pending.get(&ino).cloned(), since) {
+            (None, _) => return Ok(()),
+            (Some(current), Some(since)) if current != since => return Ok(()),
+            _ => {}
+        }
+        self.journal.delete(&record_key(ino))?;
+        pending.remove(&ino);
+        Ok(())
+    }
+
+    /// Drop `ino` from the journal without uploading it: it has left the
+    /// namespace, though not yet the backing path.
+    pub fn unqueue(&self, ino: u64) -> Result<()> {
+        self.settled(ino, None)
+    }
+
+    /// Reserve `ino` for an upload or eviction; false if one is under way.
+    pub fn claim(&self, ino: u64) -> bool {
+        self.busy.lock().unwrap().insert(ino)
+    }
+
+    /// Claim `ino`, waiting for an upload, eviction or prefetch under way.
+    pub fn claim_wait(&self, ino: u64) {
+        while !self.claim(ino) {
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    /// Let go of `ino`, first finishing its removal if it was removed while
+    /// claimed.
+    pub fn unclaim(&self, ino: u64) {
+        let removed = {
+            let mut busy = self.busy.lock().unwrap();
+            let removed = self.removed.lock().unwrap().remove(&ino);
+            if removed.is_none() {
+                busy.remove(&ino);
+            }
+            removed
+        };
+        if let Some(path) = removed {
+            if let Err(e) = self.remove_claimed(ino, &path) {
+                warn!("unable to remove cold copy of removed {:?}: {}", path, e);
+            }
+            self.unclaim(ino);
+        }
+    }
+
+    /// Make the object store hold the current content of `ino`, unless it
+    /// already does, and drop it from the journal. The caller holds the claim
+    /// on `ino`.
+    pub fn upload(&self, ino: u64, path: &Path) -> Result<()> {
+        let since = self.pending.lock().unwrap().get(&ino).cloned();
+        let metadata = fs::metadata(path).chain_err(|| format!("stat {:?}", path))?;
+        let before = self.record(ino)?;
+        if let Some(ref r) = before {
+            if !r.resident {
+                // nothing here but the placeholder; the object is the content
+                return self.settled(ino, since);
+            }
+            if r.synced && r.length == metadata.len() && r.mtime == stub::mtime_of(&metadata) {
+                return self.settled(ino, since);
+            }
+        }
+
+        let mut file = File::open(path).chain_err(|| format!("opening {:?}", path))?;
+        // a snapshot may hold the object under the usual key, which must
+        // then not be written over
+        let versioned = self.keeps_versions() || self.pinned(&object_key(ino))?;
+        let mut key = if versioned { versioned_key(ino) } else { object_key(ino) };
+        // chunks are compressed when compression is on, whatever the file
+        let chunked = self.dedup;
+        let compressed = !chunked && self.compresses(path);
+        if let Some(ref r) = before {
+            if r.key == key && (r.compressed, r.chunked) != (compressed, chunked) {
+                // held the other way, the old object would linger beside the new
+                self.object_store(r.compressed, r.chunked).delete(&key)?;
+            }
+        }
+        let size = if metadata.len() > self.transfer.part_size {
+            key = self.upload_multipart(ino, &key, &file, &metadata, compressed, chunked)?;
+            metadata.len()
+        } else {
+            let mut data = Vec::new();
+            file.read_to_end(&mut data).chain_err(|| format!("reading {:?}", path))?;
+            self.object_store(compressed, chunked).put(&key, &data)?;
+            data.len() as u64
+        };
+        self.push_sidecar(ino, path)?;
+
+        let after = match fs::symlink_metadata(path) {
+            Ok(ref m) if m.ino() == ino => m.clone(),
+            _ => {
+                // removed or moved out of the namespace while being read:
+                // nothing is to refer to what was uploaded
+                if before.as_ref().is_none_or(|r| r.key != key) {
+                    self.object_store(compressed, chunked).delete(&key)?;
+                }
+                debug!("{:?} gone while being uploaded", path);
+                return self.settled(ino, since);
+            }
+        };
+        // written to while being read: the object is a mix, and the next
+        // upload must not be skipped
+        let synced = after.len() == metadata.len() &&
+                     stub::mtime_of(&after) == stub::mtime_of(&metadata);
+
+        let _update = self.update.lock().unwrap();
+        let before = self.record(ino)?;
+        let record = Record {
+            key,
+            size,
+            resident: true,
+            synced,
+            length: metadata.len(),
+            mtime: stub::mtime_of(&metadata),
+            hits: before.as_ref().map(|r| r.hits).unwrap_or(0),
+            compressed,
+            chunked,
+        };
+        self.save(ino, before.as_ref(), Some(&record))?;
+        debug!("uploaded {:?} ({} bytes) to {}", path, record.size, record.key);
+        if let Some(before) = before {
+            if before.key != record.key {
+                self.retire(ino, path, &before)?;
+            }
+        }
+        if !synced {
+            bail!("{:?} changed while being uploaded", path);
+        }
+        self.settled(ino, since)
+    }
+
+    /// Size of the parts to upload `length` bytes in: large enough that there
+    /// are few enough of them, and whole blocks, which compression frames.
+    fn part_size(&self, length: u64) -> u64 {
+        if length.div_ceil(self.transfer.part_size) > MAX_PARTS {
+            let block_size = self.transfer.block_size;
+            length.div_ceil(MAX_PARTS).div_ceil(block_size) * block_size
+        } else {
+            self.transfer.part_size
+        }
+    }
+
+    /// Upload `file` in parts to `key`, or carry on with an interrupted upload
+    /// of the same content if there is one. Returns the key uploaded to.
+    fn upload_multipart(&self,
+                        ino: u64,
+                        key: &str,
+                        file: &File,
+                        metadata: &fs::Metadata,
+                        compressed: bool,
+                        chunked: bool)
+                        -> Result<String> {
+        let store = self.object_store(compressed, chunked);
+        let length = metadata.len();
+        let mtime = stub::mtime_of(metadata);
+        let part_size = self.part_size(length);
+        let upload = match self.uploads.get::<Multipart>(&record_key(ino))? {
+            Some(mut u) => {
+                if u.key.is_empty() {
+                    u.key = object_key(ino);
+                }
+                if store.resumable() && u.length == length && u.mtime == mtime &&
+                   u.part_size == part_size && u.compressed == compressed &&
+                   u.chunked == chunked {
+                    info!("resuming upload to {} with {} parts done", u.key, u.parts.len());
+                    Some(u)
+                } else {
+                    let stale = self.object_store(u.compressed, u.chunked);
+                    if let Err(e) = stale.abort_multipart(&u.key, &u.upload_id) {
+                        warn!("unable to abort stale upload to {}: {}", u.key, e);
+                    }
+                    None
+                }
+            }
+            None => None,
+        };
+        let upload = match upload {
+            Some(u) => u,
+            None => {
+                let upload = Multipart {
+                    upload_id: store.start_multipart(key, length, part_size)?,
+                    key: key.to_string(),
+                    part_size,
+                    length,
+                    mtime,
+                    parts: Vec::new(),
+                    compressed,
+                    chunked,
+                };
+                self.uploads.put(&record_key(ino), &upload)?;
+                upload
+            }
+        };
+        let key = upload.key.clone();
+        transfer::upload_parts(store,
+                               &key,
+                               file,
+                               upload,
+                               self.transfer.threads,
+                               |progress| self.uploads.put(&record_key(ino), progress))?;
+        self.uploads.delete(&record_key(ino))?;
+        Ok(key)
+    }
+
+    /// Extended attributes of `ino` changed: refresh the sidecar if the
+    /// file is in the cold tier.
+    pub fn xattrs_changed(&self, ino: u64, path: &Path) -> Result<()> {
+        match self.record(ino)? {
+            Some(ref r) if !r.resident => self.push_sidecar(ino, path),
+            _ => Ok(()),
+        }
+    }
+
+    fn push_sidecar(&self, ino: u64, path: &Path) -> Result<()> {
+        let sidecar = Sidecar { xattrs: xattrs_of(path)? };
+        let encoded = ::serde_json::to_vec(&sidecar).chain_err(|| "encoding sidecar")?;
+        self.store.put(&sidecar_key(ino), &encoded)
+    }
+
+    /// Evict until usage is below the low watermark, if it has passed the high one.
+    /// Files in `open` are left alone.
+    pub fn maybe_evict(&self, open: &HashSet<u64>) -> Result<()> {
+        {
+            let mut last_check = self.last_check.lock().unwrap();
+            if let Some(last) = *last_check {
+                if last.elapsed() < Duration::from_secs(1) {
+                    return Ok(());
+                }
+            }
+            *last_check = Some(Instant::now());
+        }
+
+        let usage = sys::statvfs(&self.backing).chain_err(|| "reading backing usage")?;
+        if usage.used_percent() < self.policy.high_watermark {
+            return Ok(());
+        }
+        info!("backing path {}% full, evicting to {}%",
+              usage.used_percent(),
+              self.policy.low_watermark);
+
+        let mut candidates = Vec::new();
+        self.collect(&self.backing, open, &mut HashSet::new(), &mut candidates)?;
+        match self.policy.order {
+            Order::Lru => candidates.sort_by_key(|c| c.atime),
+            Order::Lfu => candidates.sort_by_key(|c| (c.hits, c.atime)),
+        }
+
+        for candidate in candidates {
+            if !self.claim(candidate.ino) {
+                // being uploaded; it can go next time
+                continue;
+            }
+            let result = self.evict(&candidate);
+            self.unclaim(candidate.ino);
+            match result {
+                Ok(true) => break,
+                Ok(false) => {}
+                Err(e) => warn!("unable to evict {:?}: {}", candidate.path, e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether usage of the backing path has passed the high watermark.
+    pub fn needs_space(&self) -> Result<bool> {
+        let usage = sys::statvfs(&self.backing).chain_err(|| "reading backing usage")?;
+        Ok(usage.used_percent() >= self.policy.high_watermark)
+    }
+
+    pub fn below_low_watermark(&self) -> Result<bool> {
+        let usage = sys::statvfs(&self.backing).chain_err(|| "reading backing usage")?;
+        Ok(usage.used_percent() <= self.policy.low_watermark)
+    }
+
+    fn collect(&self,
+               dir: &Path,
+               open: &HashSet<u64>,
+               seen: &mut HashSet<u64>,
+               found: &mut Vec<Candidate>)
+               -> Result<()> {
+        let rd = fs::read_dir(dir).chain_err(|| format!("listing {:?}", dir))?;
+        for entry in rd {
+            let entry = entry.chain_err(|| format!("listing {:?}", dir))?;
+            let path = entry.path();
+            if dir == self.backing && entry.file_name() == STATE_DIR {
+                continue;
+            }
+            let metadata = match fs::symlink_metadata(&path) {
+                Ok(m) => m,
+                Err(_) => continue,
+            };
+            if metadata.is_dir() {
+                self.collect(&path, open, seen, found)?;
+                continue;
+            }
+            if !metadata.is_file() || metadata.len() == 0 || open.contains(&metadata.ino()) {
+                continue;
+            }
+            if !seen.insert(metadata.ino()) {
+                // another name of a hard-linked file
+                continue;
+            }
+            let record = self.record(metadata.ino())?;
+            let resident = record.as_ref().is_none_or(|r| r.resident);
+            if !resident && self.block_map(metadata.ino())?.is_empty() {
+                // nothing cached to drop
+                continue;
+            }
+            found.push(Candidate {
+                ino: metadata.ino(),
+                path,
+                resident,
+                atime: Timespec::new(metadata.atime(), metadata.atime_nsec() as i32),
+                hits: record.map(|r| r.hits).unwrap_or(0),
+            });
+        }
+        Ok(())
+    }
+
+    /// Drop cached blocks of the candidate, uploading it first if it is
+    /// resident, until usage reaches the low watermark. Returns whether it did.
+    fn evict(&self, candidate: &Candidate) -> Result<bool> {
+        let path = &candidate.path;
+        if candidate.resident {
+            self.upload(candidate.ino, path)?;
+
+            // every block is first recorded as cached, before any is punched,
+            // so a crash in between leaves content the record accounts for
+            let _update = self.update.lock().unwrap();
+            let before = self.record(candidate.ino)?;
+            let mut record = match before.clone() {
+                Some(ref r) if r.synced => r.clone(),
+                _ => bail!("{:?} not in the object store after uploading", path),
+            };
+            self.save_block_map(candidate.ino,
+                                &BlockMap::full(self.transfer.block_size, record.size))?;
+            record.resident = false;
+            self.save(candidate.ino, before.as_ref(), Some(&record))?;
+            self.blocks.sync(&record_key(candidate.ino))?;
+            self.records.sync(&record_key(candidate.ino))?;
+        }
+
+        let record = match self.record(candidate.ino)? {
+            Some(ref r) if !r.resident => r.clone(),
+            _ => bail!("{:?} recalled while being evicted", path),
+        };
+        let mut map = self.block_map(candidate.ino)?;
+        for n in map.present() {
+            // forgotten before punched, so it is never read from a hole
+            map.remove(n);
+            self.save_block_map(candidate.ino, &map)?;
+            self.blocks.sync(&record_key(candidate.ino))?;
+            let (offset, len) = map.range(n, record.size);
+            stub::punch(path, offset, len)?;
+            if self.below_low_watermark()? {
+                debug!("evicted part of {:?}, {} blocks still cached", path, map.present().len());
+                return Ok(true);
+            }
+        }
+        debug!("evicted {:?} ({} bytes) to {}", path, record.size, record.key);
+        Ok(false)
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use super::super::store::LocalStore;
+
+    use tempfile;
+
+    pub const BLOCK: u64 = 4096;
+
+    pub fn content(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 % 251) as u8).collect()
+    }
+
+    /// Tiering of `backing` under `dir` onto a local store beside it, in
+    /// parts and blocks of `BLOCK`, evicting everything it is asked to.
+    pub fn tiering(dir: &Path, retention: Duration) -> (Tiering, Arc<LocalStore>) {
+        let backing = dir.join("backing");
+        fs::create_dir_all(&backing).unwrap();
+        let store = Arc::new(LocalStore::new(dir.join("store")).unwrap());
+        let tiering = Tiering::new(&backing,
+                                   store.clone(),
+                                   EvictionPolicy {
+                                       high_watermark: 100,
+                                       low_watermark: 0,
+                                       order: Order::Lru,
+                                   },
+                                   TransferPolicy {
+                                       part_size: BLOCK,
+                                       block_size: BLOCK,
+                                       threads: 2,
+                                   },
+                                   CompressionPolicy {
+                                       codec: None,
+                                       level: 0,
+                                       skip: Vec::new(),
+                                   },
+                                   false,
+                                   retention)
+            .unwrap();
+        (tiering, store)
+    }
+
+    pub fn file(tiering: &Tiering, name: &str, data: &[u8]) -> (PathBuf, u64) {
+        let path = tiering.backing.join(name);
+        fs::write(&path, data).unwrap();
+        let ino = fs::metadata(&path).unwrap().ino();
+        (path, ino)
+    }
+
+    /// Evict `path`, leaving its content only in the store.
+    pub fn evict(tiering: &Tiering, path: &Path, ino: u64) {
+        tiering.evict(&candidate(path, ino)).unwrap();
+    }
+
+    fn candidate(path: &Path, ino: u64) -> Candidate {
+        Candidate {
+            ino,
+            path: path.to_path_buf(),
+            resident: true,
+            atime: Timespec::new(0, 0),
+            hits: 0,
+        }
+    }
+
+    #[test]
+    fn evicted_file_leaves_a_placeholder() {
+        let dir = tempfile::tempdir().unwrap();
+        let (tiering, store) = tiering(dir.path(), Duration::from_secs(0));
+        let data = content(3 * BLOCK as usize + 100);
+        let (path, ino) = file(&tiering, "a", &data);
+
+        assert!(!tiering.evict(&candidate(&path, ino)).unwrap());
+        assert!(tiering.is_evicted(ino).unwrap());
+        assert_eq!(store.get(&object_key(ino)).unwrap(), data);
+        assert_eq!(fs::metadata(&path).unwrap().len(), data.len() as u64);
+        assert!(fs::read(&path).unwrap().iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn evicted_file_is_recalled_on_open_for_writing() {
+        let dir = tempfile::tempdir().unwrap();
+        let (tiering, _) = tiering(dir.path(), Duration::from_secs(0));
+        let data = content(3 * BLOCK as usize + 100);
+        let (path, ino) = file(&tiering, "a", &data);
+        tiering.evict(&candidate(&path, ino)).unwrap();
+
+        tiering.open(ino, &path, true, false).unwrap();
+        assert!(!tiering.is_evicted(ino).unwrap());
+        assert_eq!(fs::read(&path).unwrap(), data);
+    }
+
+    #[test]
+    fn truncating_open_of_evicted_file_recalls_nothing() {
+        let dir = tempfile::tempdir().unwrap();
+        let (tiering, _) = tiering(dir.path(), Duration::from_secs(0));
+        let (path, ino) = file(&tiering, "a", &content(2 * BLOCK as usize));
+        tiering.evict(&candidate(&path, ino)).unwrap();
+
+        tiering.open(ino, &path, true, true).unwrap();
+        let record = tiering.record(ino).unwrap().unwrap();
+        assert!(record.resident);
+        assert!(record.key.is_empty());
+        assert!(!record.synced);
+    }
+
+    #[test]
+    fn evicted_file_moves_without_its_content() {
+        let dir = tempfile::tempdir().unwrap();
+        let (tiering, store) = tiering(dir.path(), Duration::from_secs(0));
+        let data = content(2 * BLOCK as usize);
+        let (path, ino) = file(&tiering, "a", &data);
+        tiering.evict(&candidate(&path, ino)).unwrap();
+        let objects = store.list("").unwrap().len();
+
+        let moved = tiering.backing.join("b");
+        fs::rename(&path, &moved).unwrap();
+        assert!(tiering.is_evicted(ino).unwrap());
+        assert_eq!(store.list("").unwrap().len(), objects);
+        tiering.open(ino, &moved, true, false).unwrap();
+        assert_eq!(fs::read(&moved).unwrap(), data);
+    }
+
+    #[test]
+    fn collect_passes_over_extra_links() {
+        let dir = tempfile::tempdir().unwrap();
+        let (tiering, _) = tiering(dir.path(), Duration::from_secs(0));
+        let (path, ino) = file(&tiering, "a", &content(100));
+        fs::hard_link(&path, tiering.backing.join("b")).unwrap();
+        file(&tiering, "c", &content(100));
+
+        let mut found = Vec::new();
+        tiering.collect(&tiering.backing, &HashSet::new(), &mut HashSet::new(), &mut found)
+            .unwrap();
+        assert_eq!(found.len(), 2);
+        assert_eq!(found.iter().filter(|c| c.ino == ino).count(), 1);
+    }
+
+    #[test]
+    fn removal_of_a_claimed_file_waits_for_the_claim() {
+        let dir = tempfile::tempdir().unwrap();
+        let (tiering, store) = tiering(dir.path(), Duration::from_secs(0));
+        let (path, ino) = file(&tiering, "a", &content(100));
+        tiering.mark_dirty(ino).unwrap();
+        tiering.upload(ino, &path).unwrap();
+        let key = tiering.record(ino).unwrap().unwrap().key;
+
+        assert!(tiering.claim(ino));
+        fs::remove_file(&path).unwrap();
+        tiering.remove(ino, &path).unwrap();
+        assert!(tiering.record(ino).unwrap().is_some());
+        assert!(store.head(&key).unwrap().is_some());
+
+        tiering.unclaim(ino);
+        assert!(tiering.record(ino).unwrap().is_none());
+        assert!(store.head(&key).unwrap().is_none());
+        assert!(tiering.claim(ino));
+    }
+
+    #[test]
+    fn interrupted_upload_is_resumed() {
+        let dir = tempfile::tempdir().unwrap();
+        let (tiering, store) = tiering(dir.path(), Duration::from_secs(0));
+        let data = content(3 * BLOCK as usize + 100);
+        let (path, ino) = file(&tiering, "a", &data);
+        let metadata = fs::metadata(&path).unwrap();
+
+        // the first part was acknowledged before the interruption; what it
+        // holds shows whether it is sent again
+        let key = object_key(ino);
+        let upload_id = store.start_multipart(&key, metadata.len(), BLOCK).unwrap();
+        let tag = store.put_part(&key, &upload_id, 1, &[0xee; BLOCK as usize]).unwrap();
+        let upload = Multipart {
+            upload_id,
+            key: key.clone(),
+            part_size: BLOCK,
+            length: metadata.len(),
+            mtime: stub::mtime_of(&metadata),
+            parts: vec![(1, tag)],
+            compressed: false,
+            chunked: false,
+        };
+        tiering.uploads.put(&record_key(ino), &upload).unwrap();
+
+        tiering.upload(ino, &path).unwrap();
+        let object = store.get(&key).unwrap();
+        assert!(object[..BLOCK as usize].iter().all(|b| *b == 0xee));
+        assert_eq!(&object[BLOCK as usize..], &data[BLOCK as usize..]);
+        assert!(tiering.uploads.get::<Multipart>(&record_key(ino)).unwrap().is_none());
+        assert!(tiering.record(ino).unwrap().unwrap().synced);
+    }
+
+    #[test]
+    fn upload_of_changed_file_starts_over() {
+        let dir = tempfile::tempdir().unwrap();
+        let (tiering, store) = tiering(dir.path(), Duration::from_secs(0));
+        let data = content(3 * BLOCK as usize + 100);
+        let (path, ino) = file(&tiering, "a", &data);
+        let metadata = fs::metadata(&path).unwrap();
+
+        let key = object_key(ino);
+        let upload_id = store.start_multipart(&key, metadata.len() + 1, BLOCK).unwrap();
+        let tag = store.put_part(&key, &upload_id, 1, &[0xee; BLOCK as usize]).unwrap();
+        let upload = Multipart {
+            upload_id,
+            key: key.clone(),
+            part_size: BLOCK,
+            length: metadata.len() + 1,
+            mtime: stub::mtime_of(&metadata),
+            parts: vec![(1, tag)],
+            compressed: false,
+            chunked: false,
+        };
+        tiering.uploads.put(&record_key(ino), &upload).unwrap();
+
+        tiering.upload(ino, &path).unwrap();
+        assert_eq!(store.get(&key).unwrap(), data);
+    }
+
+    #[test]
+    fn parts_stay_within_the_part_limit() {
+        let dir = tempfile::tempdir().unwrap();
+        let (tiering, _) = tiering(dir.path(), Duration::from_secs(0));
+        assert_eq!(tiering.part_size(BLOCK * MAX_PARTS), BLOCK);
+        let part_size = tiering.part_size(BLOCK * MAX_PARTS + 1);
+        assert_eq!(part_size, 2 * BLOCK);
+        assert!((BLOCK * MAX_PARTS + 1).div_ceil(part_size) <= MAX_PARTS);
+    }
+
+    #[test]
+    fn blocks_read_are_cached_in_the_placeholder() {
+        let dir = tempfile::tempdir().unwrap();
+        let (tiering, _) = tiering(dir.path(), Duration::from_secs(0));
+        let data = content(3 * BLOCK as usize + 100);
+        let (path, ino) = file(&tiering, "a", &data);
+        tiering.evict(&candidate(&path, ino)).unwrap();
+
+        tiering.cache_range(ino, &path, BLOCK, 10).unwrap();
+        let cached = fs::read(&path).unwrap();
+        assert_eq!(&cached[BLOCK as usize..2 * BLOCK as usize],
+                   &data[BLOCK as usize..2 * BLOCK as usize]);
+        assert!(cached[..BLOCK as usize].iter().all(|b| *b == 0));
+        assert!(cached[2 * BLOCK as usize..].iter().all(|b| *b == 0));
+        assert!(tiering.is_evicted(ino).unwrap());
+    }
+
+    #[test]
+    fn removed_file_is_kept_locally_until_uploaded() {
+        let dir = tempfile::tempdir().unwrap();
+        let (tiering, store) = tiering(dir.path(), Duration::from_secs(3600));
+        let data = content(BLOCK as usize / 2);
+        let (path, ino) = file(&tiering, "a", &data);
+
+        tiering.preserve(ino, &path, &path, true).unwrap();
+        fs::remove_file(&path).unwrap();
+        tiering.remove(ino, &path).unwrap();
+
+        let versions = tiering.versions(None).unwrap();
+        assert_eq!(versions.len(), 1);
+        let id = versions[0].id();
+        assert!(versions[0].key.is_empty());
+        assert_eq!(versions[0].path, "a");
+        assert_eq!(tiering.kept_versions(), vec![(id.clone(), ino)]);
+        assert_eq!(fs::read(tiering.restore(&id, Some("b")).unwrap()).unwrap(), data);
+
+        tiering.upload_version(&id).unwrap();
+        let version = tiering.versions(None).unwrap().remove(0);
+        assert!(!version.key.is_empty());
+        assert_eq!(store.get(&version.key).unwrap(), data);
+        assert!(!tiering.kept_path(&id).exists());
+        assert!(tiering.kept_versions().is_empty());
+        assert_eq!(fs::read(tiering.restore(&id, Some("c")).unwrap()).unwrap(), data);
+    }
+
+    #[test]
+    fn uploaded_content_is_not_kept_again() {
+        let dir = tempfile::tempdir().unwrap();
+        let (tiering, _) = tiering(dir.path(), Duration::from_secs(3600));
+        let (path, ino) = file(&tiering, "a", &content(100));
+        tiering.upload(ino, &path).unwrap();
+
+        tiering.preserve(ino, &path, &path, false).unwrap();
+        assert!(tiering.versions(None).unwrap().is_empty());
+
+        fs::remove_file(&path).unwrap();
+        tiering.remove(ino, &path).unwrap();
+        let versions = tiering.versions(None).unwrap();
+        assert_eq!(versions.len(), 1);
+        assert!(!versions[0].key.is_empty());
+    }
+
+    #[test]
+    fn content_is_copied_before_it_is_truncated() {
+        let dir = tempfile::tempdir().unwrap();
+        let (tiering, _) = tiering(dir.path(), Duration::from_secs(3600));
+        let data = content(BLOCK as usize);
+        let (path, ino) = file(&tiering, "a", &data);
+
+        tiering.preserve(ino, &path, &path, false).unwrap();
+        fs::OpenOptions::new().write(true).open(&path).unwrap().set_len(10).unwrap();
+        let id = tiering.versions(None).unwrap()[0].id();
+        assert_eq!(fs::read(tiering.kept_path(&id)).unwrap(), data);
+        assert_eq!(fs::read(tiering.restore(&id, Some("b")).unwrap()).unwrap(), data);
+    }
+
+    #[test]
+    fn versions_expire_after_the_retention_period() {
+        let dir = tempfile::tempdir().unwrap();
+        let (tiering, store) = tiering(dir.path(), Duration::from_millis(1));
+        let (path, ino) = file(&tiering, "a", &content(100));
+        tiering.upload(ino, &path).unwrap();
+        let old = tiering.record(ino).unwrap().unwrap().key;
+        // versioned keys are told apart by the millisecond of upload
+        thread::sleep(Duration::from_millis(2));
+        fs::write(&path, content(200)).unwrap();
+        tiering.mark_dirty(ino).unwrap();
+        tiering.upload(ino, &path).unwrap();
+        assert_eq!(tiering.versions(None).unwrap()[0].key, old);
+
+        thread::sleep(Duration::from_millis(5));
+        tiering.expire_versions().unwrap();
+        assert!(tiering.versions(None).unwrap().is_empty());
+        assert!(store.head(&old).unwrap().is_none());
+        assert!(store.head(&tiering.record(ino).unwrap().unwrap().key).unwrap().is_some());
+    }
+}
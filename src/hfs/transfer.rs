@@ -0,0 +1,130 @@
+use errors::*;
+
+use super::store::ObjectStore;
+use super::sys;
+
+use std::collections::HashSet;
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+
+/// How file content moves to and from the object store.
+#[derive(Debug, Clone)]
+pub struct TransferPolicy {
+    /// files longer than this are uploaded in parts of this size
+    pub part_size: u64,
+    /// evicted files are recalled and cached in blocks of this size
+    pub block_size: u64,
+    /// parts or blocks of one file moved at once
+    pub threads: usize,
+}
+
+/// A multipart upload under way, persisted after every part so that an
+/// interrupted upload carries on where it stopped.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Multipart {
+    pub upload_id: String,
+    /// object being uploaded to; empty for `object_key` of the file
+    #[serde(default)]
+    pub key: String,
+    pub part_size: u64,
+    /// length and mtime of the file being uploaded; any change starts over
+    pub length: u64,
+    pub mtime: (i64, i32),
+    /// number and tag of the parts already in the store
+    pub parts: Vec<(u32, String)>,
+    /// sent through compression
+    #[serde(default)]
+    pub compressed: bool,
+    /// sent through deduplication
+    #[serde(default)]
+    pub chunked: bool,
+}
+
+/// Run `job` for each of `0..count` on up to `threads` threads. Stops
+/// handing out work at the first error, which is returned.
+fn parallel<F>(count: u64, threads: usize, job: F) -> Result<()>
+    where F: Fn(u64) -> Result<()> + Sync
+{
+    let next = AtomicU64::new(0);
+    let failed = Mutex::new(None);
+    thread::scope(|scope| {
+        for _ in 0..threads.max(1).min(count as usize) {
+            scope.spawn(|| loop {
+                if failed.lock().unwrap().is_some() {
+                    return;
+                }
+                let n = next.fetch_add(1, Ordering::SeqCst);
+                if n >= count {
+                    return;
+                }
+                if let Err(e) = job(n) {
+                    failed.lock().unwrap().get_or_insert(e);
+                }
+            });
+        }
+    });
+    match failed.into_inner().unwrap() {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
+}
+
+/// Send the parts of `file` missing from `upload` and join them into the
+/// object under `key`. `save` is called with the progress after each part.
+pub fn upload_parts<F>(store: &dyn ObjectStore,
+                       key: &str,
+                       file: &File,
+                       upload: Multipart,
+                       threads: usize,
+                       save: F)
+                       -> Result<()>
+    where F: Fn(&Multipart) -> Result<()> + Sync
+{
+    let part_size = upload.part_size;
+    let length = upload.length;
+    let upload_id = upload.upload_id.clone();
+    let done: HashSet<u32> = upload.parts.iter().map(|p| p.0).collect();
+    let progress = Mutex::new(upload);
+
+    parallel(length.div_ceil(part_size), threads, |n| {
+        let number = n as u32 + 1;
+        if done.contains(&number) {
+            return Ok(());
+        }
+        let offset = n * part_size;
+        let mut buffer = vec![0u8; part_size.min(length - offset) as usize];
+        let got = sys::read_full_at(file, &mut buffer, offset)
+            .chain_err(|| format!("reading part {} for {}", number, key))?;
+        if got < buffer.len() {
+            bail!("file for {} shrank while uploading", key);
+        }
+        let tag = store.put_part(key, &upload_id, number, &buffer)?;
+        let mut progress = progress.lock().unwrap();
+        progress.parts.push((number, tag));
+        save(&progress)
+    })?;
+
+    let upload = progress.into_inner().unwrap();
+    store.finish_multipart(key, &upload.upload_id, &upload.parts)
+}
+
+/// Write the `(offset, len)` ranges of the object under `key` into the
+/// same place in `file`, fetching up to `threads` at once.
+pub fn download(store: &dyn ObjectStore,
+                key: &str,
+                file: &File,
+                ranges: &[(u64, u64)],
+                threads: usize)
+                -> Result<()> {
+    parallel(ranges.len() as u64, threads, |n| {
+        let (offset, len) = ranges[n as usize];
+        let data = store.get_range(key, offset, len)?;
+        if (data.len() as u64) < len {
+            bail!("object {} ends before {}", key, offset + len);
+        }
+        file.write_all_at(&data, offset).chain_err(|| format!("writing range of {}", key))
+    })
+}
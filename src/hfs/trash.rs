@@ -0,0 +1,323 @@
+use errors::*;
+
+use super::kv::KvStore;
+use super::sys;
+use super::tier::{self, Tiering, STATE_DIR};
+
+use libc;
+
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+// Files, symlinks and empty directories removed through the mount are moved
+// into the trash directory under the state directory, each named by its id,
+// rather than deleted. They keep their inode, so the tiering record and any
+// cold copy stay as they were until the entry is purged, which removes it
+// as unlink would have.
+
+/// Directory trashed entries are moved into.
+const TRASH: &str = "trash";
+/// Index of the entries in it, by id.
+const INDEX: &str = "trashed";
+
+/// Where the trash of the filesystem backed by `backing` keeps entries.
+pub fn dir_of(backing: &Path) -> PathBuf {
+    backing.join(STATE_DIR).join(TRASH)
+}
+
+/// An entry removed through the mount and kept in the trash.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Trashed {
+    pub ino: u64,
+    /// where it was, relative to the root of the filesystem
+    pub path: String,
+    /// milliseconds since the epoch at which it was removed
+    pub deleted: u64,
+    pub dir: bool,
+}
+
+impl Trashed {
+    /// Names the entry in listings and to restore it.
+    pub fn id(&self) -> String {
+        format!("{:016x}-{:016x}", self.ino, self.deleted)
+    }
+}
+
+pub struct Trash {
+    backing: PathBuf,
+    dir: PathBuf,
+    index: KvStore,
+    tiering: Arc<Tiering>,
+    /// how long entries are kept before they are purged
+    retention: Duration,
+    /// held while purging, which the expiry thread and the mount both do
+    purging: Mutex<()>,
+}
+
+impl Trash {
+    pub fn open(backing: &Path, tiering: Arc<Tiering>, retention: Duration) -> Result<Trash> {
+        let dir = dir_of(backing);
+        fs::create_dir_all(&dir).chain_err(|| format!("creating {:?}", dir))?;
+        Ok(Trash {
+            backing: backing.to_path_buf(),
+            dir,
+            index: KvStore::open(backing.join(STATE_DIR).join(INDEX))?,
+            tiering,
+            retention,
+            purging: Mutex::new(()),
+        })
+    }
+
+    /// Move the entry at `path` into the trash instead of removing it.
+    pub fn put(&self, path: &Path, metadata: &fs::Metadata) -> Result<Trashed> {
+        let mut trashed = Trashed {
+            ino: metadata.ino(),
+            path: path.strip_prefix(&self.backing).unwrap_or(path).to_string_lossy().into_owned(),
+            deleted: tier::now_millis(),
+            dir: metadata.is_dir(),
+        };
+        // hard links to one inode removed within a millisecond would share
+        // an id
+        while self.index.get::<Trashed>(&trashed.id())?.is_some() ||
+              fs::symlink_metadata(self.dir.join(trashed.id())).is_ok() {
+            trashed.deleted += 1;
+        }
+        // indexed first, so a crash never leaves an entry nothing purges
+        self.index.put(&trashed.id(), &trashed)?;
+        if let Err(e) = fs::rename(path, self.dir.join(trashed.id())) {
+            self.index.delete(&trashed.id())?;
+            return Err(e).chain_err(|| format!("moving {:?} to the trash", path));
+        }
+        if metadata.is_file() && metadata.nlink() <= 1 {
+            // out of the namespace, there is nowhere to upload it from
+            self.tiering.unqueue(trashed.ino)?;
+        }
+        debug!("trashed {} as {}", trashed.path, trashed.id());
+        Ok(trashed)
+    }
+
+    /// Entries removed from at or under `path`, or from anywhere, by path
+    /// and time of removal.
+    pub fn list(&self, path: Option<&str>) -> Result<Vec<Trashed>> {
+        let path = path.map(|p| p.trim_matches('/'));
+        let mut found = Vec::new();
+        for id in self.index.keys()? {
+            if let Some(trashed) = self.index.get::<Trashed>(&id)? {
+                let wanted = path.is_none_or(|p| {
+                    p.is_empty() || trashed.path == p ||
+                    trashed.path.starts_with(p) && trashed.path[p.len()..].starts_with('/')
+                });
+                if wanted {
+                    found.push(trashed);
+                }
+            }
+        }
+        found.sort_by(|a, b| (&a.path, a.deleted).cmp(&(&b.path, b.deleted)));
+        Ok(found)
+    }
+
+    /// Move entry `id` back to `to`, relative to the root of the filesystem,
+    /// or to where it was, creating missing directories on the way. Returns
+    /// the backing path it is at.
+    pub fn restore(&self, id: &str, to: Option<&str>) -> Result<PathBuf> {
+        let trashed = match self.index.get::<Trashed>(id)? {
+            Some(t) => t,
+            None => bail!("nothing in the trash as {}", id),
+        };
+        let relative = to.unwrap_or(&trashed.path).trim_start_matches('/');
+        if relative.is_empty() || relative.split('/').any(|c| c == ".." || c == STATE_DIR) {
+            bail!("cannot restore to {:?}", relative);
+        }
+        let target = self.backing.join(relative);
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent).chain_err(|| format!("creating {:?}", parent))?;
+        }
+        sys::rename(&self.dir.join(id), &target, libc::RENAME_NOREPLACE)
+            .chain_err(|| format!("restoring {} to {:?}", id, target))?;
+        self.index.delete(id)?;
+        if !trashed.dir && fs::symlink_metadata(&target).is_ok_and(|m| m.is_file()) {
+            // taken out of the journal when trashed: queue it again unless
+            // the object store holds its content
+            let uploaded = self.tiering
+                .record(trashed.ino)?
+                .is_some_and(|r| !r.key.is_empty() && (!r.resident || r.synced));
+            if !uploaded {
+                self.tiering.mark_dirty(trashed.ino)?;
+            }
+        }
+        Ok(target)
+    }
+
+    /// Purge entries that have been kept for the retention period.
+    pub fn expire(&self) -> Result<()> {
+        let now = tier::now_millis();
+        let keep = self.retention.as_secs() * 1000 + u64::from(self.retention.subsec_millis());
+        let _purging = self.purging.lock().unwrap();
+        for id in self.index.keys()? {
+            if let Some(trashed) = self.index.get::<Trashed>(&id)? {
+                if now >= trashed.deleted + keep {
+                    self.purge(&trashed)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Purge entries, oldest first, while the backing path is past its high
+    /// watermark and until it reaches the low one.
+    pub fn make_room(&self) -> Result<()> {
+        if !self.tiering.needs_space()? {
+            return Ok(());
+        }
+        let _purging = self.purging.lock().unwrap();
+        let mut entries = self.list(None)?;
+        entries.sort_by_key(|t| t.deleted);
+        for trashed in entries {
+            self.purge(&trashed)?;
+            if self.tiering.below_low_watermark()? {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove a trashed entry for good, as unlink or rmdir would have.
+    fn purge(&self, trashed: &Trashed) -> Result<()> {
+        let path = self.dir.join(trashed.id());
+        match fs::symlink_metadata(&path) {
+            Ok(ref m) if m.is_dir() => {
+                fs::remove_dir_all(&path).chain_err(|| format!("purging {:?}", path))?;
+            }
+            Ok(m) => {
+                let last = m.is_file() && m.nlink() <= 1;
+                // versions are kept under where the file was
+                let was = self.backing.join(&trashed.path);
+                if last {
+                    self.tiering.preserve(m.ino(), &path, &was, true)?;
+                }
+                fs::remove_file(&path).chain_err(|| format!("purging {:?}", path))?;
+                if last {
+                    self.tiering.remove(m.ino(), &was)?;
+                }
+            }
+            Err(_) => warn!("trashed {} is gone", trashed.id()),
+        }
+        self.index.delete(&trashed.id())?;
+        debug!("purged {} from the trash", trashed.path);
+        Ok(())
+    }
+}
+
+/// Purge entries older than the retention period, once a minute.
+pub fn start_expiry(trash: Arc<Trash>) -> Result<()> {
+    thread::Builder::new()
+        .name("trash-expiry".to_string())
+        .spawn(move || loop {
+            if let Err(e) = trash.expire() {
+                warn!("purging trash: {}", e);
+            }
+            thread::sleep(Duration::from_secs(60));
+        })
+        .chain_err(|| "starting trash expiry")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::store::{LocalStore, ObjectStore};
+    use super::super::tier::tests::{content, file, tiering};
+
+    use tempfile;
+
+    fn trash(dir: &Path, retention: Duration) -> (Trash, Arc<Tiering>, Arc<LocalStore>) {
+        let (tiering, store) = tiering(dir, Duration::from_secs(0));
+        let tiering = Arc::new(tiering);
+        let trash = Trash::open(&dir.join("backing"), tiering.clone(), retention).unwrap();
+        (trash, tiering, store)
+    }
+
+    #[test]
+    fn trashed_file_is_restored_where_it_was_or_elsewhere() {
+        let dir = tempfile::tempdir().unwrap();
+        let (trash, tiering, _) = trash(dir.path(), Duration::from_secs(3600));
+        let (path, _) = file(&tiering, "a", &content(100));
+
+        let trashed = trash.put(&path, &fs::metadata(&path).unwrap()).unwrap();
+        assert!(!path.exists());
+        assert_eq!(trash.list(None).unwrap().len(), 1);
+        assert!(trash.list(Some("b")).unwrap().is_empty());
+        assert!(trash.restore(&trashed.id(), Some("../b")).is_err());
+        assert_eq!(trash.restore(&trashed.id(), None).unwrap(), path);
+        assert_eq!(fs::read(&path).unwrap(), content(100));
+        assert!(trash.list(None).unwrap().is_empty());
+
+        let trashed = trash.put(&path, &fs::metadata(&path).unwrap()).unwrap();
+        let moved = trash.restore(&trashed.id(), Some("d/b")).unwrap();
+        assert_eq!(moved, dir.path().join("backing/d/b"));
+        assert_eq!(fs::read(&moved).unwrap(), content(100));
+    }
+
+    #[test]
+    fn restore_does_not_replace_what_took_the_place() {
+        let dir = tempfile::tempdir().unwrap();
+        let (trash, tiering, _) = trash(dir.path(), Duration::from_secs(3600));
+        let (path, _) = file(&tiering, "a", &content(100));
+        let trashed = trash.put(&path, &fs::metadata(&path).unwrap()).unwrap();
+        fs::write(&path, content(10)).unwrap();
+
+        assert!(trash.restore(&trashed.id(), None).is_err());
+        assert_eq!(fs::read(&path).unwrap(), content(10));
+        assert_eq!(trash.list(None).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn restored_file_not_uploaded_is_queued_again() {
+        let dir = tempfile::tempdir().unwrap();
+        let (trash, tiering, _) = trash(dir.path(), Duration::from_secs(3600));
+        let (path, ino) = file(&tiering, "a", &content(100));
+        tiering.mark_dirty(ino).unwrap();
+
+        let trashed = trash.put(&path, &fs::metadata(&path).unwrap()).unwrap();
+        assert!(!tiering.queued(ino));
+        trash.restore(&trashed.id(), None).unwrap();
+        assert!(tiering.queued(ino));
+    }
+
+    #[test]
+    fn links_trashed_together_get_ids_of_their_own() {
+        let dir = tempfile::tempdir().unwrap();
+        let (trash, tiering, _) = trash(dir.path(), Duration::from_secs(3600));
+        let (a, _) = file(&tiering, "a", &content(100));
+        let b = dir.path().join("backing/b");
+        fs::hard_link(&a, &b).unwrap();
+
+        let first = trash.put(&a, &fs::metadata(&a).unwrap()).unwrap();
+        let second = trash.put(&b, &fs::metadata(&b).unwrap()).unwrap();
+        assert!(first.id() != second.id());
+        assert_eq!(trash.list(None).unwrap().len(), 2);
+        assert_eq!(trash.restore(&first.id(), None).unwrap(), a);
+        assert_eq!(trash.restore(&second.id(), None).unwrap(), b);
+    }
+
+    #[test]
+    fn expired_entries_are_purged_with_their_objects() {
+        let dir = tempfile::tempdir().unwrap();
+        let (trash, tiering, store) = trash(dir.path(), Duration::from_millis(1));
+        let (path, ino) = file(&tiering, "a", &content(100));
+        tiering.upload(ino, &path).unwrap();
+        let key = tiering.record(ino).unwrap().unwrap().key;
+        trash.put(&path, &fs::metadata(&path).unwrap()).unwrap();
+
+        thread::sleep(Duration::from_millis(5));
+        trash.expire().unwrap();
+        assert!(trash.list(None).unwrap().is_empty());
+        assert_eq!(fs::read_dir(dir_of(&dir.path().join("backing"))).unwrap().count(), 0);
+        assert!(tiering.record(ino).unwrap().is_none());
+        assert!(store.head(&key).unwrap().is_none());
+    }
+}
@@ -0,0 +1,177 @@
+use errors::*;
+
+use super::inode::InodeTable;
+use super::tier::{self, Tiering};
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver};
+use std::thread;
+use std::time::Duration;
+
+/// How the write-back queue runs.
+#[derive(Debug, Clone)]
+pub struct UploadPolicy {
+    /// how long a closed file must go unwritten before it is uploaded
+    pub settle: Duration,
+    /// uploads running at once
+    pub threads: usize,
+}
+
+/// Uploads files from the dirty journal in the background, once they are
+/// closed and have settled, and versions still held under the state
+/// directory.
+///
+/// A dispatcher thread looks through the journal every second and hands
+/// settled files to a fixed pool of workers. Paths are resolved through the
+/// mount's inode table.
+pub struct Uploader {
+    open: Arc<Mutex<HashMap<u64, usize>>>,
+    uploading: Arc<AtomicUsize>,
+}
+
+/// What a worker is handed.
+enum Job {
+    /// a file from the journal, by inode and path
+    File(u64, PathBuf),
+    /// a version kept under the state directory, by inode and id
+    Version(u64, String),
+}
+
+impl Uploader {
+    pub fn start(inodes: Arc<Mutex<InodeTable>>,
+                 tiering: Arc<Tiering>,
+                 policy: UploadPolicy)
+                 -> Result<Uploader> {
+        if policy.threads == 0 {
+            bail!("at least one upload thread is needed");
+        }
+        let open = Arc::new(Mutex::new(HashMap::new()));
+        let uploading = Arc::new(AtomicUsize::new(0));
+
+        // no buffer: the dispatcher waits for a free worker rather than
+        // claiming files it cannot start on
+        let (tx, rx) = sync_channel::<Job>(0);
+        let rx = Arc::new(Mutex::new(rx));
+        for n in 0..policy.threads {
+            let rx = rx.clone();
+            let tiering = tiering.clone();
+            let uploading = uploading.clone();
+            thread::Builder::new()
+                .name(format!("upload-{}", n))
+                .spawn(move || work(&rx, &tiering, &uploading))
+                .chain_err(|| "starting upload thread")?;
+        }
+
+        let dispatch_open = open.clone();
+        let settle = policy.settle.as_secs() * 1000 + u64::from(policy.settle.subsec_millis());
+        thread::Builder::new()
+            .name("upload-dispatch".to_string())
+            .spawn(move || loop {
+                thread::sleep(Duration::from_secs(1));
+                let now = tier::now_millis();
+                for (ino, since) in tiering.pending() {
+                    if now < since + settle || dispatch_open.lock().unwrap().contains_key(&ino) {
+                        continue;
+                    }
+                    if !tiering.claim(ino) {
+                        continue;
+                    }
+                    let resolved = inodes.lock().unwrap().path(ino);
+                    let path = match resolved {
+                        Ok(Some(p)) => p,
+                        Ok(None) => {
+                            debug!("inode {} waiting for upload not found", ino);
+                            tiering.unclaim(ino);
+                            continue;
+                        }
+                        Err(e) => {
+                            error!("resolving inode {}: {}", ino, e);
+                            tiering.unclaim(ino);
+                            continue;
+                        }
+                    };
+                    if tx.send(Job::File(ino, path)).is_err() {
+                        return;
+                    }
+                }
+                for (id, ino) in tiering.kept_versions() {
+                    if !tiering.claim(ino) {
+                        continue;
+                    }
+                    if tx.send(Job::Version(ino, id)).is_err() {
+                        return;
+                    }
+                }
+            })
+            .chain_err(|| "starting upload dispatcher")?;
+
+        Ok(Uploader { open, uploading })
+    }
+
+    /// An uploader that never uploads, for a read-only mount.
+    pub fn idle() -> Uploader {
+        Uploader {
+            open: Arc::new(Mutex::new(HashMap::new())),
+            uploading: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// A handle on `ino` was opened; it is not uploaded until all are closed.
+    pub fn opened(&self, ino: u64) {
+        *self.open.lock().unwrap().entry(ino).or_insert(0) += 1;
+    }
+
+    pub fn closed(&self, ino: u64) {
+        let mut open = self.open.lock().unwrap();
+        let gone = match open.get_mut(&ino) {
+            Some(n) => {
+                *n -= 1;
+                *n == 0
+            }
+            None => false,
+        };
+        if gone {
+            open.remove(&ino);
+        }
+    }
+
+    /// Number of uploads under way.
+    pub fn uploading(&self) -> usize {
+        self.uploading.load(Ordering::SeqCst)
+    }
+}
+
+fn work(rx: &Mutex<Receiver<Job>>, tiering: &Tiering, uploading: &AtomicUsize) {
+    loop {
+        let job = match rx.lock().unwrap().recv() {
+            Ok(job) => job,
+            Err(_) => return,
+        };
+        uploading.fetch_add(1, Ordering::SeqCst);
+        let ino = match job {
+            Job::File(ino, path) => {
+                debug!("uploading {:?}, {} waiting", path, tiering.queue_depth());
+                if let Err(e) = tiering.upload(ino, &path) {
+                    warn!("unable to upload {:?}: {}", path, e);
+                    // try again once it settles anew
+                    if let Err(e) = tiering.mark_dirty(ino) {
+                        error!("recording failed upload of inode {}: {}", ino, e);
+                    }
+                }
+                ino
+            }
+            Job::Version(ino, id) => {
+                // left where it is to try again on the next pass
+                if let Err(e) = tiering.upload_version(&id) {
+                    warn!("unable to upload version {}: {}", id, e);
+                }
+                ino
+            }
+        };
+        tiering.unclaim(ino);
+        uploading.fetch_sub(1, Ordering::SeqCst);
+    }
+}
@@ -0,0 +1,49 @@
+use errors::*;
+
+use super::tier::Tiering;
+
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// Content a file held before it was replaced, truncated or removed, kept
+/// in the object store until its retention runs out.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Version {
+    pub ino: u64,
+    /// where the file was, relative to the root of the filesystem
+    pub path: String,
+    /// milliseconds since the epoch at which it stopped being current
+    pub retired: u64,
+    /// empty while the content is held under the state directory, waiting
+    /// to be uploaded
+    pub key: String,
+    pub size: u64,
+    /// mtime of the file when this content was uploaded
+    pub mtime: (i64, i32),
+    #[serde(default)]
+    pub compressed: bool,
+    #[serde(default)]
+    pub chunked: bool,
+}
+
+impl Version {
+    /// Names the version in listings and to restore it.
+    pub fn id(&self) -> String {
+        format!("{:016x}-{:016x}", self.ino, self.retired)
+    }
+}
+
+/// Drop versions older than the retention period, once a minute.
+pub fn start_expiry(tiering: Arc<Tiering>) -> Result<()> {
+    thread::Builder::new()
+        .name("version-expiry".to_string())
+        .spawn(move || loop {
+            if let Err(e) = tiering.expire_versions() {
+                warn!("expiring versions: {}", e);
+            }
+            thread::sleep(Duration::from_secs(60));
+        })
+        .chain_err(|| "starting version expiry")?;
+    Ok(())
+}
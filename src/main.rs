@@ -16,9 +16,24 @@ extern crate clap;
 extern crate time;
 extern crate fuse;
 extern crate libc;
+extern crate rand;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
+extern crate rusoto_core;
+extern crate rusoto_s3;
+extern crate openssl;
+extern crate zstd;
+extern crate lz4_flex;
+#[cfg(test)]
+extern crate tempfile;
 
 mod hfs;
 
+use std::sync::Arc;
+use std::time::Duration;
+
 fn main() {
 
     if let Err(ref e) = run() {
@@ -43,12 +58,24 @@ fn main() {
 
 }
 
+const HOUR: u64 = 60 * 60;
+const DAY: u64 = 24 * HOUR;
+
+/// The whole number `value` given for `flag`, in units of `unit`.
+fn scaled(value: &str, flag: &str, unit: u64) -> Result<u64> {
+    let n: u64 = value.parse().chain_err(|| format!("parsing {}", flag))?;
+    match n.checked_mul(unit) {
+        Some(scaled) => Ok(scaled),
+        None => bail!("parsing {}: {} is too large", flag, value),
+    }
+}
+
 fn run() -> Result<()> {
 
     env_logger::init().unwrap();
     trace!("Starting");
 
-    use clap::{Arg, App};
+    use clap::{Arg, App, SubCommand};
 
     let app = App::new("S3 Hierarchical Filesystem")
         .version("0.1.0")
@@ -61,16 +88,407 @@ fn run() -> Result<()> {
         .arg(Arg::with_name("BACKINGPATH")
             .default_value("/tmp/back")
             // .required(true)
-            .help("path where underlying files will be "));
+            .help("path where underlying files will be "))
+        .arg(Arg::with_name("store")
+            .long("store")
+            .value_name("URL")
+            .takes_value(true)
+            .default_value("file:///tmp/store")
+            .help("object store for cold data: file:///path or s3://bucket/prefix"))
+        .arg(Arg::with_name("high_watermark")
+            .long("high-watermark")
+            .value_name("PERCENT")
+            .takes_value(true)
+            .default_value("90")
+            .help("backing path usage at which files start being evicted"))
+        .arg(Arg::with_name("low_watermark")
+            .long("low-watermark")
+            .value_name("PERCENT")
+            .takes_value(true)
+            .default_value("75")
+            .help("backing path usage eviction works down to"))
+        .arg(Arg::with_name("evict_order")
+            .long("evict-order")
+            .value_name("ORDER")
+            .takes_value(true)
+            .possible_values(&["lru", "lfu"])
+            .default_value("lru")
+            .help("which files to evict first"))
+        .arg(Arg::with_name("cold_capacity")
+            .long("cold-capacity")
+            .value_name("GIB")
+            .takes_value(true)
+            .default_value("1048576")
+            .help("capacity the object store adds to the backing path, as reported by statfs"))
+        .arg(Arg::with_name("durability")
+            .long("durability")
+            .value_name("MODE")
+            .takes_value(true)
+            .possible_values(&["local", "object"])
+            .default_value("local")
+            .help("whether fsync waits for the file to reach the object store"))
+        .arg(Arg::with_name("settle")
+            .long("settle")
+            .value_name("SECONDS")
+            .takes_value(true)
+            .default_value("30")
+            .help("how long a closed file must go unwritten before it is uploaded"))
+        .arg(Arg::with_name("upload_threads")
+            .long("upload-threads")
+            .value_name("N")
+            .takes_value(true)
+            .default_value("4")
+            .help("uploads to run at once"))
+        .arg(Arg::with_name("part_size")
+            .long("part-size")
+            .value_name("MIB")
+            .takes_value(true)
+            .default_value("64")
+            .help("files larger than this are uploaded in parts of this size (S3 needs 5 or more)"))
+        .arg(Arg::with_name("transfer_threads")
+            .long("transfer-threads")
+            .value_name("N")
+            .takes_value(true)
+            .default_value("4")
+            .help("parts of one file moved at once"))
+        .arg(Arg::with_name("block_size")
+            .long("block-size")
+            .value_name("MIB")
+            .takes_value(true)
+            .default_value("4")
+            .help("evicted files are fetched back and cached in blocks of this size"))
+        .arg(Arg::with_name("readahead")
+            .long("readahead")
+            .value_name("BLOCKS")
+            .takes_value(true)
+            .default_value("8")
+            .help("blocks of an evicted file fetched ahead of a sequential reader, 0 for none"))
+        .arg(Arg::with_name("prefetch_threads")
+            .long("prefetch-threads")
+            .value_name("N")
+            .takes_value(true)
+            .default_value("4")
+            .help("prefetches to run at once"))
+        .arg(Arg::with_name("prefetch_tools")
+            .long("prefetch-tools")
+            .value_name("COMMANDS")
+            .takes_value(true)
+            .default_value("tar,rsync,cp")
+            .help("commands whose directory listings prefetch the evicted files listed"))
+        .arg(Arg::with_name("compress")
+            .long("compress")
+            .value_name("CODEC")
+            .takes_value(true)
+            .possible_values(&["none", "zstd", "lz4"])
+            .default_value("none")
+            .help("compress files on their way to the object store"))
+        .arg(Arg::with_name("compress_level")
+            .long("compress-level")
+            .value_name("LEVEL")
+            .takes_value(true)
+            .default_value("3")
+            .help("zstd compression level"))
+        .arg(Arg::with_name("compress_skip")
+            .long("compress-skip")
+            .value_name("EXTENSIONS")
+            .takes_value(true)
+            .default_value("gz,tgz,bz2,xz,zst,lz4,zip,7z,rar,jpg,jpeg,png,gif,webp,mp3,mp4,mkv,mov")
+            .help("extensions of files stored uncompressed because they already are"))
+        .arg(Arg::with_name("dedup")
+            .long("dedup")
+            .help("store file content as chunks shared between files, compressed with --compress"))
+        .arg(Arg::with_name("keep_versions")
+            .long("keep-versions")
+            .value_name("DAYS")
+            .takes_value(true)
+            .default_value("0")
+            .help("keep content replaced or removed in the object store this long; \
+                   files are uploaded before they are truncated or removed"))
+        .arg(Arg::with_name("trash")
+            .long("trash")
+            .value_name("DAYS")
+            .takes_value(true)
+            .default_value("0")
+            .help("keep removed files and directories in a trash this long, or until the \
+                   space is needed, rather than removing them at once"))
+        .arg(Arg::with_name("gc_grace")
+            .long("gc-grace")
+            .value_name("HOURS")
+            .takes_value(true)
+            .default_value("24")
+            .help("how long an object must have been found unreferenced before gc deletes it"))
+        .arg(Arg::with_name("gc_every")
+            .long("gc-every")
+            .value_name("HOURS")
+            .takes_value(true)
+            .default_value("0")
+            .help("collect garbage in the object store this often while mounted, 0 for never"))
+        .arg(Arg::with_name("key_file")
+            .long("key-file")
+            .value_name("PATH")
+            .takes_value(true)
+            .help("encrypt the object store with this 32-byte key, raw or hex; \
+                   S3HFS_KEY may hold the hex instead"))
+        .arg(Arg::with_name("snapshot")
+            .long("snapshot")
+            .value_name("NAME")
+            .takes_value(true)
+            .help("mount this snapshot read-only instead of the live filesystem"))
+        .subcommand(SubCommand::with_name("gc")
+            .about("find objects in the store nothing refers to, as kind, key, size and state, \
+                    and delete those past --gc-grace")
+            .arg(Arg::with_name("dry_run")
+                .long("dry-run")
+                .help("report only, deleting nothing")))
+        .subcommand(SubCommand::with_name("fsck")
+            .about("check the backing path, metadata and object store agree, printing a JSON \
+                    object per finding; run while not mounted")
+            .arg(Arg::with_name("repair")
+                .long("repair")
+                .help("settle what can be settled from what is still there"))
+            .arg(Arg::with_name("checksums")
+                .long("checksums")
+                .help("also compare the content of resident files with their objects")))
+        .subcommand(SubCommand::with_name("snapshot")
+            .about("take a read-only snapshot of the whole filesystem, through the mount if \
+                    it is mounted")
+            .arg(Arg::with_name("NAME").required(true).help("name of the snapshot"))
+            .arg(Arg::with_name("delete")
+                .long("delete")
+                .help("delete the snapshot, and content only it held, instead")))
+        .subcommand(SubCommand::with_name("snapshots")
+            .about("list snapshots, as name, time, files and bytes"))
+        .subcommand(SubCommand::with_name("versions")
+            .about("list versions kept of files, as id, time, size and path")
+            .arg(Arg::with_name("PATH").help("file or directory, relative to the root")))
+        .subcommand(SubCommand::with_name("trash")
+            .about("list what is in the trash, as id, time removed and path")
+            .arg(Arg::with_name("PATH").help("where it was removed from, relative to the root")))
+        .subcommand(SubCommand::with_name("untrash")
+            .about("move an entry out of the trash")
+            .arg(Arg::with_name("ID").required(true).help("entry, as listed"))
+            .arg(Arg::with_name("to")
+                .long("to")
+                .value_name("PATH")
+                .takes_value(true)
+                .help("where to put it, relative to the root; where it was by default")))
+        .subcommand(SubCommand::with_name("restore")
+            .about("write a version back as a new file")
+            .arg(Arg::with_name("ID").required(true).help("version, as listed"))
+            .arg(Arg::with_name("to")
+                .long("to")
+                .value_name("PATH")
+                .takes_value(true)
+                .help("where to write it, relative to the root; where it was by default")));
 
     let cmdline = app.get_matches();
     let mountpath = cmdline.value_of("MOUNTPATH").unwrap();
     let backingpath = cmdline.value_of("BACKINGPATH").unwrap();
+    let mut store = hfs::store::open(cmdline.value_of("store").unwrap())?;
+    if let Some(key) = hfs::store::load_key(cmdline.value_of("key_file"))? {
+        store = Arc::new(hfs::store::EncryptedStore::new(store, key)?);
+    }
+    let policy = hfs::tier::EvictionPolicy {
+        high_watermark: cmdline.value_of("high_watermark")
+            .unwrap()
+            .parse()
+            .chain_err(|| "parsing --high-watermark")?,
+        low_watermark: cmdline.value_of("low_watermark")
+            .unwrap()
+            .parse()
+            .chain_err(|| "parsing --low-watermark")?,
+        order: match cmdline.value_of("evict_order").unwrap() {
+            "lfu" => hfs::tier::Order::Lfu,
+            _ => hfs::tier::Order::Lru,
+        },
+    };
+    let options = hfs::Options {
+        policy,
+        cold_capacity: scaled(cmdline.value_of("cold_capacity").unwrap(),
+                              "--cold-capacity",
+                              1 << 30)?,
+        durability: match cmdline.value_of("durability").unwrap() {
+            "object" => hfs::tier::Durability::Object,
+            _ => hfs::tier::Durability::Local,
+        },
+        upload: hfs::UploadPolicy {
+            settle: Duration::from_secs(cmdline.value_of("settle")
+                .unwrap()
+                .parse()
+                .chain_err(|| "parsing --settle")?),
+            threads: cmdline.value_of("upload_threads")
+                .unwrap()
+                .parse()
+                .chain_err(|| "parsing --upload-threads")?,
+        },
+        transfer: hfs::TransferPolicy {
+            part_size: scaled(cmdline.value_of("part_size").unwrap(), "--part-size", 1 << 20)?,
+            block_size: scaled(cmdline.value_of("block_size").unwrap(), "--block-size", 1 << 20)?,
+            threads: cmdline.value_of("transfer_threads")
+                .unwrap()
+                .parse()
+                .chain_err(|| "parsing --transfer-threads")?,
+        },
+        prefetch: hfs::PrefetchPolicy {
+            blocks: cmdline.value_of("readahead")
+                .unwrap()
+                .parse()
+                .chain_err(|| "parsing --readahead")?,
+            threads: cmdline.value_of("prefetch_threads")
+                .unwrap()
+                .parse()
+                .chain_err(|| "parsing --prefetch-threads")?,
+            tools: cmdline.value_of("prefetch_tools")
+                .unwrap()
+                .split(',')
+                .filter(|t| !t.is_empty())
+                .map(|t| t.to_string())
+                .collect(),
+        },
+        compression: hfs::tier::CompressionPolicy {
+            codec: match cmdline.value_of("compress").unwrap() {
+                "zstd" => Some(hfs::store::Codec::Zstd),
+                "lz4" => Some(hfs::store::Codec::Lz4),
+                _ => None,
+            },
+            level: cmdline.value_of("compress_level")
+                .unwrap()
+                .parse()
+                .chain_err(|| "parsing --compress-level")?,
+            skip: cmdline.value_of("compress_skip")
+                .unwrap()
+                .split(',')
+                .filter(|e| !e.is_empty())
+                .map(|e| e.trim_start_matches('.').to_lowercase())
+                .collect(),
+        },
+        dedup: cmdline.is_present("dedup"),
+        retention: Duration::from_secs(scaled(cmdline.value_of("keep_versions").unwrap(),
+                                              "--keep-versions",
+                                              DAY)?),
+        trash: Duration::from_secs(scaled(cmdline.value_of("trash").unwrap(), "--trash", DAY)?),
+        gc: hfs::GcPolicy {
+            grace: Duration::from_secs(scaled(cmdline.value_of("gc_grace").unwrap(),
+                                              "--gc-grace",
+                                              HOUR)?),
+            every: Duration::from_secs(scaled(cmdline.value_of("gc_every").unwrap(),
+                                              "--gc-every",
+                                              HOUR)?),
+        },
+    };
 
     trace!("{:?}", cmdline);
 
-    match cmdline.subcommand_name() {
-        None => hfs::S3HierarchicalFilesystem::mount(mountpath, backingpath),
+    match cmdline.subcommand() {
+        ("versions", Some(sub)) => {
+            for version in hfs::versions(backingpath, store, &options, sub.value_of("PATH"))? {
+                let retired = time::Timespec::new((version.retired / 1000) as i64, 0);
+                println!("{}\t{}\t{}\t{}",
+                         version.id(),
+                         time::at_utc(retired).rfc3339(),
+                         version.size,
+                         version.path);
+            }
+            Ok(())
+        }
+        ("restore", Some(sub)) => {
+            let id = sub.value_of("ID").unwrap();
+            let path = hfs::restore(mountpath,
+                                    backingpath,
+                                    store,
+                                    &options,
+                                    id,
+                                    sub.value_of("to"))?;
+            println!("{}", path.display());
+            Ok(())
+        }
+        ("trash", Some(sub)) => {
+            for trashed in hfs::trashed(backingpath, store, &options, sub.value_of("PATH"))? {
+                let deleted = time::Timespec::new((trashed.deleted / 1000) as i64, 0);
+                println!("{}\t{}\t{}{}",
+                         trashed.id(),
+                         time::at_utc(deleted).rfc3339(),
+                         trashed.path,
+                         if trashed.dir { "/" } else { "" });
+            }
+            Ok(())
+        }
+        ("untrash", Some(sub)) => {
+            let id = sub.value_of("ID").unwrap();
+            let path = hfs::untrash(mountpath,
+                                    backingpath,
+                                    store,
+                                    &options,
+                                    id,
+                                    sub.value_of("to"))?;
+            println!("{}", path.display());
+            Ok(())
+        }
+        ("gc", Some(sub)) => {
+            let dry_run = sub.is_present("dry_run");
+            for orphan in hfs::gc(mountpath, backingpath, store, &options, dry_run)? {
+                let state = if orphan.deleted {
+                    "deleted".to_string()
+                } else if orphan.due {
+                    "due".to_string()
+                } else {
+                    let since = time::Timespec::new((orphan.since / 1000) as i64, 0);
+                    format!("orphaned since {}", time::at_utc(since).rfc3339())
+                };
+                println!("{}\t{}\t{}\t{}", orphan.kind, orphan.key, orphan.size, state);
+            }
+            Ok(())
+        }
+        ("fsck", Some(sub)) => {
+            let findings = hfs::fsck(mountpath,
+                                     backingpath,
+                                     store,
+                                     &options,
+                                     sub.is_present("repair"),
+                                     sub.is_present("checksums"))?;
+            for finding in &findings {
+                println!("{}", serde_json::to_string(finding).chain_err(|| "encoding finding")?);
+            }
+            let left = findings.iter().filter(|f| !f.repaired).count();
+            if left > 0 {
+                bail!("{} of {} problems left unrepaired", left, findings.len());
+            }
+            Ok(())
+        }
+        ("snapshot", Some(sub)) => {
+            let name = sub.value_of("NAME").unwrap();
+            if sub.is_present("delete") {
+                return hfs::delete_snapshot(mountpath, backingpath, store, &options, name);
+            }
+            let snapshot = hfs::take_snapshot(mountpath, backingpath, store, &options, name)?;
+            println!("{}\t{}\t{}", snapshot.name, snapshot.files, snapshot.bytes);
+            Ok(())
+        }
+        ("snapshots", Some(_)) => {
+            for snapshot in hfs::snapshots(backingpath)? {
+                let taken = time::Timespec::new((snapshot.taken / 1000) as i64, 0);
+                println!("{}\t{}\t{}\t{}",
+                         snapshot.name,
+                         time::at_utc(taken).rfc3339(),
+                         snapshot.files,
+                         snapshot.bytes);
+            }
+            Ok(())
+        }
+        (_, None) => {
+            match cmdline.value_of("snapshot") {
+                Some(name) => {
+                    hfs::S3HierarchicalFilesystem::mount_snapshot(mountpath,
+                                                                  backingpath,
+                                                                  store,
+                                                                  options,
+                                                                  name)
+                }
+                None => {
+                    hfs::S3HierarchicalFilesystem::mount(mountpath, backingpath, store, options)
+                }
+            }
+        }
         _ => bail!("incorrect options"),
     }
 }